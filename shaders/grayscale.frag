@@ -0,0 +1,14 @@
+#version 450
+
+layout (location = 0) in vec2 in_uv;
+
+layout (set = 0, binding = 0) uniform sampler2D input_sampler;
+
+layout (location = 0) out vec4 out_color;
+
+void main() {
+    vec4 color = texture(input_sampler, in_uv);
+    float luminance = dot(color.rgb, vec3(0.2126, 0.7152, 0.0722));
+
+    out_color = vec4(vec3(luminance), color.a);
+}
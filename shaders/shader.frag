@@ -12,7 +12,10 @@ layout(location = 0) out vec4 out_color;
 readonly layout (set = 1, binding = 0) buffer StorageBufferObject {
     float num_directional;
     float num_point;
-    vec3 data[];
+    float num_spot;
+    vec3 ambient_color;
+    float ambient_intensity;
+    vec4 data[];
 } sbo;
 
 struct DirectionalLight {
@@ -23,6 +26,15 @@ struct DirectionalLight {
 struct PointLight {
     vec3 position;
     vec3 luminous_flux;
+    float range;
+};
+
+struct SpotLight {
+    vec3 position;
+    vec3 direction;
+    vec3 luminous_flux;
+    float cos_inner;
+    float cos_outer;
 };
 
 const float PI = 3.14159265358979323846264;
@@ -69,16 +81,17 @@ void main() {
     vec3 normal = normalize(in_normal);
     vec3 direction_to_camera = normalize(in_camera_pos - in_world_pos);
 
-    vec3 light = vec3(0);
+    vec3 light = sbo.ambient_color * sbo.ambient_intensity * in_color;
 
     // Directional lights:
 
     int number_directional = int(sbo.num_directional);
     int number_point = int(sbo.num_point);
+    int number_spot = int(sbo.num_spot);
 
     for (int i = 0; i < number_directional; i++) {
-        vec3 data1=sbo.data[2*i];
-        vec3 data2=sbo.data[2*i+1];
+        vec3 data1=sbo.data[2*i].xyz;
+        vec3 data2=sbo.data[2*i+1].xyz;
 
         DirectionalLight dlight = DirectionalLight(normalize(data1),data2);
 
@@ -88,12 +101,44 @@ void main() {
     // Point lights:
 
     for (int i=0;i<number_point;i++){
-        vec3 data1=sbo.data[2*i+2*number_directional];
-        vec3 data2=sbo.data[2*i+1+2*number_directional];
-        PointLight plight = PointLight(data1,data2);
+        vec3 data1=sbo.data[2*i+2*number_directional].xyz;
+        vec4 data2=sbo.data[2*i+1+2*number_directional];
+        PointLight plight = PointLight(data1,data2.xyz,data2.w);
         vec3 direction_to_light = normalize(plight.position - in_world_pos);
         float d = length(in_world_pos - plight.position);
-        vec3 irradiance = plight.luminous_flux/(4*PI*d*d);
+
+        // A range of 0 means the light reaches everywhere
+        if (plight.range > 0 && d >= plight.range) {
+            continue;
+        }
+
+        // Windowed inverse square falloff, the window reaches exactly zero at the range
+        float window = 1.0;
+        if (plight.range > 0) {
+            float ratio = d / plight.range;
+            window = clamp(1 - ratio*ratio*ratio*ratio, 0, 1);
+            window *= window;
+        }
+
+        vec3 irradiance = window*plight.luminous_flux/(4*PI*d*d);
+
+        light += compute_radiance(irradiance, direction_to_light, normal, direction_to_camera, in_color);
+    }
+
+    // Spot lights:
+
+    int spot_offset = 2*number_directional + 2*number_point;
+
+    for (int i=0;i<number_spot;i++){
+        vec3 data1=sbo.data[4*i+spot_offset].xyz;
+        vec3 data2=sbo.data[4*i+1+spot_offset].xyz;
+        vec3 data3=sbo.data[4*i+2+spot_offset].xyz;
+        vec3 data4=sbo.data[4*i+3+spot_offset].xyz;
+        SpotLight slight = SpotLight(data1,normalize(data2),data3,data4.x,data4.y);
+        vec3 direction_to_light = normalize(slight.position - in_world_pos);
+        float d = length(in_world_pos - slight.position);
+        float cone = smoothstep(slight.cos_outer, slight.cos_inner, dot(-direction_to_light, slight.direction));
+        vec3 irradiance = cone*slight.luminous_flux/(4*PI*d*d);
 
         light += compute_radiance(irradiance, direction_to_light, normal, direction_to_camera, in_color);
     }
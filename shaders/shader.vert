@@ -13,6 +13,12 @@ layout (set = 0, binding = 0) uniform UniformBufferObject {
     mat4 projection_matrix;
 } ubo;
 
+// Set for models drawn without instances, see Model::draw_with_push_constant
+layout (push_constant) uniform PushConstants {
+    mat4 model_matrix;
+    uint use_push_constant;
+} pc;
+
 layout (location = 0) out vec3 out_color;
 layout (location = 1) out vec3 out_normal;
 layout (location = 2) out vec4 out_world_pos;
@@ -21,11 +27,19 @@ layout (location = 4) out float out_metallic;
 layout (location = 5) out float out_roughness;
 
 void main() {
-    out_world_pos = in_model_matrix * vec4(in_position, 1.0);
+    mat4 model_matrix = in_model_matrix;
+    mat4 inverse_model_matrix = in_inverse_model_matrix;
+
+    if (pc.use_push_constant != 0) {
+        model_matrix = pc.model_matrix;
+        inverse_model_matrix = inverse(pc.model_matrix);
+    }
+
+    out_world_pos = model_matrix * vec4(in_position, 1.0);
 
     gl_Position = ubo.projection_matrix * ubo.view_matrix * out_world_pos;
 
-    out_normal = transpose(mat3(in_inverse_model_matrix)) * in_normal;
+    out_normal = transpose(mat3(inverse_model_matrix)) * in_normal;
 
     out_color = in_color;
 
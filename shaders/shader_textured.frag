@@ -1,11 +1,34 @@
 #version 450
+#extension GL_EXT_nonuniform_qualifier : require
 
 layout (location = 0) in vec2 in_uv;
+layout (location = 1) in vec3 in_view_pos;
+layout (location = 2) in vec3 in_normal;
+layout (location = 3) in vec4 in_tangent;
+layout (location = 4) flat in uint in_texture_index;
 
-layout (set = 1, binding = 0) uniform sampler2D texture_sampler;
+// Set through specialization to the engine's max_textures
+layout (constant_id = 0) const uint MAX_TEXTURES = 16;
+
+layout (set = 1, binding = 0) uniform sampler2D texture_samplers[MAX_TEXTURES];
+layout (set = 1, binding = 1) uniform sampler2D normal_sampler;
 
 layout (location = 0) out vec4 out_color;
 
 void main() {
-    out_color = texture(texture_sampler, in_uv);
-}
\ No newline at end of file
+    vec3 n = normalize(in_normal);
+    vec3 t = normalize(in_tangent.xyz - n * dot(n, in_tangent.xyz));
+    vec3 b = cross(n, t) * in_tangent.w;
+
+    // Tangent space: red along increasing u, green along increasing v, blue out of the surface
+    vec3 tangent_normal = texture(normal_sampler, in_uv).xyz * 2.0 - 1.0;
+    vec3 normal = normalize(mat3(t, b, n) * tangent_normal);
+
+    // Lit from the camera, the flat default normal map leaves surfaces facing it unchanged
+    vec3 to_camera = normalize(-in_view_pos);
+    float diffuse = max(dot(normal, to_camera), 0.0);
+
+    // Instances of one draw can pick different textures, so the index isn't uniform
+    vec4 color = texture(texture_samplers[nonuniformEXT(in_texture_index)], in_uv);
+    out_color = vec4(color.rgb * mix(0.2, 1.0, diffuse), color.a);
+}
@@ -4,6 +4,9 @@ layout (location = 0) in vec3 in_position;
 layout (location = 1) in vec2 in_texcord;
 layout (location = 2) in mat4 in_model_matrix;
 layout (location = 6) in mat4 in_inverse_model_matrix;
+layout (location = 10) in vec3 in_normal;
+layout (location = 11) in vec4 in_tangent;
+layout (location = 12) in uint in_texture_index;
 
 layout (set = 0, binding = 0) uniform UniformBufferObject {
     mat4 view_matrix;
@@ -11,9 +14,21 @@ layout (set = 0, binding = 0) uniform UniformBufferObject {
 } ubo;
 
 layout (location = 0) out vec2 out_uv;
+layout (location = 1) out vec3 out_view_pos;
+layout (location = 2) out vec3 out_normal;
+layout (location = 3) out vec4 out_tangent;
+layout (location = 4) flat out uint out_texture_index;
 
 void main() {
     vec4 world_pos = in_model_matrix * vec4(in_position, 1.0);
-    gl_Position = ubo.projection_matrix * ubo.view_matrix * world_pos;
+    vec4 view_pos = ubo.view_matrix * world_pos;
+    gl_Position = ubo.projection_matrix * view_pos;
     out_uv = in_texcord;
-}
\ No newline at end of file
+    out_texture_index = in_texture_index;
+    out_view_pos = view_pos.xyz;
+
+    // Normals go through the inverse transpose, tangents lie in the surface and transform like positions
+    mat3 view_rotation = mat3(ubo.view_matrix);
+    out_normal = view_rotation * transpose(mat3(in_inverse_model_matrix)) * in_normal;
+    out_tangent = vec4(view_rotation * mat3(in_model_matrix) * in_tangent.xyz, in_tangent.w);
+}
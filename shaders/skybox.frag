@@ -0,0 +1,11 @@
+#version 450
+
+layout (location = 0) in vec3 in_direction;
+
+layout (set = 1, binding = 0) uniform samplerCube skybox_sampler;
+
+layout (location = 0) out vec4 out_color;
+
+void main() {
+    out_color = texture(skybox_sampler, in_direction);
+}
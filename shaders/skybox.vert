@@ -0,0 +1,20 @@
+#version 450
+
+layout (location = 0) in vec3 in_position;
+
+layout (set = 0, binding = 0) uniform UniformBufferObject {
+    mat4 view_matrix;
+    mat4 projection_matrix;
+} ubo;
+
+layout (location = 0) out vec3 out_direction;
+
+void main() {
+    // Without the translation the cube stays centered on the camera
+    mat4 view_rotation = mat4(mat3(ubo.view_matrix));
+    vec4 pos = ubo.projection_matrix * view_rotation * vec4(in_position, 1.0);
+
+    // z = w ends up as a depth of 1.0, behind everything else
+    gl_Position = pos.xyww;
+    out_direction = in_position;
+}
@@ -0,0 +1,23 @@
+#version 450
+
+layout (location = 0) in vec2 in_uv;
+
+layout (set = 0, binding = 0) uniform sampler2D input_sampler;
+
+layout (push_constant) uniform PushConstants {
+    float exposure;
+} push;
+
+layout (location = 0) out vec4 out_color;
+
+// Narkowicz's fit of the ACES filmic curve
+vec3 aces(vec3 x) {
+    return clamp((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14), 0.0, 1.0);
+}
+
+void main() {
+    vec4 color = texture(input_sampler, in_uv);
+
+    // Stays linear, the sRGB swapchain encodes it on write
+    out_color = vec4(aces(color.rgb * push.exposure), color.a);
+}
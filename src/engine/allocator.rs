@@ -1,12 +1,13 @@
-use std::error::Error;
 use std::mem::ManuallyDrop;
 use gpu_allocator::vulkan::{Allocation, AllocationCreateDesc, Allocator, AllocatorCreateDesc};
 use ash::{vk};
 use gpu_allocator::{AllocationError, MemoryLocation};
+use crate::engine::error::EngineError;
 
 pub struct VkAllocator {
     device: ash::Device,
     allocator: ManuallyDrop<Allocator>,
+    allocation_count: usize,
 }
 
 impl VkAllocator {
@@ -15,12 +16,21 @@ impl VkAllocator {
 
         VkAllocator {
             device: info.device.clone(),
-            allocator: ManuallyDrop::new(allocator)
+            allocator: ManuallyDrop::new(allocator),
+            allocation_count: 0,
         }
     }
 
     pub fn allocate(&mut self, info: &AllocationCreateDesc) -> Result<Allocation, AllocationError> {
-        self.allocator.allocate(info)
+        let allocation = self.allocator.allocate(info)?;
+        self.allocation_count += 1;
+
+        Ok(allocation)
+    }
+
+    // Allocations that haven't been freed yet, for spotting leaks
+    pub fn allocation_count(&self) -> usize {
+        self.allocation_count
     }
 
     // Maybe create fns like free_image and free_buffer for convenience
@@ -30,6 +40,7 @@ impl VkAllocator {
         destroyer: &dyn Fn(&ash::Device) -> ()
     ) {
         self.allocator.free(allocation).unwrap();
+        self.allocation_count -= 1;
         destroyer(&self.device);
     }
 
@@ -38,7 +49,7 @@ impl VkAllocator {
         image_info: &vk::ImageCreateInfo,
         location: MemoryLocation,
         linear: bool,
-    ) -> Result<(vk::Image, Allocation), Box<dyn Error>> {
+    ) -> Result<(vk::Image, Allocation), EngineError> {
         let image = unsafe {
             self.device.create_image(image_info, None)
         }?;
@@ -66,7 +77,7 @@ impl VkAllocator {
         buffer_info: &vk::BufferCreateInfo,
         location: MemoryLocation,
         linear: bool,
-    ) -> Result<(vk::Buffer, Allocation), Box<dyn Error>> {
+    ) -> Result<(vk::Buffer, Allocation), EngineError> {
         let buffer = unsafe {
             self.device.create_buffer(&buffer_info, None)
         }?;
@@ -0,0 +1,128 @@
+use std::ffi::CStr;
+
+use ash::vk;
+use crate::engine::allocator::VkAllocator;
+use crate::engine::buffer::EngineBuffer;
+use crate::engine::error::EngineError;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Checkpoint {
+    BeginCommandBuffer = 1,
+    BeginRenderPass,
+    DrawModels,
+    EndRenderPass,
+}
+
+impl Checkpoint {
+    fn from_marker(marker: u32) -> Option<Checkpoint> {
+        match marker {
+            1 => Some(Checkpoint::BeginCommandBuffer),
+            2 => Some(Checkpoint::BeginRenderPass),
+            3 => Some(Checkpoint::DrawModels),
+            4 => Some(Checkpoint::EndRenderPass),
+            _ => None,
+        }
+    }
+}
+
+// Progress markers written during command recording, read back after a device lost to see
+// how far the GPU got. NV checkpoints are preferred, AMD buffer markers are the fallback.
+pub enum Breadcrumbs {
+    Checkpoints(ash::extensions::nv::DeviceDiagnosticCheckpoints),
+    BufferMarker {
+        fp: vk::AmdBufferMarkerFn,
+        buffer: EngineBuffer,
+    },
+}
+
+impl Breadcrumbs {
+    pub fn extension_names() -> [&'static CStr; 2] {
+        [
+            ash::extensions::nv::DeviceDiagnosticCheckpoints::name(),
+            vk::AmdBufferMarkerFn::name(),
+        ]
+    }
+
+    pub fn init(
+        instance: &ash::Instance,
+        device: &ash::Device,
+        allocator: &mut VkAllocator,
+        extension_name: &CStr,
+    ) -> Result<Breadcrumbs, EngineError> {
+        if extension_name == ash::extensions::nv::DeviceDiagnosticCheckpoints::name() {
+            return Ok(Breadcrumbs::Checkpoints(
+                ash::extensions::nv::DeviceDiagnosticCheckpoints::new(instance, device)
+            ));
+        }
+
+        let fp = vk::AmdBufferMarkerFn::load(|name| unsafe {
+            std::mem::transmute(instance.get_device_proc_addr(device.handle(), name.as_ptr()))
+        });
+
+        let mut buffer = EngineBuffer::new(
+            allocator,
+            std::mem::size_of::<u32>() as u64,
+            vk::BufferUsageFlags::TRANSFER_DST,
+            gpu_allocator::MemoryLocation::GpuToCpu,
+        )?;
+
+        buffer.fill(allocator, &[0u32])?;
+
+        Ok(Breadcrumbs::BufferMarker { fp, buffer })
+    }
+
+    pub fn mark(&self, command_buffer: vk::CommandBuffer, checkpoint: Checkpoint) {
+        let marker = checkpoint as u32;
+
+        match self {
+            Breadcrumbs::Checkpoints(loader) => unsafe {
+                // The marker is an opaque pointer-sized value, it is never dereferenced
+                loader.cmd_set_checkpoint(command_buffer, marker as usize as *const std::ffi::c_void);
+            },
+            Breadcrumbs::BufferMarker { fp, buffer } => unsafe {
+                fp.cmd_write_buffer_marker_amd(
+                    command_buffer,
+                    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                    buffer.buffer,
+                    0,
+                    marker,
+                );
+            },
+        }
+    }
+
+    // The last checkpoints the queue got through, along with the stage they were reached at
+    pub fn last_checkpoints(&self, queue: vk::Queue) -> Vec<(Checkpoint, vk::PipelineStageFlags)> {
+        match self {
+            Breadcrumbs::Checkpoints(loader) => {
+                let data = unsafe {
+                    loader.get_queue_checkpoint_data(queue)
+                };
+
+                data.iter()
+                    .filter_map(|checkpoint| {
+                        Checkpoint::from_marker(checkpoint.p_checkpoint_marker as usize as u32)
+                            .map(|c| (c, checkpoint.stage))
+                    })
+                    .collect()
+            }
+            Breadcrumbs::BufferMarker { buffer, .. } => {
+                let marker = buffer.allocation
+                    .as_ref()
+                    .and_then(|allocation| allocation.mapped_ptr())
+                    .map(|ptr| unsafe { (ptr.as_ptr() as *const u32).read_volatile() });
+
+                marker
+                    .and_then(Checkpoint::from_marker)
+                    .map(|c| vec![(c, vk::PipelineStageFlags::BOTTOM_OF_PIPE)])
+                    .unwrap_or_default()
+            }
+        }
+    }
+
+    pub unsafe fn cleanup(&mut self, allocator: &mut VkAllocator) {
+        if let Breadcrumbs::BufferMarker { buffer, .. } = self {
+            buffer.cleanup(allocator);
+        }
+    }
+}
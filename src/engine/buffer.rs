@@ -1,6 +1,25 @@
 use ash::vk;
 use gpu_allocator::vulkan::{Allocation, AllocationCreateDesc, Allocator};
 use crate::engine::allocator::VkAllocator;
+use crate::engine::error::EngineError;
+
+#[derive(Debug, Clone)]
+pub struct OutOfBounds {
+    pub offset: u64,
+    pub len: u64,
+    pub size: u64,
+}
+
+impl std::fmt::Display for OutOfBounds {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "writing {} bytes at offset {} exceeds buffer size {}", self.len, self.offset, self.size)
+    }
+}
+impl std::error::Error for OutOfBounds {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
 
 pub struct EngineBuffer {
     pub buffer: vk::Buffer,
@@ -10,13 +29,18 @@ pub struct EngineBuffer {
     pub memory_usage: gpu_allocator::MemoryLocation,
 }
 
+#[allow(dead_code)]
 impl EngineBuffer {
     pub fn new(
         allocator: &mut VkAllocator,
         size_in_bytes: u64,
         usage: vk::BufferUsageFlags,
         memory_usage: gpu_allocator::MemoryLocation
-    ) -> Result<EngineBuffer, gpu_allocator::AllocationError> {
+    ) -> Result<EngineBuffer, EngineError> {
+        if size_in_bytes == 0 {
+            return Err(EngineError::ZeroSizedBuffer);
+        }
+
         let buffer_info = vk::BufferCreateInfo::builder()
             .size(size_in_bytes)
             .usage(usage);
@@ -25,7 +49,7 @@ impl EngineBuffer {
             &buffer_info,
             memory_usage,
             true
-        ).unwrap();
+        )?;
 
         Ok(EngineBuffer {
             buffer,
@@ -36,11 +60,247 @@ impl EngineBuffer {
         })
     }
 
+    // GpuOnly memory can't be mapped, it's written with fill_staged instead of fill
+    // and read with read_staged instead of read
+    pub fn new_device_local(
+        allocator: &mut VkAllocator,
+        size_in_bytes: u64,
+        usage: vk::BufferUsageFlags,
+    ) -> Result<EngineBuffer, EngineError> {
+        EngineBuffer::new(
+            allocator,
+            size_in_bytes,
+            usage | vk::BufferUsageFlags::TRANSFER_DST | vk::BufferUsageFlags::TRANSFER_SRC,
+            gpu_allocator::MemoryLocation::GpuOnly
+        )
+    }
+
+    // Copies the data through a temporary CpuToGpu buffer on the transfer queue and waits for
+    // the copy to finish. The buffer ends up owned by the graphics queue family.
+    pub fn fill_staged<T: Sized>(
+        &mut self,
+        device: &ash::Device,
+        allocator: &mut VkAllocator,
+        upload_queues: &UploadQueues,
+        data: &[T],
+    ) -> Result<(), EngineError> {
+        let bytes_to_write = std::mem::size_of_val(data) as u64;
+
+        if bytes_to_write == 0 {
+            return Ok(());
+        }
+
+        if bytes_to_write > self.size_in_bytes {
+            unsafe {
+                self.cleanup(allocator);
+            }
+
+            *self = EngineBuffer::new(
+                allocator,
+                bytes_to_write,
+                self.usage,
+                self.memory_usage
+            )?;
+        }
+
+        let mut staging_buffer = EngineBuffer::new(
+            allocator,
+            bytes_to_write,
+            vk::BufferUsageFlags::TRANSFER_SRC,
+            gpu_allocator::MemoryLocation::CpuToGpu
+        )?;
+
+        staging_buffer.fill(allocator, data)?;
+
+        let region = vk::BufferCopy {
+            src_offset: 0,
+            dst_offset: 0,
+            size: bytes_to_write,
+        };
+
+        let ownership_barrier = vk::BufferMemoryBarrier::builder()
+            .buffer(self.buffer)
+            .src_queue_family_index(upload_queues.transfer_family)
+            .dst_queue_family_index(upload_queues.graphics_family)
+            .size(vk::WHOLE_SIZE)
+            .build();
+
+        let result = submit_once(
+            device,
+            upload_queues.transfer_pool,
+            upload_queues.transfer_queue,
+            |command_buffer| unsafe {
+                device.cmd_copy_buffer(command_buffer, staging_buffer.buffer, self.buffer, &[region]);
+
+                if upload_queues.needs_ownership_transfer() {
+                    let release = vk::BufferMemoryBarrier {
+                        src_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+                        ..ownership_barrier
+                    };
+
+                    device.cmd_pipeline_barrier(
+                        command_buffer,
+                        vk::PipelineStageFlags::TRANSFER,
+                        vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                        vk::DependencyFlags::empty(),
+                        &[],
+                        &[release],
+                        &[],
+                    );
+                }
+            },
+        );
+
+        unsafe {
+            staging_buffer.cleanup(allocator);
+        }
+
+        result?;
+
+        if upload_queues.needs_ownership_transfer() {
+            submit_once(
+                device,
+                upload_queues.graphics_pool,
+                upload_queues.graphics_queue,
+                |command_buffer| unsafe {
+                    let acquire = vk::BufferMemoryBarrier {
+                        dst_access_mask: vk::AccessFlags::MEMORY_READ,
+                        ..ownership_barrier
+                    };
+
+                    device.cmd_pipeline_barrier(
+                        command_buffer,
+                        vk::PipelineStageFlags::TOP_OF_PIPE,
+                        vk::PipelineStageFlags::ALL_COMMANDS,
+                        vk::DependencyFlags::empty(),
+                        &[],
+                        &[acquire],
+                        &[],
+                    );
+                },
+            )?;
+        }
+
+        Ok(())
+    }
+
+    // Updates part of the buffer in place. Unlike fill it never grows the buffer, so the
+    // data has to fit between the offset and the end of the buffer.
+    pub fn fill_at<T: Sized>(
+        &mut self,
+        offset_bytes: u64,
+        data: &[T],
+    ) -> Result<(), EngineError> {
+        let bytes_to_write = std::mem::size_of_val(data) as u64;
+
+        match offset_bytes.checked_add(bytes_to_write) {
+            Some(end) if end <= self.size_in_bytes => {}
+            _ => {
+                return Err(OutOfBounds {
+                    offset: offset_bytes,
+                    len: bytes_to_write,
+                    size: self.size_in_bytes,
+                }.into());
+            }
+        }
+
+        if let Some(allocation) = &self.allocation {
+            let mapped_ptr = allocation.mapped_ptr().ok_or(EngineError::NotMapped)?;
+
+            unsafe {
+                let data_ptr = mapped_ptr.as_ptr()
+                    .cast::<u8>()
+                    .add(offset_bytes as usize);
+
+                data_ptr.copy_from_nonoverlapping(data.as_ptr() as *const u8, bytes_to_write as usize);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Only works for mapped memory (CpuToGpu and GpuToCpu), GpuOnly buffers need read_staged.
+    // Returns as many elements as fit into the buffer.
+    pub fn read<T: Copy>(&self) -> Result<Vec<T>, EngineError> {
+        let count = self.size_in_bytes as usize / std::mem::size_of::<T>();
+
+        match &self.allocation {
+            Some(allocation) => {
+                let data_ptr = allocation.mapped_ptr()
+                    .ok_or(EngineError::NotMapped)?
+                    .as_ptr() as *const T;
+
+                unsafe {
+                    Ok(std::slice::from_raw_parts(data_ptr, count).to_vec())
+                }
+            }
+            None => Ok(vec![]),
+        }
+    }
+
+    // Copies the buffer into a temporary GpuToCpu buffer and reads that. Writes from earlier
+    // submissions have to be finished, e.g. by waiting on their fence.
+    pub fn read_staged<T: Copy>(
+        &self,
+        device: &ash::Device,
+        allocator: &mut VkAllocator,
+        command_pool: vk::CommandPool,
+        queue: vk::Queue,
+    ) -> Result<Vec<T>, EngineError> {
+        let mut readback_buffer = EngineBuffer::new(
+            allocator,
+            self.size_in_bytes,
+            vk::BufferUsageFlags::TRANSFER_DST,
+            gpu_allocator::MemoryLocation::GpuToCpu
+        )?;
+
+        let region = vk::BufferCopy {
+            src_offset: 0,
+            dst_offset: 0,
+            size: self.size_in_bytes,
+        };
+
+        // Makes the copied data visible to the host once the fence is signaled
+        let barrier = vk::BufferMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::HOST_READ)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .buffer(readback_buffer.buffer)
+            .offset(0)
+            .size(vk::WHOLE_SIZE)
+            .build();
+
+        let result = submit_once(device, command_pool, queue, |command_buffer| unsafe {
+            device.cmd_copy_buffer(command_buffer, self.buffer, readback_buffer.buffer, &[region]);
+
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::HOST,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[barrier],
+                &[],
+            );
+        });
+
+        let data = result
+            .map_err(EngineError::from)
+            .and_then(|_| readback_buffer.read());
+
+        unsafe {
+            readback_buffer.cleanup(allocator);
+        }
+
+        data
+    }
+
     pub fn fill<T: Sized>(
         &mut self,
         allocator: &mut VkAllocator,
         data: &[T],
-    ) -> Result<(), gpu_allocator::AllocationError> {
+    ) -> Result<(), EngineError> {
         let bytes_to_write = (data.len() * std::mem::size_of::<T>()) as u64;
 
         if bytes_to_write > self.size_in_bytes {
@@ -59,7 +319,7 @@ impl EngineBuffer {
         }
 
         if let Some(allocation) = &self.allocation {
-            let data_ptr = allocation.mapped_ptr().unwrap().as_ptr() as *mut T;
+            let data_ptr = allocation.mapped_ptr().ok_or(EngineError::NotMapped)?.as_ptr() as *mut T;
 
             unsafe {
                 data_ptr.copy_from_nonoverlapping(data.as_ptr(), data.len());
@@ -76,4 +336,74 @@ impl EngineBuffer {
         let destroyer = |device: &ash::Device| device.destroy_buffer(self.buffer, None);
         allocator.free(self.allocation.take().unwrap(), &destroyer);
     }
-}
\ No newline at end of file
+}
+
+// Staged uploads copy on the transfer queue. Resources are exclusive to one queue family, so
+// when the transfer family isn't the graphics one they're released by the transfer queue and
+// acquired by the graphics queue afterwards.
+#[derive(Copy, Clone, Debug)]
+pub struct UploadQueues {
+    pub transfer_pool: vk::CommandPool,
+    pub transfer_queue: vk::Queue,
+    pub transfer_family: u32,
+    pub graphics_pool: vk::CommandPool,
+    pub graphics_queue: vk::Queue,
+    pub graphics_family: u32,
+}
+
+impl UploadQueues {
+    pub fn needs_ownership_transfer(&self) -> bool {
+        self.transfer_family != self.graphics_family
+    }
+}
+
+// Records a single use command buffer, submits it and waits until it has executed
+pub fn submit_once<F: FnOnce(vk::CommandBuffer)>(
+    device: &ash::Device,
+    command_pool: vk::CommandPool,
+    queue: vk::Queue,
+    record: F,
+) -> Result<(), vk::Result> {
+    let command_buffer_allocate_info = vk::CommandBufferAllocateInfo::builder()
+        .command_pool(command_pool)
+        .command_buffer_count(1);
+
+    let command_buffer = unsafe {
+        device.allocate_command_buffers(&command_buffer_allocate_info)
+    }?[0];
+
+    let cmd_begin_info = vk::CommandBufferBeginInfo::builder()
+        .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+
+    let result = unsafe {
+        device.begin_command_buffer(command_buffer, &cmd_begin_info)
+    }.and_then(|_| {
+        record(command_buffer);
+
+        let command_buffers = [command_buffer];
+        let submit_infos = [
+            vk::SubmitInfo::builder()
+                .command_buffers(&command_buffers)
+                .build()
+        ];
+
+        unsafe {
+            device.end_command_buffer(command_buffer)?;
+
+            let fence = device.create_fence(&vk::FenceCreateInfo::default(), None)?;
+
+            let result = device.queue_submit(queue, &submit_infos, fence)
+                .and_then(|_| device.wait_for_fences(&[fence], true, u64::MAX));
+
+            device.destroy_fence(fence, None);
+
+            result
+        }
+    });
+
+    unsafe {
+        device.free_command_buffers(command_pool, &[command_buffer]);
+    }
+
+    result
+}
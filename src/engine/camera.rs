@@ -2,22 +2,77 @@ use ash::Device;
 use gpu_allocator::vulkan::Allocator;
 
 use crate::engine::buffer::EngineBuffer;
+use crate::engine::error::EngineError;
 
 use nalgebra as na;
 use crate::engine::allocator::VkAllocator;
 
+// Keeps rotate from pitching the view past straight up or down
+const MAX_PITCH: f32 = std::f32::consts::FRAC_PI_2 - 0.01;
+
+// Layout of the camera uniform buffer the shaders read
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct CameraUniform {
+    pub view: [[f32; 4]; 4],
+    pub proj: [[f32; 4]; 4],
+}
+
+// Planes of the view volume as (normal, distance) with the normals pointing inwards
+#[derive(Copy, Clone, Debug)]
+pub struct Frustum {
+    planes: [na::Vector4<f32>; 6],
+}
+
+impl Frustum {
+    // Gribb/Hartmann plane extraction, the clip volume is -w..w in x and y and 0..w in z
+    pub fn from_matrix(matrix: &na::Matrix4<f32>) -> Frustum {
+        let row = |i: usize| matrix.row(i).transpose();
+
+        let planes = [
+            row(3) + row(0),
+            row(3) - row(0),
+            row(3) + row(1),
+            row(3) - row(1),
+            row(2),
+            row(3) - row(2),
+        ];
+
+        Frustum {
+            planes: planes.map(|plane| plane / plane.xyz().norm()),
+        }
+    }
+
+    pub fn contains_sphere(&self, center: &na::Vector3<f32>, radius: f32) -> bool {
+        self.planes
+            .iter()
+            .all(|plane| plane.xyz().dot(center) + plane.w >= -radius)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Projection {
+    Perspective,
+    // Height of the visible area in world units, the width follows from the aspect
+    Orthographic { height: f32 },
+}
+
 pub struct Camera {
     view_matrix: na::Matrix4<f32>,
     position: na::Vector3<f32>,
     view_direction: na::Unit<na::Vector3<f32>>,
     down_direction: na::Unit<na::Vector3<f32>>,
+    pitch: f32,
     fovy: f32,
     aspect: f32,
     near: f32,
     far: f32,
+    projection: Projection,
+    reverse_z: bool,
     projection_matrix: na::Matrix4<f32>,
 }
 
+#[allow(dead_code)]
 impl Camera {
     pub fn builder() -> CameraBuilder {
         CameraBuilder {
@@ -28,6 +83,8 @@ impl Camera {
             aspect: 800.0 / 600.0,
             near: 0.1,
             far: 100.0,
+            projection: Projection::Perspective,
+            reverse_z: false,
         }
     }
 
@@ -35,10 +92,13 @@ impl Camera {
         &self,
         allocator: &mut VkAllocator,
         buffer: &mut EngineBuffer
-    ) -> Result<(), gpu_allocator::AllocationError> {
-        let data: [[[f32; 4]; 4]; 2] = [self.view_matrix.into(), self.projection_matrix.into()];
+    ) -> Result<(), EngineError> {
+        let data = CameraUniform {
+            view: self.view_matrix.into(),
+            proj: self.projection_matrix.into(),
+        };
 
-        buffer.fill(allocator,  &data)?;
+        buffer.fill(allocator, &[data])?;
 
         Ok(())
     }
@@ -66,7 +126,38 @@ impl Camera {
         );
     }
 
+    // With reverse_z the near plane maps to a depth of 1 and the far plane to 0, which spreads
+    // the float precision more evenly over the distance
     pub fn update_projection_matrix(&mut self) {
+        let (near, far) = if self.reverse_z {
+            (self.far, self.near)
+        } else {
+            (self.near, self.far)
+        };
+
+        if let Projection::Orthographic { height } = self.projection {
+            self.projection_matrix = na::Matrix4::new(
+                2.0 / (height * self.aspect),
+                0.0,
+                0.0,
+                0.0,
+                0.0,
+                2.0 / height,
+                0.0,
+                0.0,
+                0.0,
+                0.0,
+                1.0 / (far - near),
+                -near / (far - near),
+                0.0,
+                0.0,
+                0.0,
+                1.0,
+            );
+
+            return;
+        }
+
         let d = 1.0 / (0.5 * self.fovy).tan();
 
         self.projection_matrix = na::Matrix4::new(
@@ -80,8 +171,8 @@ impl Camera {
             0.0,
             0.0,
             0.0,
-            self.far / (self.far - self.near),
-            -self.near * self.far / (self.far - self.near),
+            far / (far - near),
+            -near * far / (far - near),
             0.0,
             0.0,
             1.0,
@@ -98,6 +189,25 @@ impl Camera {
         self.move_forward(-distance);
     }
 
+    pub fn move_right(&mut self, distance: f32) {
+        let right = na::Unit::new_normalize(self.down_direction.cross(&self.view_direction));
+        self.position += distance * right.as_ref();
+        self.update_view_matrix();
+    }
+
+    pub fn move_left(&mut self, distance: f32) {
+        self.move_right(-distance);
+    }
+
+    pub fn move_up(&mut self, distance: f32) {
+        self.position -= distance * self.down_direction.as_ref();
+        self.update_view_matrix();
+    }
+
+    pub fn move_down(&mut self, distance: f32) {
+        self.move_up(-distance);
+    }
+
     pub fn turn_right(&mut self, angle: f32) {
         let rotation = na::Rotation3::from_axis_angle(&self.down_direction, angle);
         self.view_direction = rotation * self.view_direction;
@@ -113,6 +223,7 @@ impl Camera {
         let rotation = na::Rotation3::from_axis_angle(&right, angle);
         self.view_direction = rotation * self.view_direction;
         self.down_direction = rotation * self.down_direction;
+        self.pitch += angle;
         self.update_view_matrix();
     }
 
@@ -120,10 +231,76 @@ impl Camera {
         self.turn_up(-angle);
     }
 
+    // Meant for mouse deltas; yaw is applied around the unpitched down direction so looking
+    // around doesn't introduce roll, and the accumulated pitch is clamped to MAX_PITCH
+    pub fn rotate(&mut self, yaw_delta: f32, pitch_delta: f32) {
+        let right = na::Unit::new_normalize(self.down_direction.cross(&self.view_direction));
+        let level_down = na::Rotation3::from_axis_angle(&right, -self.pitch) * self.down_direction;
+
+        let yaw = na::Rotation3::from_axis_angle(&level_down, yaw_delta);
+        self.view_direction = yaw * self.view_direction;
+        self.down_direction = yaw * self.down_direction;
+
+        let pitch = (self.pitch + pitch_delta).clamp(-MAX_PITCH, MAX_PITCH);
+        self.turn_up(pitch - self.pitch);
+    }
+
+    pub fn position(&self) -> na::Vector3<f32> {
+        self.position
+    }
+
+    pub fn view_direction(&self) -> na::Unit<na::Vector3<f32>> {
+        self.view_direction
+    }
+
+    pub fn down_direction(&self) -> na::Unit<na::Vector3<f32>> {
+        self.down_direction
+    }
+
+    pub fn pitch(&self) -> f32 {
+        self.pitch
+    }
+
+    pub fn view_matrix(&self) -> na::Matrix4<f32> {
+        self.view_matrix
+    }
+
+    pub fn projection_matrix(&self) -> na::Matrix4<f32> {
+        self.projection_matrix
+    }
+
+    // Takes world space straight to clip space, the same product the vertex shader computes
+    pub fn view_projection(&self) -> na::Matrix4<f32> {
+        self.projection_matrix * self.view_matrix
+    }
+
+    pub fn frustum(&self) -> Frustum {
+        Frustum::from_matrix(&self.view_projection())
+    }
+
     pub fn set_aspect(&mut self, aspect: f32) {
         self.aspect = aspect;
         self.update_projection_matrix();
     }
+
+    pub fn projection(&self) -> Projection {
+        self.projection
+    }
+
+    pub fn set_projection(&mut self, projection: Projection) {
+        self.projection = projection;
+        self.update_projection_matrix();
+    }
+
+    pub fn reverse_z(&self) -> bool {
+        self.reverse_z
+    }
+
+    // Has to match the engine, see VulkanEngine::set_reverse_z
+    pub fn set_reverse_z(&mut self, reverse_z: bool) {
+        self.reverse_z = reverse_z;
+        self.update_projection_matrix();
+    }
 }
 
 pub struct CameraBuilder {
@@ -134,6 +311,8 @@ pub struct CameraBuilder {
     aspect: f32,
     near: f32,
     far: f32,
+    projection: Projection,
+    reverse_z: bool,
 }
 
 #[allow(dead_code)]
@@ -163,6 +342,16 @@ impl CameraBuilder {
         self
     }
 
+    pub fn orthographic(mut self, height: f32) -> CameraBuilder {
+        self.projection = Projection::Orthographic { height };
+        self
+    }
+
+    pub fn reverse_z(mut self, reverse_z: bool) -> CameraBuilder {
+        self.reverse_z = reverse_z;
+        self
+    }
+
     pub fn view_direction(mut self, direction: na::Vector3<f32>) -> CameraBuilder {
         self.view_direction = na::Unit::new_normalize(direction);
         self
@@ -173,6 +362,33 @@ impl CameraBuilder {
         self
     }
 
+    // The up vector only has to be roughly right, build() makes it perpendicular to the view
+    pub fn look_at(
+        mut self,
+        eye: na::Point3<f32>,
+        target: na::Point3<f32>,
+        up: na::Vector3<f32>,
+    ) -> CameraBuilder {
+        self.position = eye.coords;
+        self.view_direction = na::Unit::new_normalize(target - eye);
+        self.down_direction = na::Unit::new_normalize(-up);
+        self
+    }
+
+    pub fn build_orbit(self, target: na::Point3<f32>, radius: f32) -> OrbitCamera {
+        let mut orbit = OrbitCamera {
+            camera: self.build(),
+            target,
+            radius,
+            yaw: 0.0,
+            pitch: 0.0,
+        };
+
+        orbit.update_camera();
+
+        orbit
+    }
+
     pub fn build(self) -> Camera {
         if self.far < self.near {
             println!(
@@ -192,10 +408,13 @@ impl CameraBuilder {
                     .dot(self.view_direction.as_ref())
                     * self.view_direction.as_ref(),
             ),
+            pitch: 0.0,
             fovy: self.fovy,
             aspect: self.aspect,
             near: self.near,
             far: self.far,
+            projection: self.projection,
+            reverse_z: self.reverse_z,
             view_matrix: na::Matrix4::identity(),
             projection_matrix: na::Matrix4::identity(),
         };
@@ -205,4 +424,76 @@ impl CameraBuilder {
 
         cam
     }
-}
\ No newline at end of file
+}
+
+// Camera that always looks at a target point from a distance, for inspecting models
+pub struct OrbitCamera {
+    camera: Camera,
+    target: na::Point3<f32>,
+    radius: f32,
+    yaw: f32,
+    pitch: f32,
+}
+
+#[allow(dead_code)]
+impl OrbitCamera {
+    pub fn orbit(&mut self, yaw_delta: f32, pitch_delta: f32) {
+        self.yaw += yaw_delta;
+        self.pitch = (self.pitch + pitch_delta).clamp(-MAX_PITCH, MAX_PITCH);
+        self.update_camera();
+    }
+
+    // Positive deltas move closer to the target
+    pub fn zoom(&mut self, delta: f32) {
+        self.radius = (self.radius - delta).max(self.camera.near);
+        self.update_camera();
+    }
+
+    pub fn set_target(&mut self, target: na::Point3<f32>) {
+        self.target = target;
+        self.update_camera();
+    }
+
+    pub fn target(&self) -> na::Point3<f32> {
+        self.target
+    }
+
+    pub fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    pub fn camera(&self) -> &Camera {
+        &self.camera
+    }
+
+    pub fn update_buffer(
+        &self,
+        allocator: &mut VkAllocator,
+        buffer: &mut EngineBuffer
+    ) -> Result<(), EngineError> {
+        self.camera.update_buffer(allocator, buffer)
+    }
+
+    pub fn set_aspect(&mut self, aspect: f32) {
+        self.camera.set_aspect(aspect);
+    }
+
+    fn update_camera(&mut self) {
+        // Yaw 0 and pitch 0 look along +z like the default camera, positive pitch looks up (-y)
+        let view_direction = na::Unit::new_normalize(na::Vector3::new(
+            self.pitch.cos() * self.yaw.sin(),
+            -self.pitch.sin(),
+            self.pitch.cos() * self.yaw.cos(),
+        ));
+
+        let down = na::Vector3::new(0.0, 1.0, 0.0);
+
+        self.camera.position = self.target.coords - self.radius * view_direction.as_ref();
+        self.camera.view_direction = view_direction;
+        self.camera.down_direction = na::Unit::new_normalize(
+            down - down.dot(view_direction.as_ref()) * view_direction.as_ref()
+        );
+        self.camera.pitch = self.pitch;
+        self.camera.update_view_matrix();
+    }
+}
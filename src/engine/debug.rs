@@ -1,3 +1,5 @@
+use std::ffi::CString;
+
 use ash::vk;
 use ash::vk::PFN_vkDebugUtilsMessengerCallbackEXT;
 
@@ -37,6 +39,26 @@ impl EngineDebug {
             messenger
         })
     }
+
+    // Names show up in validation messages instead of raw handles
+    pub fn set_object_name<H: vk::Handle>(
+        &self,
+        device: &ash::Device,
+        handle: H,
+        name: &str,
+    ) -> Result<(), vk::Result> {
+        // A nul byte can't be passed on in a C string, so the object is named without them
+        let name = CString::new(name.replace('\0', "")).unwrap_or_default();
+
+        let name_info = vk::DebugUtilsObjectNameInfoEXT::builder()
+            .object_type(H::TYPE)
+            .object_handle(handle.as_raw())
+            .object_name(&name);
+
+        unsafe {
+            self.loader.debug_utils_set_object_name(device.handle(), &name_info)
+        }
+    }
 }
 
 impl Drop for EngineDebug {
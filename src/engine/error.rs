@@ -0,0 +1,108 @@
+use ash::vk;
+use crate::engine::CaptureError;
+use crate::engine::buffer::OutOfBounds;
+use crate::engine::texture::InvalidTextureData;
+
+// Common error of engine setup and of buffer, texture and model operations
+#[derive(Debug)]
+pub enum EngineError {
+    Vulkan(vk::Result),
+    Allocation(gpu_allocator::AllocationError),
+    Image(image::ImageError),
+    Io(std::io::Error),
+    InvalidTextureData(InvalidTextureData),
+    Capture(CaptureError),
+    OutOfBounds(OutOfBounds),
+    // Vulkan doesn't allow buffers without any bytes
+    ZeroSizedBuffer,
+    // GpuOnly memory can't be accessed from the host, only through the *_staged methods
+    NotMapped,
+    InvalidModelIndex(usize),
+    // At or past the builder's max_textures
+    InvalidTextureIndex(u32),
+    // A device feature requested through the builder that the device doesn't support
+    MissingFeature(&'static str),
+    // The builder's app name has to fit in a C string
+    InvalidAppName(std::ffi::NulError),
+}
+
+impl std::fmt::Display for EngineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            EngineError::Vulkan(result) => write!(f, "vulkan error: {}", result),
+            EngineError::Allocation(error) => write!(f, "allocation failed: {}", error),
+            EngineError::Image(error) => write!(f, "image error: {}", error),
+            EngineError::Io(error) => write!(f, "io error: {}", error),
+            EngineError::InvalidTextureData(error) => write!(f, "invalid texture data: {}", error),
+            EngineError::Capture(error) => write!(f, "capture failed: {}", error),
+            EngineError::OutOfBounds(error) => write!(f, "buffer write out of bounds: {}", error),
+            EngineError::ZeroSizedBuffer => write!(f, "buffers can't have a size of 0"),
+            EngineError::NotMapped => write!(f, "buffer memory isn't mapped"),
+            EngineError::InvalidModelIndex(index) => write!(f, "no model at index {}", index),
+            EngineError::InvalidTextureIndex(index) => write!(f, "no texture slot at index {}", index),
+            EngineError::MissingFeature(name) => write!(f, "device doesn't support the feature {}", name),
+            EngineError::InvalidAppName(error) => write!(f, "invalid app name: {}", error),
+        }
+    }
+}
+impl std::error::Error for EngineError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EngineError::Vulkan(result) => Some(result),
+            EngineError::Allocation(error) => Some(error),
+            EngineError::Image(error) => Some(error),
+            EngineError::Io(error) => Some(error),
+            EngineError::InvalidTextureData(error) => Some(error),
+            EngineError::Capture(error) => Some(error),
+            EngineError::OutOfBounds(error) => Some(error),
+            EngineError::ZeroSizedBuffer => None,
+            EngineError::NotMapped => None,
+            EngineError::InvalidModelIndex(_) => None,
+            EngineError::InvalidTextureIndex(_) => None,
+            EngineError::MissingFeature(_) => None,
+            EngineError::InvalidAppName(error) => Some(error),
+        }
+    }
+}
+
+impl From<vk::Result> for EngineError {
+    fn from(result: vk::Result) -> Self {
+        EngineError::Vulkan(result)
+    }
+}
+
+impl From<gpu_allocator::AllocationError> for EngineError {
+    fn from(error: gpu_allocator::AllocationError) -> Self {
+        EngineError::Allocation(error)
+    }
+}
+
+impl From<image::ImageError> for EngineError {
+    fn from(error: image::ImageError) -> Self {
+        EngineError::Image(error)
+    }
+}
+
+impl From<std::io::Error> for EngineError {
+    fn from(error: std::io::Error) -> Self {
+        EngineError::Io(error)
+    }
+}
+
+impl From<InvalidTextureData> for EngineError {
+    fn from(error: InvalidTextureData) -> Self {
+        EngineError::InvalidTextureData(error)
+    }
+}
+
+impl From<CaptureError> for EngineError {
+    fn from(error: CaptureError) -> Self {
+        EngineError::Capture(error)
+    }
+}
+
+impl From<OutOfBounds> for EngineError {
+    fn from(error: OutOfBounds) -> Self {
+        EngineError::OutOfBounds(error)
+    }
+}
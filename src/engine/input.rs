@@ -0,0 +1,88 @@
+use std::collections::HashSet;
+
+use winit::event::{DeviceEvent, ElementState, Event, MouseButton, VirtualKeyCode, WindowEvent};
+
+// Keeps the state of keys and mouse buttons between events, so controls can be polled once per
+// frame instead of reacting to each event. Every event goes through handle_event, and end_frame
+// runs after the frame has read the mouse delta.
+pub struct Input {
+    pressed_keys: HashSet<VirtualKeyCode>,
+    pressed_buttons: HashSet<MouseButton>,
+    mouse_delta: (f64, f64),
+}
+
+impl Default for Input {
+    fn default() -> Self {
+        Input::new()
+    }
+}
+
+#[allow(dead_code)]
+impl Input {
+    pub fn new() -> Input {
+        Input {
+            pressed_keys: HashSet::new(),
+            pressed_buttons: HashSet::new(),
+            mouse_delta: (0.0, 0.0),
+        }
+    }
+
+    pub fn handle_event<T>(&mut self, event: &Event<T>) {
+        match event {
+            Event::WindowEvent {
+                event: WindowEvent::KeyboardInput { input, .. },
+                ..
+            } => {
+                if let Some(keycode) = input.virtual_keycode {
+                    match input.state {
+                        ElementState::Pressed => self.pressed_keys.insert(keycode),
+                        ElementState::Released => self.pressed_keys.remove(&keycode),
+                    };
+                }
+            }
+            Event::WindowEvent {
+                event: WindowEvent::MouseInput { button, state, .. },
+                ..
+            } => {
+                match state {
+                    ElementState::Pressed => self.pressed_buttons.insert(*button),
+                    ElementState::Released => self.pressed_buttons.remove(button),
+                };
+            }
+            // Keys held while the window loses focus never report a release
+            Event::WindowEvent {
+                event: WindowEvent::Focused(false),
+                ..
+            } => {
+                self.pressed_keys.clear();
+                self.pressed_buttons.clear();
+            }
+            // Raw motion keeps coming when the cursor hits the edge of the window
+            Event::DeviceEvent {
+                event: DeviceEvent::MouseMotion { delta: (dx, dy) },
+                ..
+            } => {
+                self.mouse_delta.0 += dx;
+                self.mouse_delta.1 += dy;
+            }
+            _ => {}
+        }
+    }
+
+    pub fn is_pressed(&self, keycode: VirtualKeyCode) -> bool {
+        self.pressed_keys.contains(&keycode)
+    }
+
+    pub fn is_mouse_pressed(&self, button: MouseButton) -> bool {
+        self.pressed_buttons.contains(&button)
+    }
+
+    // How far the mouse moved since the last end_frame
+    pub fn mouse_delta(&self) -> (f64, f64) {
+        self.mouse_delta
+    }
+
+    pub fn end_frame(&mut self) {
+        self.mouse_delta = (0.0, 0.0);
+    }
+}
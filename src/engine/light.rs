@@ -1,22 +1,75 @@
-use ash::vk;
+use std::collections::HashMap;
+
 use gpu_allocator::vulkan::Allocator;
 use nalgebra as na;
 use crate::engine::allocator::VkAllocator;
 use crate::engine::buffer::EngineBuffer;
+use crate::engine::error::EngineError;
+use crate::engine::model::InvalidHandle;
+
+pub const DEFAULT_MAX_LIGHTS: usize = 64;
+
+#[derive(Debug, Clone)]
+pub enum LightError {
+    TooManyLights { max_lights: usize },
+    // The shader fades spot lights out with smoothstep(cos(outer_cone), cos(inner_cone), ..),
+    // which is undefined unless inner_cone < outer_cone
+    InvalidCone { inner_cone: f32, outer_cone: f32 },
+    ZeroDirection,
+}
+
+impl std::fmt::Display for LightError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LightError::TooManyLights { max_lights } => write!(f, "no room for more than {} lights", max_lights),
+            LightError::InvalidCone { inner_cone, outer_cone } => write!(
+                f,
+                "spot light inner cone {} has to be smaller than its outer cone {}",
+                inner_cone,
+                outer_cone
+            ),
+            LightError::ZeroDirection => write!(f, "spot light direction has zero length"),
+        }
+    }
+}
+impl std::error::Error for LightError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
 
 pub struct DirectionalLight {
     pub direction: na::Vector3<f32>,
     pub illuminance: [f32; 3],
 }
 
+// Without a range the light falls off with the inverse square of the distance forever,
+// with one it fades out smoothly and reaches zero at the range
 pub struct PointLight {
     pub position: na::Point3<f32>,
     pub luminous_flux: [f32; 3],
+    pub range: Option<f32>,
+}
+
+// Cone angles are in radians, measured from the direction to the cone's edge
+pub struct SpotLight {
+    pub position: na::Point3<f32>,
+    pub direction: na::Vector3<f32>,
+    pub luminous_flux: [f32; 3],
+    pub inner_cone: f32,
+    pub outer_cone: f32,
 }
 
 pub enum Light {
     Directional(DirectionalLight),
     Point(PointLight),
+    Spot(SpotLight),
+}
+
+impl From<SpotLight> for Light {
+    fn from(s: SpotLight) -> Self {
+        Light::Spot(s)
+    }
 }
 
 impl From<PointLight> for Light {
@@ -31,95 +84,374 @@ impl From<DirectionalLight> for Light {
     }
 }
 
+#[allow(dead_code)]
+pub enum LightMut<'a> {
+    Directional(&'a mut DirectionalLight),
+    Point(&'a mut PointLight),
+    Spot(&'a mut SpotLight),
+}
+
+// Layouts of the light storage buffer as shader.frag reads it (std430): the header, then all
+// directional, point and spot lights in that order. Every vec3 is padded to 16 bytes.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct GpuLightHeader {
+    pub num_directional: f32,
+    pub num_point: f32,
+    pub num_spot: f32,
+    pub _padding: f32,
+    pub ambient_color: [f32; 3],
+    pub ambient_intensity: f32,
+}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct GpuDirectionalLight {
+    pub direction: [f32; 3],
+    pub _padding0: f32,
+    pub illuminance: [f32; 3],
+    pub _padding1: f32,
+}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct GpuPointLight {
+    pub position: [f32; 3],
+    pub _padding0: f32,
+    pub luminous_flux: [f32; 3],
+    // 0 for no range
+    pub range: f32,
+}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct GpuSpotLight {
+    pub position: [f32; 3],
+    pub _padding0: f32,
+    pub direction: [f32; 3],
+    pub _padding1: f32,
+    pub luminous_flux: [f32; 3],
+    pub _padding2: f32,
+    pub cos_inner: f32,
+    pub cos_outer: f32,
+    pub _padding3: [f32; 2],
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum LightKind {
+    Directional,
+    Point,
+    Spot,
+}
+
 pub struct LightManager {
     directional_lights: Vec<DirectionalLight>,
     point_lights: Vec<PointLight>,
+    spot_lights: Vec<SpotLight>,
+    directional_handles: Vec<usize>,
+    point_handles: Vec<usize>,
+    spot_handles: Vec<usize>,
+    handle_to_light: HashMap<usize, (LightKind, usize)>,
+    next_handle: usize,
+    max_lights: usize,
+    ambient_color: [f32; 3],
+    ambient_intensity: f32,
 }
 
 impl Default for LightManager {
     fn default() -> Self {
+        LightManager::with_capacity(DEFAULT_MAX_LIGHTS)
+    }
+}
+
+#[allow(dead_code)]
+impl LightManager {
+    // The light buffer has to be created with buffer_size(max_lights)
+    pub fn with_capacity(max_lights: usize) -> Self {
         LightManager {
             directional_lights: vec![],
             point_lights: vec![],
+            spot_lights: vec![],
+            directional_handles: vec![],
+            point_handles: vec![],
+            spot_handles: vec![],
+            handle_to_light: HashMap::new(),
+            next_handle: 0,
+            max_lights,
+            ambient_color: [1.0, 1.0, 1.0],
+            ambient_intensity: 0.0,
         }
     }
-}
 
-impl LightManager {
-    pub fn add_light<T: Into<Light>>(&mut self, l: T) {
+    pub fn max_lights(&self) -> usize {
+        self.max_lights
+    }
+
+    // Light reaching every surface regardless of the other lights, off by default
+    pub fn set_ambient(&mut self, color: [f32; 3], intensity: f32) {
+        self.ambient_color = color;
+        self.ambient_intensity = intensity;
+    }
+
+    pub fn ambient(&self) -> ([f32; 3], f32) {
+        (self.ambient_color, self.ambient_intensity)
+    }
+
+    pub fn add_light<T: Into<Light>>(&mut self, l: T) -> Result<usize, LightError> {
         use Light::*;
 
-        match l.into() {
+        if self.handle_to_light.len() >= self.max_lights {
+            return Err(LightError::TooManyLights { max_lights: self.max_lights });
+        }
+
+        // Checked before anything is stored, so a rejected light leaves no trace
+        let l = match l.into() {
+            Spot(sl) => {
+                if sl.inner_cone >= sl.outer_cone {
+                    return Err(LightError::InvalidCone {
+                        inner_cone: sl.inner_cone,
+                        outer_cone: sl.outer_cone,
+                    });
+                }
+
+                let direction = sl.direction.try_normalize(f32::EPSILON).ok_or(LightError::ZeroDirection)?;
+
+                Spot(SpotLight { direction, ..sl })
+            }
+            l => l,
+        };
+
+        let handle = self.next_handle;
+        self.next_handle += 1;
+
+        let light = match l {
             Directional(dl) => {
                 self.directional_lights.push(dl);
+                self.directional_handles.push(handle);
+                (LightKind::Directional, self.directional_lights.len() - 1)
             },
             Point(pl) => {
                 self.point_lights.push(pl);
+                self.point_handles.push(handle);
+                (LightKind::Point, self.point_lights.len() - 1)
+            }
+            Spot(sl) => {
+                self.spot_lights.push(sl);
+                self.spot_handles.push(handle);
+                (LightKind::Spot, self.spot_lights.len() - 1)
+            }
+        };
+
+        self.handle_to_light.insert(handle, light);
+
+        Ok(handle)
+    }
+
+    pub fn get_light_mut(&mut self, handle: usize) -> Option<LightMut<'_>> {
+        let &(kind, index) = self.handle_to_light.get(&handle)?;
+
+        match kind {
+            LightKind::Directional => self.directional_lights.get_mut(index).map(LightMut::Directional),
+            LightKind::Point => self.point_lights.get_mut(index).map(LightMut::Point),
+            LightKind::Spot => self.spot_lights.get_mut(index).map(LightMut::Spot),
+        }
+    }
+
+    pub fn remove_light(&mut self, handle: usize) -> Result<Light, InvalidHandle> {
+        let (kind, index) = self.handle_to_light.remove(&handle).ok_or(InvalidHandle)?;
+
+        // swap_remove moves the last light of the same kind into the freed slot
+        let moved_handle = match kind {
+            LightKind::Directional => {
+                self.directional_handles.swap_remove(index);
+                self.directional_handles.get(index).copied()
+            }
+            LightKind::Point => {
+                self.point_handles.swap_remove(index);
+                self.point_handles.get(index).copied()
             }
+            LightKind::Spot => {
+                self.spot_handles.swap_remove(index);
+                self.spot_handles.get(index).copied()
+            }
+        };
+
+        if let Some(moved_handle) = moved_handle {
+            self.handle_to_light.insert(moved_handle, (kind, index));
         }
+
+        Ok(match kind {
+            LightKind::Directional => Light::Directional(self.directional_lights.swap_remove(index)),
+            LightKind::Point => Light::Point(self.point_lights.swap_remove(index)),
+            LightKind::Spot => Light::Spot(self.spot_lights.swap_remove(index)),
+        })
     }
 
+    // Enough for max_lights of any kind, spot lights being the largest
+    pub fn buffer_size(max_lights: usize) -> u64 {
+        let light_size = std::mem::size_of::<GpuDirectionalLight>()
+            .max(std::mem::size_of::<GpuPointLight>())
+            .max(std::mem::size_of::<GpuSpotLight>());
+
+        (std::mem::size_of::<GpuLightHeader>() + max_lights * light_size) as u64
+    }
+
+    // The contents of the light buffer, see GpuLightHeader
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(
+            std::mem::size_of::<GpuLightHeader>()
+                + self.directional_lights.len() * std::mem::size_of::<GpuDirectionalLight>()
+                + self.point_lights.len() * std::mem::size_of::<GpuPointLight>()
+                + self.spot_lights.len() * std::mem::size_of::<GpuSpotLight>()
+        );
+
+        push_bytes(&mut data, &GpuLightHeader {
+            num_directional: self.directional_lights.len() as f32,
+            num_point: self.point_lights.len() as f32,
+            num_spot: self.spot_lights.len() as f32,
+            _padding: 0.0,
+            ambient_color: self.ambient_color,
+            ambient_intensity: self.ambient_intensity,
+        });
+
+        for dl in &self.directional_lights {
+            push_bytes(&mut data, &GpuDirectionalLight {
+                direction: dl.direction.into(),
+                _padding0: 0.0,
+                illuminance: dl.illuminance,
+                _padding1: 0.0,
+            });
+        }
+
+        for pl in &self.point_lights {
+            push_bytes(&mut data, &GpuPointLight {
+                position: pl.position.coords.into(),
+                _padding0: 0.0,
+                luminous_flux: pl.luminous_flux,
+                range: pl.range.unwrap_or(0.0),
+            });
+        }
+
+        for sl in &self.spot_lights {
+            push_bytes(&mut data, &GpuSpotLight {
+                position: sl.position.coords.into(),
+                _padding0: 0.0,
+                direction: sl.direction.into(),
+                _padding1: 0.0,
+                luminous_flux: sl.luminous_flux,
+                _padding2: 0.0,
+                cos_inner: sl.inner_cone.cos(),
+                cos_outer: sl.outer_cone.cos(),
+                _padding3: [0.0; 2],
+            });
+        }
+
+        data
+    }
+
+    // The buffer is sized for max_lights, so it's never reallocated and the descriptor sets
+    // pointing at it stay valid
     pub fn update_buffer(
         &self,
-        device: &ash::Device,
         allocator: &mut VkAllocator,
         buffer: &mut EngineBuffer,
-        descriptor_sets_light: &mut [vk::DescriptorSet],
-    ) -> Result<(), gpu_allocator::AllocationError> {
-        let mut data: Vec<f32> = vec![];
+    ) -> Result<(), EngineError> {
+        buffer.fill(allocator, &self.to_bytes())
+    }
+}
 
-        data.push(self.directional_lights.len() as f32);
-        data.push(self.point_lights.len() as f32);
-        data.push(0.0);
-        data.push(0.0);
+// The Gpu* structs are made of f32s with explicit padding, so all of their bytes are initialized
+fn push_bytes<T: Copy>(data: &mut Vec<u8>, value: &T) {
+    let bytes = unsafe {
+        std::slice::from_raw_parts(value as *const T as *const u8, std::mem::size_of::<T>())
+    };
 
-        for dl in &self.directional_lights {
-            data.push(dl.direction.x);
-            data.push(dl.direction.y);
-            data.push(dl.direction.z);
-            data.push(0.0);
-            data.push(dl.illuminance[0]);
-            data.push(dl.illuminance[1]);
-            data.push(dl.illuminance[2]);
-            data.push(0.0);
-        }
+    data.extend_from_slice(bytes);
+}
 
-        for pl in &self.point_lights {
-            data.push(pl.position.x);
-            data.push(pl.position.y);
-            data.push(pl.position.z);
-            data.push(0.0);
-            data.push(pl.luminous_flux[0]);
-            data.push(pl.luminous_flux[1]);
-            data.push(pl.luminous_flux[2]);
-            data.push(0.0);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point_light(x: f32) -> PointLight {
+        PointLight {
+            position: na::Point3::new(x, 0.0, 0.0),
+            luminous_flux: [100.0, 100.0, 100.0],
+            range: None,
         }
+    }
 
-        let old_size = buffer.size_in_bytes;
+    #[test]
+    fn gpu_layout_matches_shader() {
+        // shader.frag reads these with std430, where every vec3 takes 16 bytes
+        assert_eq!(std::mem::size_of::<GpuLightHeader>(), 32);
+        assert_eq!(std::mem::size_of::<GpuDirectionalLight>(), 32);
+        assert_eq!(std::mem::size_of::<GpuPointLight>(), 32);
+        assert_eq!(std::mem::size_of::<GpuSpotLight>(), 64);
+    }
 
-        buffer.fill(allocator, &data)?;
+    #[test]
+    fn add_light_fails_at_max_lights() {
+        let mut lights = LightManager::with_capacity(2);
 
-        if old_size != buffer.size_in_bytes {
-            for desc_set in descriptor_sets_light {
-                let buffer_infos = [vk::DescriptorBufferInfo {
-                    buffer: buffer.buffer,
-                    offset: 0,
-                    range: 4 * data.len() as u64,
-                }];
+        lights.add_light(point_light(1.0)).unwrap();
+        let handle = lights.add_light(point_light(2.0)).unwrap();
 
-                let desc_sets_write = [vk::WriteDescriptorSet::builder()
-                    .dst_set(*desc_set)
-                    .dst_binding(0)
-                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
-                    .buffer_info(&buffer_infos)
-                    .build()];
+        let error = lights.add_light(point_light(3.0)).unwrap_err();
+        assert!(matches!(error, LightError::TooManyLights { max_lights: 2 }));
 
-                unsafe {
-                    device.update_descriptor_sets(&desc_sets_write, &[])
-                };
-            }
+        // Removing one makes room again
+        lights.remove_light(handle).unwrap();
+        lights.add_light(point_light(3.0)).unwrap();
+    }
+
+    #[test]
+    fn add_light_rejects_invalid_spot_lights() {
+        let spot_light = |direction: na::Vector3<f32>, inner_cone: f32, outer_cone: f32| SpotLight {
+            position: na::Point3::origin(),
+            direction,
+            luminous_flux: [100.0, 100.0, 100.0],
+            inner_cone,
+            outer_cone,
+        };
+        let down = na::Vector3::new(0.0, 2.0, 0.0);
+
+        let mut lights = LightManager::default();
+
+        assert!(matches!(lights.add_light(spot_light(down, 0.5, 0.5)), Err(LightError::InvalidCone { .. })));
+        assert!(matches!(lights.add_light(spot_light(down, 0.6, 0.5)), Err(LightError::InvalidCone { .. })));
+        assert!(matches!(lights.add_light(spot_light(na::Vector3::zeros(), 0.3, 0.5)), Err(LightError::ZeroDirection)));
+        assert_eq!(lights.to_bytes().len(), std::mem::size_of::<GpuLightHeader>());
+
+        let handle = lights.add_light(spot_light(down, 0.3, 0.5)).unwrap();
+
+        match lights.get_light_mut(handle) {
+            Some(LightMut::Spot(sl)) => assert_eq!(sl.direction, na::Vector3::new(0.0, 1.0, 0.0)),
+            _ => panic!("expected a spot light"),
         }
+    }
 
-        Ok(())
+    #[test]
+    fn to_bytes_length() {
+        let mut lights = LightManager::default();
+
+        lights.add_light(DirectionalLight {
+            direction: na::Vector3::new(0.0, 1.0, 0.0),
+            illuminance: [1.0, 1.0, 1.0],
+        }).unwrap();
+        lights.add_light(point_light(1.0)).unwrap();
+        lights.add_light(point_light(2.0)).unwrap();
+        lights.add_light(SpotLight {
+            position: na::Point3::origin(),
+            direction: na::Vector3::new(0.0, 0.0, 1.0),
+            luminous_flux: [100.0, 100.0, 100.0],
+            inner_cone: 0.3,
+            outer_cone: 0.5,
+        }).unwrap();
+
+        assert_eq!(
+            lights.to_bytes().len(),
+            std::mem::size_of::<GpuLightHeader>() + 32 + 2 * 32 + 64
+        );
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,519 @@
+use std::collections::HashMap;
+
+use crate::engine::model::{InstanceData, Model, TexturedInstanceData, TexturedVertexData, VertexData};
+
+#[derive(Debug)]
+pub enum ModelLoadError {
+    Io(std::io::Error),
+    Parse { line: usize, message: String },
+    Gltf(gltf::Error),
+    Image(image::ImageError),
+    Unsupported(String),
+}
+
+impl std::fmt::Display for ModelLoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ModelLoadError::Io(error) => write!(f, "failed to read model: {}", error),
+            ModelLoadError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+            ModelLoadError::Gltf(error) => write!(f, "failed to load glTF: {}", error),
+            ModelLoadError::Image(error) => write!(f, "failed to load heightmap: {}", error),
+            ModelLoadError::Unsupported(message) => write!(f, "unsupported model: {}", message),
+        }
+    }
+}
+impl std::error::Error for ModelLoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ModelLoadError::Io(error) => Some(error),
+            ModelLoadError::Gltf(error) => Some(error),
+            ModelLoadError::Image(error) => Some(error),
+            ModelLoadError::Parse { .. } | ModelLoadError::Unsupported(_) => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ModelLoadError {
+    fn from(error: std::io::Error) -> Self {
+        ModelLoadError::Io(error)
+    }
+}
+
+impl From<gltf::Error> for ModelLoadError {
+    fn from(error: gltf::Error) -> Self {
+        ModelLoadError::Gltf(error)
+    }
+}
+
+impl From<image::ImageError> for ModelLoadError {
+    fn from(error: image::ImageError) -> Self {
+        ModelLoadError::Image(error)
+    }
+}
+
+// Material properties matching the arguments of InstanceData::from_props
+#[allow(dead_code)]
+#[derive(Copy, Clone, Debug)]
+pub struct Material {
+    pub color: [f32; 3],
+    pub metallic: f32,
+    pub roughness: f32,
+}
+
+// Where a vertex gets its normal from: a `vn` line, or the face it belongs to if there are none
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+enum NormalSource {
+    File(usize),
+    Face(usize),
+}
+
+#[allow(dead_code)]
+impl Model<VertexData, InstanceData> {
+    pub fn from_obj<P: AsRef<std::path::Path>>(path: P) -> Result<Self, ModelLoadError> {
+        let source = std::fs::read_to_string(path)?;
+
+        Self::parse_obj(&source)
+    }
+
+    // Only the first primitive of the first mesh is loaded, without animation or scene hierarchy
+    pub fn from_gltf<P: AsRef<std::path::Path>>(path: P) -> Result<(Self, Material), ModelLoadError> {
+        let (document, buffers, _) = gltf::import(path)?;
+
+        let primitive = first_primitive(&document)?;
+
+        let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+
+        let positions: Vec<[f32; 3]> = reader.read_positions()
+            .ok_or_else(|| ModelLoadError::Unsupported("primitive has no positions".to_string()))?
+            .collect();
+
+        let indices = checked_indices(reader.read_indices(), positions.len())?;
+
+        let normals: Option<Vec<[f32; 3]>> = reader.read_normals().map(|normals| normals.collect());
+
+        let (vertex_data, index_data) = vertices_with_normals(&positions, normals, indices)?;
+
+        Ok((Self::from_mesh(vertex_data, index_data), material_of(&primitive)))
+    }
+
+    // One vertex per pixel on the XZ plane, the longer side of the image spanning -scale_xz..scale_xz.
+    // Black is at y = 0 and white scale_y above it (towards -y).
+    pub fn from_heightmap<P: AsRef<std::path::Path>>(
+        path: P,
+        scale_xz: f32,
+        scale_y: f32,
+    ) -> Result<Self, ModelLoadError> {
+        let heightmap = image::open(path)?.to_luma16();
+        let (width, height) = heightmap.dimensions();
+
+        if width < 2 || height < 2 {
+            return Err(ModelLoadError::Unsupported(format!("{}x{} heightmap", width, height)));
+        }
+
+        let spacing = 2.0 * scale_xz / (width.max(height) - 1) as f32;
+        let x_offset = 0.5 * (width - 1) as f32 * spacing;
+        let z_offset = 0.5 * (height - 1) as f32 * spacing;
+
+        let height_at = |x: u32, z: u32| {
+            heightmap.get_pixel(x, z)[0] as f32 / u16::MAX as f32 * scale_y
+        };
+
+        let mut vertex_data = Vec::with_capacity((width * height) as usize);
+
+        for z in 0..height {
+            for x in 0..width {
+                // Central differences inside the image, one-sided ones along its border
+                let (left, right) = (x.saturating_sub(1), (x + 1).min(width - 1));
+                let (near, far) = (z.saturating_sub(1), (z + 1).min(height - 1));
+
+                let slope_x = (height_at(right, z) - height_at(left, z)) / ((right - left) as f32 * spacing);
+                let slope_z = (height_at(x, far) - height_at(x, near)) / ((far - near) as f32 * spacing);
+
+                vertex_data.push(VertexData {
+                    position: [
+                        x as f32 * spacing - x_offset,
+                        -height_at(x, z),
+                        z as f32 * spacing - z_offset,
+                    ],
+                    normal: normalize([-slope_x, -1.0, -slope_z]),
+                });
+            }
+        }
+
+        let mut index_data = Vec::with_capacity(((width - 1) * (height - 1) * 6) as usize);
+
+        for z in 0..height - 1 {
+            for x in 0..width - 1 {
+                let near_left = z * width + x;
+                let near_right = near_left + 1;
+                let far_left = near_left + width;
+                let far_right = far_left + 1;
+
+                index_data.extend_from_slice(&[
+                    near_left, near_right, far_left,
+                    near_right, far_right, far_left,
+                ]);
+            }
+        }
+
+        Ok(Self::from_mesh(vertex_data, index_data))
+    }
+
+    pub fn parse_obj(source: &str) -> Result<Self, ModelLoadError> {
+        let mut positions: Vec<[f32; 3]> = vec![];
+        let mut normals: Vec<[f32; 3]> = vec![];
+        let mut face_normals: Vec<[f32; 3]> = vec![];
+
+        let mut vertex_data = vec![];
+        let mut index_data = vec![];
+        let mut vertex_indices = HashMap::<(usize, NormalSource), u32>::new();
+
+        for (i, line) in source.lines().enumerate() {
+            let line_number = i + 1;
+            let mut tokens = line.split_whitespace();
+
+            match tokens.next() {
+                Some("v") => positions.push(parse_vector(tokens, line_number)?),
+                Some("vn") => normals.push(parse_vector(tokens, line_number)?),
+                Some("f") => {
+                    let mut corners = vec![];
+
+                    for token in tokens {
+                        corners.push(parse_corner(token, positions.len(), normals.len(), line_number)?);
+                    }
+
+                    if corners.len() < 3 {
+                        return Err(parse_error(line_number, "face needs at least 3 vertices"));
+                    }
+
+                    let face = face_normals.len();
+                    face_normals.push(face_normal(
+                        positions[corners[0].0],
+                        positions[corners[1].0],
+                        positions[corners[2].0],
+                    ));
+
+                    let mut face_indices = Vec::with_capacity(corners.len());
+
+                    for (position, normal) in corners {
+                        let normal_source = match normal {
+                            Some(n) => NormalSource::File(n),
+                            None => NormalSource::Face(face),
+                        };
+
+                        let index = *vertex_indices.entry((position, normal_source)).or_insert_with(|| {
+                            let normal = match normal_source {
+                                NormalSource::File(n) => normals[n],
+                                NormalSource::Face(f) => face_normals[f],
+                            };
+
+                            vertex_data.push(VertexData {
+                                position: positions[position],
+                                normal,
+                            });
+
+                            (vertex_data.len() - 1) as u32
+                        });
+
+                        face_indices.push(index);
+                    }
+
+                    // Polygons are triangulated as a fan around their first vertex
+                    for i in 1..face_indices.len() - 1 {
+                        index_data.extend_from_slice(&[face_indices[0], face_indices[i], face_indices[i + 1]]);
+                    }
+                }
+                // Texture coordinates, groups, materials etc. aren't needed for VertexData
+                _ => {}
+            }
+        }
+
+        Ok(Self::from_mesh(vertex_data, index_data))
+    }
+}
+
+#[allow(dead_code)]
+impl Model<TexturedVertexData, TexturedInstanceData> {
+    // Like Model::from_gltf, but with the first set of texture coordinates. Tangents are taken
+    // from the file if it has them and generated otherwise, see compute_tangents.
+    pub fn from_gltf_textured<P: AsRef<std::path::Path>>(path: P) -> Result<(Self, Material), ModelLoadError> {
+        let (document, buffers, _) = gltf::import(path)?;
+
+        let primitive = first_primitive(&document)?;
+
+        let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+
+        let positions: Vec<[f32; 3]> = reader.read_positions()
+            .ok_or_else(|| ModelLoadError::Unsupported("primitive has no positions".to_string()))?
+            .collect();
+
+        let normals: Vec<[f32; 3]> = reader.read_normals()
+            .ok_or_else(|| ModelLoadError::Unsupported("primitive has no normals".to_string()))?
+            .collect();
+
+        let texcoords: Vec<[f32; 2]> = reader.read_tex_coords(0)
+            .ok_or_else(|| ModelLoadError::Unsupported("primitive has no texture coordinates".to_string()))?
+            .into_f32()
+            .collect();
+
+        if normals.len() != positions.len() || texcoords.len() != positions.len() {
+            return Err(ModelLoadError::Unsupported("attributes have different lengths".to_string()));
+        }
+
+        let indices = checked_indices(reader.read_indices(), positions.len())?;
+
+        let tangents: Option<Vec<[f32; 4]>> = reader.read_tangents().map(|tangents| tangents.collect());
+
+        let vertex_data = positions
+            .iter()
+            .zip(&normals)
+            .zip(&texcoords)
+            .enumerate()
+            .map(|(i, ((&position, &normal), &texcoord))| TexturedVertexData {
+                position,
+                texcoord,
+                normal,
+                tangent: tangents.as_ref().and_then(|tangents| tangents.get(i).copied()).unwrap_or([0.0; 4]),
+            })
+            .collect();
+
+        let mut model = Self::from_mesh(vertex_data, indices);
+
+        if tangents.is_none() {
+            model.compute_tangents();
+        }
+
+        Ok((model, material_of(&primitive)))
+    }
+}
+
+fn first_primitive(document: &gltf::Document) -> Result<gltf::Primitive, ModelLoadError> {
+    let mesh = document.meshes().next()
+        .ok_or_else(|| ModelLoadError::Unsupported("no meshes".to_string()))?;
+
+    let primitive = mesh.primitives().next()
+        .ok_or_else(|| ModelLoadError::Unsupported("mesh has no primitives".to_string()))?;
+
+    if primitive.mode() != gltf::mesh::Mode::Triangles {
+        return Err(ModelLoadError::Unsupported(format!("primitive mode {:?}", primitive.mode())));
+    }
+
+    Ok(primitive)
+}
+
+// Non-indexed primitives get an index per vertex
+fn checked_indices(
+    indices: Option<gltf::mesh::util::ReadIndices>,
+    vertex_count: usize,
+) -> Result<Vec<u32>, ModelLoadError> {
+    let indices: Vec<u32> = match indices {
+        Some(indices) => indices.into_u32().collect(),
+        None => (0..vertex_count as u32).collect(),
+    };
+
+    if let Some(&index) = indices.iter().find(|&&index| index as usize >= vertex_count) {
+        return Err(ModelLoadError::Unsupported(format!("index {} out of range", index)));
+    }
+
+    Ok(indices)
+}
+
+// Pairs every position with its normal. Without normals glTF expects flat shading, so every
+// triangle gets its own vertices.
+fn vertices_with_normals(
+    positions: &[[f32; 3]],
+    normals: Option<Vec<[f32; 3]>>,
+    indices: Vec<u32>,
+) -> Result<(Vec<VertexData>, Vec<u32>), ModelLoadError> {
+    match normals {
+        Some(normals) => {
+            if normals.len() != positions.len() {
+                return Err(ModelLoadError::Unsupported("attributes have different lengths".to_string()));
+            }
+
+            let vertex_data = positions
+                .iter()
+                .zip(normals)
+                .map(|(&position, normal)| VertexData { position, normal })
+                .collect();
+
+            Ok((vertex_data, indices))
+        }
+        None => {
+            let vertex_data: Vec<VertexData> = indices
+                .chunks_exact(3)
+                .flat_map(|triangle| {
+                    let [a, b, c] = [
+                        positions[triangle[0] as usize],
+                        positions[triangle[1] as usize],
+                        positions[triangle[2] as usize],
+                    ];
+                    let normal = face_normal(a, b, c);
+
+                    [a, b, c].map(|position| VertexData { position, normal })
+                })
+                .collect();
+
+            let index_data = (0..vertex_data.len() as u32).collect();
+
+            Ok((vertex_data, index_data))
+        }
+    }
+}
+
+fn material_of(primitive: &gltf::Primitive) -> Material {
+    let pbr = primitive.material().pbr_metallic_roughness();
+    let [r, g, b, _] = pbr.base_color_factor();
+
+    Material {
+        color: [r, g, b],
+        metallic: pbr.metallic_factor(),
+        roughness: pbr.roughness_factor(),
+    }
+}
+
+fn parse_error(line: usize, message: &str) -> ModelLoadError {
+    ModelLoadError::Parse {
+        line,
+        message: message.to_string(),
+    }
+}
+
+fn parse_vector<'a>(
+    tokens: impl Iterator<Item = &'a str>,
+    line: usize,
+) -> Result<[f32; 3], ModelLoadError> {
+    let values = tokens
+        .take(3)
+        .map(|t| t.parse::<f32>().map_err(|_| parse_error(line, &format!("invalid number '{}'", t))))
+        .collect::<Result<Vec<f32>, ModelLoadError>>()?;
+
+    if values.len() < 3 {
+        return Err(parse_error(line, "expected 3 components"));
+    }
+
+    Ok([values[0], values[1], values[2]])
+}
+
+// OBJ indices are 1-based, negative ones count back from the most recent element
+fn resolve_index(token: &str, count: usize, line: usize) -> Result<usize, ModelLoadError> {
+    let index = token
+        .parse::<i64>()
+        .map_err(|_| parse_error(line, &format!("invalid index '{}'", token)))?;
+
+    let resolved = if index < 0 {
+        count as i64 + index
+    } else {
+        index - 1
+    };
+
+    if resolved < 0 || resolved >= count as i64 {
+        return Err(parse_error(line, &format!("index {} out of range", index)));
+    }
+
+    Ok(resolved as usize)
+}
+
+// A face corner is `v`, `v/vt`, `v//vn` or `v/vt/vn`
+fn parse_corner(
+    token: &str,
+    position_count: usize,
+    normal_count: usize,
+    line: usize,
+) -> Result<(usize, Option<usize>), ModelLoadError> {
+    let mut parts = token.split('/');
+
+    let position = resolve_index(parts.next().unwrap_or(""), position_count, line)?;
+
+    let normal = match parts.nth(1) {
+        Some(n) if !n.is_empty() => Some(resolve_index(n, normal_count, line)?),
+        _ => None,
+    };
+
+    Ok((position, normal))
+}
+
+fn face_normal(a: [f32; 3], b: [f32; 3], c: [f32; 3]) -> [f32; 3] {
+    let ab = [b[0] - a[0], b[1] - a[1], b[2] - a[2]];
+    let ac = [c[0] - a[0], c[1] - a[1], c[2] - a[2]];
+
+    let n = [
+        ab[1] * ac[2] - ab[2] * ac[1],
+        ab[2] * ac[0] - ab[0] * ac[2],
+        ab[0] * ac[1] - ab[1] * ac[0],
+    ];
+
+    normalize(n)
+}
+
+fn normalize(n: [f32; 3]) -> [f32; 3] {
+    let l = (n[0] * n[0] + n[1] * n[1] + n[2] * n[2]).sqrt();
+
+    // Degenerate faces have no direction, any unit vector is as good as another
+    if l == 0.0 {
+        [0.0, 1.0, 0.0]
+    } else {
+        [n[0] / l, n[1] / l, n[2] / l]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SQUARE: &str = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n";
+
+    fn parse_error_line(source: &str) -> usize {
+        match Model::parse_obj(source) {
+            Err(ModelLoadError::Parse { line, .. }) => line,
+            Err(error) => panic!("expected a parse error, got {}", error),
+            Ok(_) => panic!("expected a parse error"),
+        }
+    }
+
+    #[test]
+    fn obj_quad_is_fan_triangulated() {
+        let model = Model::parse_obj(&format!("{}f 1 2 3 4\n", SQUARE)).unwrap();
+
+        assert_eq!(model.vertex_data.len(), 4);
+        assert_eq!(model.index_data, vec![0, 1, 2, 0, 2, 3]);
+    }
+
+    #[test]
+    fn obj_shared_corners_are_deduplicated() {
+        let source = format!("{}vn 0 0 1\nf 1//1 2//1 3//1\nf 1//1 3//1 4//1\n", SQUARE);
+        let model = Model::parse_obj(&source).unwrap();
+
+        assert_eq!(model.vertex_data.len(), 4);
+        assert_eq!(model.index_data, vec![0, 1, 2, 0, 2, 3]);
+    }
+
+    #[test]
+    fn obj_faces_without_normals_get_the_face_normal() {
+        let model = Model::parse_obj("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n").unwrap();
+
+        for vertex in &model.vertex_data {
+            assert_eq!(vertex.normal, [0.0, 0.0, 1.0]);
+        }
+    }
+
+    #[test]
+    fn obj_errors_report_their_line() {
+        assert_eq!(parse_error_line(&format!("{}f 1 2\n", SQUARE)), 5);
+        assert_eq!(parse_error_line(&format!("{}\nf 1 2 5\n", SQUARE)), 6);
+        assert_eq!(parse_error_line(&format!("{}f 1 2 -5\n", SQUARE)), 5);
+    }
+
+    #[test]
+    fn gltf_normals_must_match_positions() {
+        let positions = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]];
+        let normals = vec![[0.0, 0.0, 1.0]; 2];
+
+        let result = vertices_with_normals(&positions, Some(normals), vec![0, 1, 2]);
+        assert!(matches!(result, Err(ModelLoadError::Unsupported(_))));
+
+        let (vertex_data, index_data) = vertices_with_normals(&positions, Some(vec![[0.0, 0.0, 1.0]; 3]), vec![0, 1, 2]).unwrap();
+        assert_eq!(vertex_data.len(), 3);
+        assert_eq!(index_data, vec![0, 1, 2]);
+    }
+}
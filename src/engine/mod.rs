@@ -8,12 +8,21 @@ pub mod pools;
 pub mod model;
 
 pub mod camera;
+pub mod input;
 pub mod light;
 pub mod texture;
 pub mod allocator;
+pub mod breadcrumbs;
+pub mod loader;
+pub mod skybox;
+pub mod render_target;
+pub mod post_process;
+pub mod timer;
+pub mod error;
 
 use std::ffi::{CStr, CString};
 use std::mem::ManuallyDrop;
+use std::path::PathBuf;
 
 use ash::{Device, Entry, Instance, vk};
 use gpu_allocator::vulkan::{Allocator, AllocatorCreateDesc};
@@ -23,14 +32,29 @@ use winit::window::Window;
 use nalgebra as na;
 use crate::engine::allocator::VkAllocator;
 
-use crate::engine::buffer::EngineBuffer;
+use crate::engine::breadcrumbs::{Breadcrumbs, Checkpoint};
+use crate::engine::buffer::{EngineBuffer, UploadQueues};
+use crate::engine::camera::{Camera, CameraUniform};
 use crate::engine::debug::EngineDebug;
-use crate::engine::model::{InstanceData, Model, TexturedInstanceData, TexturedVertexData, VertexData};
-use crate::engine::pipeline::EnginePipeline;
+use crate::engine::error::EngineError;
+use crate::engine::light::LightManager;
+use crate::engine::model::{GpuInstanceData, InstanceData, Model, TexturedInstanceData, TexturedVertexData, ToGpu, VertexData};
+use crate::engine::pipeline::{EnginePipeline, PushConstants};
 use crate::engine::pools::Pools;
+use crate::engine::post_process::{PostProcess, HDR_FORMAT};
 use crate::engine::queue_families::QueueFamilies;
+use crate::engine::render_target::RenderTarget;
+use crate::engine::skybox::Skybox;
 use crate::engine::surface::EngineSurface;
-use crate::engine::swapchain::EngineSwapchain;
+use crate::engine::swapchain::{EngineSwapchain, PresentIds, PresentMode, SwapchainConfig};
+use crate::engine::texture::{SamplerConfig, Texture};
+use crate::engine::timer::{FrameTimer, GpuTimer};
+
+// VK_KHR_portability_enumeration is newer than the bundled ash bindings
+const PORTABILITY_ENUMERATION_EXTENSION_NAME: &[u8] = b"VK_KHR_portability_enumeration\0";
+const INSTANCE_CREATE_ENUMERATE_PORTABILITY_BIT: u32 = 0x0000_0001;
+
+const PIPELINE_CACHE_PATH: &str = "pipeline_cache.bin";
 
 unsafe extern "system" fn vulkan_debug_utils_callback(
     message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
@@ -47,6 +71,155 @@ unsafe extern "system" fn vulkan_debug_utils_callback(
     vk::FALSE
 }
 
+#[derive(Debug, Clone)]
+pub enum CaptureError {
+    // The surface doesn't allow copying out of swapchain images
+    TransferNotSupported,
+    UnsupportedFormat(vk::Format),
+}
+
+impl std::fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CaptureError::TransferNotSupported => write!(f, "swapchain images can't be copied from"),
+            CaptureError::UnsupportedFormat(format) => write!(f, "can't capture swapchain format {:?}", format),
+        }
+    }
+}
+impl std::error::Error for CaptureError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+// A screenshot on its way back from the GPU. The copy is recorded into the frame's command
+// buffer while the swapchain image is still acquired, the PNG is written once the frame's
+// fence has signaled.
+pub struct FrameCapture {
+    pub path: PathBuf,
+    pub buffer: EngineBuffer,
+    pub extent: vk::Extent2D,
+    pub bgra: bool,
+    pub frame: usize,
+    pub image_index: usize,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum DeviceSelector {
+    #[default]
+    Auto,
+    Index(usize),
+    NameContains(String),
+}
+
+pub struct VulkanEngineBuilder {
+    app_name: String,
+    app_version: u32,
+    api_version: u32,
+    validation: bool,
+    clear_color: [f32; 4],
+    present_mode: PresentMode,
+    reverse_z: bool,
+    max_lights: usize,
+    max_textures: u32,
+    device_selector: DeviceSelector,
+    present_wait: bool,
+    wireframe: bool,
+    sampler_anisotropy: bool,
+    wide_lines: bool,
+}
+
+#[allow(dead_code)]
+impl VulkanEngineBuilder {
+    pub fn app_name(mut self, app_name: &str) -> VulkanEngineBuilder {
+        self.app_name = app_name.to_string();
+        self
+    }
+
+    pub fn app_version(mut self, major: u32, minor: u32, patch: u32) -> VulkanEngineBuilder {
+        self.app_version = vk::make_api_version(0, major, minor, patch);
+        self
+    }
+
+    // The engine needs at least Vulkan 1.1
+    pub fn api_version(mut self, api_version: u32) -> VulkanEngineBuilder {
+        self.api_version = api_version.max(vk::API_VERSION_1_1);
+        self
+    }
+
+    pub fn validation(mut self, validation: bool) -> VulkanEngineBuilder {
+        self.validation = validation;
+        self
+    }
+
+    pub fn clear_color(mut self, clear_color: [f32; 4]) -> VulkanEngineBuilder {
+        self.clear_color = clear_color;
+        self
+    }
+
+    pub fn present_mode(mut self, present_mode: PresentMode) -> VulkanEngineBuilder {
+        self.present_mode = present_mode;
+        self
+    }
+
+    // The camera has to be built with the same setting
+    pub fn reverse_z(mut self, reverse_z: bool) -> VulkanEngineBuilder {
+        self.reverse_z = reverse_z;
+        self
+    }
+
+    // The light buffer is allocated for this many lights up front
+    pub fn max_lights(mut self, max_lights: usize) -> VulkanEngineBuilder {
+        self.max_lights = max_lights;
+        self
+    }
+
+    // Length of the texture array, instances can use texture indices below it. Indexing it per
+    // instance needs shaderSampledImageArrayNonUniformIndexing, devices without it are never
+    // picked, whatever the device selector.
+    pub fn max_textures(mut self, max_textures: u32) -> VulkanEngineBuilder {
+        self.max_textures = max_textures.max(1);
+        self
+    }
+
+    pub fn device_selector(mut self, device_selector: DeviceSelector) -> VulkanEngineBuilder {
+        self.device_selector = device_selector;
+        self
+    }
+
+    // Tags every present with an id that VulkanEngine::wait_for_present can block on, for
+    // measuring latency. Off by default, and ignored on devices without VK_KHR_present_wait.
+    pub fn present_wait(mut self, present_wait: bool) -> VulkanEngineBuilder {
+        self.present_wait = present_wait;
+        self
+    }
+
+    // Enables fill_mode_non_solid for set_wireframe and Model::wireframe. Without it the
+    // wireframe pipeline draws filled triangles.
+    pub fn wireframe(mut self, wireframe: bool) -> VulkanEngineBuilder {
+        self.wireframe = wireframe;
+        self
+    }
+
+    // Enables sampler_anisotropy, VulkanEngine::sampler_anisotropy clamps to 1.0 without it
+    pub fn sampler_anisotropy(mut self, sampler_anisotropy: bool) -> VulkanEngineBuilder {
+        self.sampler_anisotropy = sampler_anisotropy;
+        self
+    }
+
+    // Enables wide_lines, needed by custom pipelines with line widths other than 1.0
+    pub fn wide_lines(mut self, wide_lines: bool) -> VulkanEngineBuilder {
+        self.wide_lines = wide_lines;
+        self
+    }
+
+    // Requested features the device doesn't support fail with EngineError::MissingFeature
+    pub fn build(self, window: Window) -> Result<VulkanEngine, EngineError> {
+        VulkanEngine::init_with_builder(window, self)
+    }
+}
+
 pub struct VulkanEngine {
     pub window: Window,
     pub entry: Entry,
@@ -61,35 +234,168 @@ pub struct VulkanEngine {
     pub swapchain: EngineSwapchain,
     pub render_pass: vk::RenderPass,
     pub pipeline: EnginePipeline,
+    pub wireframe_pipeline: EnginePipeline,
+    pub transparent_pipeline: EnginePipeline,
+    pub skybox_pipeline: EnginePipeline,
+    pub lit_pipeline: EnginePipeline,
     pub pools: Pools,
     pub graphics_command_buffers: Vec<vk::CommandBuffer>,
     pub allocator: VkAllocator,
     pub models: Vec<Model<TexturedVertexData, TexturedInstanceData>>,
+    pub lit_models: Vec<Model<VertexData, InstanceData>>,
+    pub lights: LightManager,
+    pub textures: Vec<Texture>,
+    pub skybox: Option<Skybox>,
     pub uniform_buffer: EngineBuffer,
     pub descriptor_pool: vk::DescriptorPool,
     pub descriptor_sets_cam: Vec<vk::DescriptorSet>,
     pub descriptor_sets_light: Vec<vk::DescriptorSet>,
     pub descriptor_sets_texture: Vec<vk::DescriptorSet>,
-    //pub light_buffer: EngineBuffer,
+    pub descriptor_set_skybox: vk::DescriptorSet,
+    pub pipeline_cache: vk::PipelineCache,
+    pub wireframe: bool,
+    pub clear_depth: f32,
+    pub clear_stencil: u32,
+    pub present_wait: Option<ash::extensions::khr::PresentWait>,
+    pub present_ids: PresentIds,
+    pub conservative_rasterization: bool,
+    pub conservative_rasterization_supported: bool,
+    pub breadcrumbs: Option<Breadcrumbs>,
+    pub enabled_features: vk::PhysicalDeviceFeatures,
+    pub max_sampler_anisotropy: f32,
+    pub shader_paths: Option<(PathBuf, PathBuf)>,
+    pub frame_timer: FrameTimer,
+    pub gpu_timer: Option<GpuTimer>,
+    pub command_buffers_dirty: bool,
+    // samples and sampled_depth waiting for the window to be restored, see recreate_render_pass
+    pub pending_render_pass: Option<(vk::SampleCountFlags, bool)>,
+    pub clear_color: [f32; 4],
+    pub framebuffer_resized: bool,
+    pub capture_path: Option<PathBuf>,
+    pub frame_capture: Option<FrameCapture>,
+    pub light_buffer: EngineBuffer,
+    // A single white instance for lit models drawn without instances of their own
+    pub default_instance_buffer: EngineBuffer,
+    pub post_process: Option<PostProcess>,
+    pub post_process_target: Option<RenderTarget>,
+    pub exposure: f32,
+    pub default_normal_map: Texture,
+    pub default_texture: Texture,
+    pub max_textures: u32,
+    pub transparent_order: Vec<usize>,
 }
 
+#[allow(dead_code)]
 impl VulkanEngine {
-    pub fn init(window: Window) -> Result<VulkanEngine, vk::Result> {
+    pub fn builder() -> VulkanEngineBuilder {
+        VulkanEngineBuilder {
+            app_name: "Vulkan Engine".to_string(),
+            app_version: vk::make_api_version(0, 1, 0, 0),
+            api_version: vk::API_VERSION_1_1,
+            validation: true,
+            clear_color: [0.0, 0.0, 0.08, 1.0],
+            present_mode: PresentMode::Fifo,
+            reverse_z: false,
+            max_lights: light::DEFAULT_MAX_LIGHTS,
+            max_textures: texture::DEFAULT_MAX_TEXTURES,
+            device_selector: DeviceSelector::Auto,
+            present_wait: false,
+            wireframe: false,
+            sampler_anisotropy: false,
+            wide_lines: false,
+        }
+    }
+
+    pub fn init(window: Window) -> Result<VulkanEngine, EngineError> {
+        Self::builder().build(window)
+    }
+
+    pub fn init_with_device(window: Window, device_selector: DeviceSelector) -> Result<VulkanEngine, EngineError> {
+        Self::builder().device_selector(device_selector).build(window)
+    }
+
+    fn init_with_builder(window: Window, builder: VulkanEngineBuilder) -> Result<VulkanEngine, EngineError> {
         let entry = Entry::linked();
 
-        let layer_names = vec!["VK_LAYER_KHRONOS_validation"];
+        let layer_names = if builder.validation {
+            vec!["VK_LAYER_KHRONOS_validation"]
+        } else {
+            vec![]
+        };
 
-        let instance = Self::init_instance(&entry, &layer_names)?;
+        let instance = Self::init_instance(&entry, &layer_names, &window, &builder)?;
 
         let debug = EngineDebug::init(&entry, &instance, Some(vulkan_debug_utils_callback))?;
 
         let surfaces = EngineSurface::init(&window, &entry, &instance)?;
 
-        let (physical_device, physical_device_properties) = Self::init_physical_device(&instance)?;
+        let (physical_device, physical_device_properties) = Self::init_physical_device(
+            &instance,
+            &surfaces,
+            &builder.device_selector
+        )?;
 
         let queue_families = QueueFamilies::init(&instance, physical_device, &surfaces)?;
 
-        let (device, queues) = Self::init_device_queues(&instance, physical_device, &queue_families, &layer_names)?;
+        let present_wait_enabled = builder.present_wait
+            && Self::present_wait_supported(&instance, physical_device)?;
+
+        if builder.present_wait && !present_wait_enabled {
+            println!("Device doesn't support VK_KHR_present_wait, presents won't be waited for");
+        }
+
+        let conservative_rasterization_supported = Self::device_extension_supported(
+            &instance,
+            physical_device,
+            vk::ExtConservativeRasterizationFn::name()
+        )?;
+
+        let mut optional_extensions = vec![];
+
+        if conservative_rasterization_supported {
+            optional_extensions.push(vk::ExtConservativeRasterizationFn::name());
+        }
+
+        // Breadcrumbs cost a little on every command buffer, so they're only used in debug builds
+        let mut breadcrumbs_extension = None;
+
+        if cfg!(debug_assertions) {
+            for name in Breadcrumbs::extension_names() {
+                if Self::device_extension_supported(&instance, physical_device, name)? {
+                    breadcrumbs_extension = Some(name);
+                    optional_extensions.push(name);
+                    break;
+                }
+            }
+        }
+
+        let supported_features = unsafe {
+            instance.get_physical_device_features(physical_device)
+        };
+
+        let enabled_features = Self::choose_features(&supported_features, &builder)?;
+
+        let (device, queues) = Self::init_device_queues(
+            &instance,
+            physical_device,
+            &queue_families,
+            &layer_names,
+            present_wait_enabled,
+            &enabled_features,
+            &optional_extensions
+        )?;
+
+        let max_sampler_anisotropy = if enabled_features.sampler_anisotropy == vk::TRUE {
+            physical_device_properties.limits.max_sampler_anisotropy
+        } else {
+            1.0
+        };
+
+        let present_wait = if present_wait_enabled {
+            Some(ash::extensions::khr::PresentWait::new(&instance, &device))
+        } else {
+            None
+        };
 
         let mut allocator = VkAllocator::new(
             &AllocatorCreateDesc {
@@ -101,39 +407,151 @@ impl VulkanEngine {
             }
         );
 
+        let breadcrumbs = breadcrumbs_extension
+            .map(|name| Breadcrumbs::init(&instance, &device, &mut allocator, name))
+            .transpose()?;
+
+        let depth_format = Self::choose_depth_format(&instance, physical_device)?;
+
         let mut swapchain = EngineSwapchain::init(
             &instance,
             physical_device,
             &device,
             &surfaces,
             &queue_families,
-            &mut allocator
+            &mut allocator,
+            SwapchainConfig {
+                extent: Self::window_extent(&window),
+                frames_in_flight: swapchain::DEFAULT_FRAMES_IN_FLIGHT,
+                sampled_depth: false,
+                samples: vk::SampleCountFlags::TYPE_1,
+                depth_format,
+                transparent: false,
+                present_mode: builder.present_mode,
+                reverse_z: builder.reverse_z,
+            },
         )?;
 
-        let render_pass = Self::init_render_pass(&device, physical_device, &surfaces)?;
+        let render_pass = Self::init_render_pass(
+            &device,
+            physical_device,
+            &surfaces,
+            &swapchain.config
+        )?;
 
         swapchain.create_framebuffers(&device, render_pass)?;
 
-        let pipeline = EnginePipeline::init_textured(&device, &swapchain, render_pass)?;
+        let pipeline_cache = Self::init_pipeline_cache(&device, &physical_device_properties)?;
+
+        let pipeline = EnginePipeline::init_textured(
+            &device,
+            &swapchain,
+            render_pass,
+            vk::PolygonMode::FILL,
+            true,
+            None,
+            false,
+            builder.max_textures,
+            pipeline_cache,
+        )?;
+
+        let wireframe_pipeline = EnginePipeline::init_textured(
+            &device,
+            &swapchain,
+            render_pass,
+            wireframe_polygon_mode(&enabled_features),
+            true,
+            None,
+            false,
+            builder.max_textures,
+            pipeline_cache,
+        )?;
+
+        let transparent_pipeline = EnginePipeline::init_textured(
+            &device,
+            &swapchain,
+            render_pass,
+            vk::PolygonMode::FILL,
+            false,
+            None,
+            false,
+            builder.max_textures,
+            pipeline_cache,
+        )?;
+
+        let skybox_pipeline = EnginePipeline::init_skybox(
+            &device,
+            &swapchain,
+            render_pass,
+            pipeline_cache,
+        )?;
+
+        let lit_pipeline = EnginePipeline::init(
+            &device,
+            &swapchain,
+            render_pass,
+            vk::PolygonMode::FILL,
+            pipeline_cache,
+        )?;
 
         let pools = Pools::init(&device, &queue_families)?;
         let command_buffers = pools.create_command_buffers(&device, swapchain.framebuffers.len())?;
 
+        let gpu_timer = GpuTimer::init(
+            &device,
+            &queue_families,
+            &physical_device_properties.limits,
+            pools.command_pool_graphics,
+            queues.graphics,
+            command_buffers.len(),
+        )?;
+
         // Camera buffer
 
         let mut uniform_buffer = EngineBuffer::new(
             &mut allocator,
-            128,
+            std::mem::size_of::<CameraUniform>() as u64,
             vk::BufferUsageFlags::UNIFORM_BUFFER,
             gpu_allocator::MemoryLocation::CpuToGpu
-        ).unwrap();
+        )?;
 
-        let camera_transforms: [[[f32; 4]; 4]; 2] = [
-            na::Matrix4::identity().into(),
-            na::Matrix4::identity().into(),
-        ];
+        let camera_uniform = CameraUniform {
+            view: na::Matrix4::identity().into(),
+            proj: na::Matrix4::identity().into(),
+        };
+
+        uniform_buffer.fill(&mut allocator, &[camera_uniform])?;
+
+        // Light buffer, sized for max_lights so adding lights doesn't have to reallocate it.
+        // It starts out with the header of an empty LightManager.
+
+        let lights = LightManager::with_capacity(builder.max_lights);
+        let light_data = lights.to_bytes();
+
+        let mut light_buffer = EngineBuffer::new(
+            &mut allocator,
+            LightManager::buffer_size(lights.max_lights()),
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            gpu_allocator::MemoryLocation::CpuToGpu
+        )?;
+
+        light_buffer.fill(&mut allocator, &light_data)?;
+
+        let default_instance = InstanceData {
+            model_matrix: na::Matrix4::identity(),
+            color: [1.0, 1.0, 1.0],
+            metallic: 0.0,
+            roughness: 0.5,
+        };
+
+        let mut default_instance_buffer = EngineBuffer::new(
+            &mut allocator,
+            std::mem::size_of::<GpuInstanceData>() as u64,
+            vk::BufferUsageFlags::VERTEX_BUFFER,
+            gpu_allocator::MemoryLocation::CpuToGpu
+        )?;
 
-        uniform_buffer.fill(&mut allocator, &camera_transforms).unwrap();
+        default_instance_buffer.fill(&mut allocator, &[default_instance.to_gpu()])?;
 
         // Descriptor pool
 
@@ -142,14 +560,19 @@ impl VulkanEngine {
                 ty: vk::DescriptorType::UNIFORM_BUFFER,
                 descriptor_count: swapchain.amount_of_images,
             },
+            // Per image the texture array and the normal map, plus the skybox cubemap
             vk::DescriptorPoolSize {
                 ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                descriptor_count: (builder.max_textures + 1) * swapchain.amount_of_images + 1,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::STORAGE_BUFFER,
                 descriptor_count: swapchain.amount_of_images,
             },
         ];
 
         let descriptor_pool_info = vk::DescriptorPoolCreateInfo::builder()
-            .max_sets(2 * swapchain.amount_of_images) //
+            .max_sets(3 * swapchain.amount_of_images + 1) //
             .pool_sizes(&pool_sizes);
 
         let descriptor_pool = unsafe {
@@ -169,21 +592,24 @@ impl VulkanEngine {
             device.allocate_descriptor_sets(&descriptor_set_allocate_info_camera)
         }?;
 
-        for desc_set in &descriptor_sets_camera {
-            let buffer_infos = [vk::DescriptorBufferInfo {
-                buffer: uniform_buffer.buffer,
-                offset: 0,
-                range: 128,
-            }];
-            let desc_sets_write = [vk::WriteDescriptorSet::builder()
+        // Every write points into buffer_infos, so it has to outlive the update call
+        let buffer_infos = [vk::DescriptorBufferInfo {
+            buffer: uniform_buffer.buffer,
+            offset: 0,
+            range: std::mem::size_of::<CameraUniform>() as u64,
+        }];
+
+        let desc_sets_write: Vec<vk::WriteDescriptorSet> = descriptor_sets_camera
+            .iter()
+            .map(|desc_set| vk::WriteDescriptorSet::builder()
                 .dst_set(*desc_set)
                 .dst_binding(0)
                 .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
                 .buffer_info(&buffer_infos)
-                .build()];
+                .build())
+            .collect();
 
-            unsafe { device.update_descriptor_sets(&desc_sets_write, &[]) };
-        }
+        unsafe { device.update_descriptor_sets(&desc_sets_write, &[]) };
 
         // Texture Descriptor Set Allocation
 
@@ -198,6 +624,65 @@ impl VulkanEngine {
             device.allocate_descriptor_sets(&descriptor_set_allocate_info_texture)
         }?;
 
+        // Light Descriptor Set Allocation, the camera sets are shared with the lit pipeline
+
+        let desc_layouts_light =
+            vec![lit_pipeline.descriptor_set_layouts[1]; swapchain.amount_of_images as usize];
+
+        let descriptor_set_allocate_info_light = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(&desc_layouts_light);
+
+        let descriptor_sets_light = unsafe {
+            device.allocate_descriptor_sets(&descriptor_set_allocate_info_light)
+        }?;
+
+        let light_buffer_infos = [vk::DescriptorBufferInfo {
+            buffer: light_buffer.buffer,
+            offset: 0,
+            range: vk::WHOLE_SIZE,
+        }];
+
+        let desc_sets_write: Vec<vk::WriteDescriptorSet> = descriptor_sets_light
+            .iter()
+            .map(|desc_set| vk::WriteDescriptorSet::builder()
+                .dst_set(*desc_set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&light_buffer_infos)
+                .build())
+            .collect();
+
+        unsafe { device.update_descriptor_sets(&desc_sets_write, &[]) };
+
+        // Skybox Descriptor Set Allocation, the cubemap doesn't change per frame so one set is enough
+
+        let desc_layouts_skybox = [skybox_pipeline.descriptor_set_layouts[1]];
+
+        let descriptor_set_allocate_info_skybox = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(&desc_layouts_skybox);
+
+        let descriptor_set_skybox = unsafe {
+            device.allocate_descriptor_sets(&descriptor_set_allocate_info_skybox)
+        }?[0];
+
+        let upload_queues = Self::upload_queues_for(&pools, &queues, &queue_families);
+
+        // Bound until a model's own normal map is written, see write_normal_map_descriptors
+        let default_normal_map = Texture::flat_normal_map(&device, &mut allocator, &upload_queues)?;
+
+        // Fills the texture array elements nothing was written to, the pipeline may read any of them
+        let default_texture = Texture::from_rgba(
+            &[255, 255, 255, 255],
+            1,
+            1,
+            SamplerConfig::default(),
+            &device,
+            &mut allocator,
+            &upload_queues,
+        )?;
+
         let engine = VulkanEngine {
             window,
             entry,
@@ -212,19 +697,77 @@ impl VulkanEngine {
             swapchain,
             render_pass,
             pipeline,
+            wireframe_pipeline,
+            transparent_pipeline,
+            skybox_pipeline,
+            lit_pipeline,
             pools,
             graphics_command_buffers: command_buffers,
             allocator: allocator,
             models: vec![],
+            lit_models: vec![],
+            lights,
+            textures: vec![],
+            skybox: None,
             uniform_buffer,
             descriptor_pool,
             descriptor_sets_cam: descriptor_sets_camera,
-            descriptor_sets_light: vec![],
+            descriptor_sets_light,
             descriptor_sets_texture,
-            //light_buffer,
+            descriptor_set_skybox,
+            pipeline_cache,
+            wireframe: false,
+            clear_depth: if builder.reverse_z { 0.0 } else { 1.0 },
+            clear_stencil: 0,
+            present_wait,
+            present_ids: PresentIds::new(present_wait_enabled),
+            conservative_rasterization: false,
+            conservative_rasterization_supported,
+            breadcrumbs,
+            enabled_features,
+            max_sampler_anisotropy,
+            shader_paths: None,
+            frame_timer: FrameTimer::new(),
+            gpu_timer,
+            command_buffers_dirty: false,
+            pending_render_pass: None,
+            clear_color: builder.clear_color,
+            framebuffer_resized: false,
+            capture_path: None,
+            frame_capture: None,
+            light_buffer,
+            default_instance_buffer,
+            post_process: None,
+            post_process_target: None,
+            exposure: 1.0,
+            default_normal_map,
+            default_texture,
+            max_textures: builder.max_textures,
+            transparent_order: vec![],
         };
 
-        engine.fill_command_buffers(&engine.models);
+        // The array is indexed dynamically and bound without PARTIALLY_BOUND, so every element
+        // has to hold a valid texture from the start. Nothing is in flight yet, so the sets can
+        // be written without waiting.
+        for index in 0..engine.max_textures {
+            engine.write_texture_binding(
+                0,
+                index,
+                engine.default_texture.image_view,
+                engine.default_texture.sampler,
+            );
+        }
+
+        engine.write_texture_binding(
+            1,
+            0,
+            engine.default_normal_map.image_view,
+            engine.default_normal_map.sampler,
+        );
+
+        engine.name_objects()?;
+
+        engine.fill_command_buffers();
 
         Ok(engine)
     }
@@ -232,16 +775,18 @@ impl VulkanEngine {
     fn init_instance(
         entry: &Entry,
         layer_names: &[&str],
-    ) -> Result<Instance, vk::Result> {
-        let app_name = CString::new("Vulkan Engine").unwrap();
+        window: &Window,
+        builder: &VulkanEngineBuilder,
+    ) -> Result<Instance, EngineError> {
+        let app_name = CString::new(builder.app_name.as_str()).map_err(EngineError::InvalidAppName)?;
         let engine_name = CString::new("Vulkan Engine").unwrap();
 
         let app_info = vk::ApplicationInfo::builder()
             .application_name(&app_name)
             .engine_name(&engine_name)
             .engine_version(vk::make_api_version(0, 1, 0, 0))
-            .application_version(vk::make_api_version(0, 1, 0, 0))
-            .api_version(vk::API_VERSION_1_1);
+            .application_version(builder.app_version)
+            .api_version(builder.api_version);
 
         let layer_names: Vec<CString> = layer_names
             .iter()
@@ -251,43 +796,181 @@ impl VulkanEngine {
             .iter()
             .map(|layer_name| layer_name.as_ptr())
             .collect();
-        let extension_name_pts: Vec<*const i8> = vec![
+        let mut extension_name_pts: Vec<*const i8> = vec![
             ash::extensions::ext::DebugUtils::name().as_ptr(),
-            ash::extensions::khr::Surface::name().as_ptr(),
-            ash::extensions::khr::XlibSurface::name().as_ptr(),
         ];
 
+        for extension in ash_window::enumerate_required_extensions(window)? {
+            extension_name_pts.push(extension.as_ptr());
+        }
+
+        let mut instance_flags = vk::InstanceCreateFlags::empty();
+
+        // MoltenVK is only enumerated when the application opts into portability drivers
+        if cfg!(target_os = "macos") {
+            extension_name_pts.push(PORTABILITY_ENUMERATION_EXTENSION_NAME.as_ptr() as *const i8);
+            extension_name_pts.push(vk::KhrGetPhysicalDeviceProperties2Fn::name().as_ptr());
+            instance_flags |= vk::InstanceCreateFlags::from_raw(INSTANCE_CREATE_ENUMERATE_PORTABILITY_BIT);
+        }
+
         let instance_create_info = vk::InstanceCreateInfo::builder()
+            .flags(instance_flags)
             .application_info(&app_info)
             .enabled_layer_names(&layer_name_pts)
             .enabled_extension_names(&extension_name_pts);
 
-        unsafe {
-            entry.create_instance(&instance_create_info, None)
-        }
+        let instance = unsafe {
+            entry.create_instance(&instance_create_info, None)?
+        };
+
+        Ok(instance)
     }
 
     fn init_physical_device(
         instance: &Instance,
+        surfaces: &EngineSurface,
+        device_selector: &DeviceSelector,
     ) -> Result<(vk::PhysicalDevice, vk::PhysicalDeviceProperties), vk::Result> {
         let phys_devs = unsafe {
             instance.enumerate_physical_devices()?
         };
 
-        let p = phys_devs[0];
+        let mut best: Option<(u32, vk::PhysicalDevice, vk::PhysicalDeviceProperties)> = None;
+
+        for (i, p) in phys_devs.into_iter().enumerate() {
+            let properties = unsafe {
+                instance.get_physical_device_properties(p)
+            };
+
+            let device_name = unsafe { CStr::from_ptr(properties.device_name.as_ptr()) };
+
+            let requested = match device_selector {
+                DeviceSelector::Auto => true,
+                DeviceSelector::Index(index) => i == *index,
+                DeviceSelector::NameContains(name) => device_name.to_string_lossy().contains(name.as_str()),
+            };
+
+            if !requested {
+                continue;
+            }
+
+            if !Self::physical_device_suitable(instance, p, surfaces)? {
+                if *device_selector != DeviceSelector::Auto {
+                    println!("Requested physical device {:?} lacks the required queues or extensions", device_name);
+                    return Err(vk::Result::ERROR_FEATURE_NOT_PRESENT);
+                }
+
+                continue;
+            }
+
+            let score = match properties.device_type {
+                vk::PhysicalDeviceType::DISCRETE_GPU => 3,
+                vk::PhysicalDeviceType::INTEGRATED_GPU => 2,
+                vk::PhysicalDeviceType::VIRTUAL_GPU => 1,
+                _ => 0,
+            };
+
+            let better = match best {
+                Some((best_score, _, _)) => score > best_score,
+                None => true,
+            };
+
+            if better {
+                best = Some((score, p, properties));
+            }
+        }
 
-        let properties = unsafe {
-            instance.get_physical_device_properties(p)
+        let (_, p, properties) = match best {
+            Some(best) => best,
+            None => {
+                println!("No physical device matches {:?}", device_selector);
+                return Err(vk::Result::ERROR_INITIALIZATION_FAILED);
+            }
         };
 
+        let device_name = unsafe { CStr::from_ptr(properties.device_name.as_ptr()) };
+        println!("Using physical device {:?} ({:?})", device_name, properties.device_type);
+
         Ok((p, properties))
     }
 
+    fn physical_device_suitable(
+        instance: &Instance,
+        physical_device: vk::PhysicalDevice,
+        surfaces: &EngineSurface,
+    ) -> Result<bool, vk::Result> {
+        if !Self::device_extension_supported(instance, physical_device, ash::extensions::khr::Swapchain::name())? {
+            return Ok(false);
+        }
+
+        if !Self::non_uniform_indexing_supported(instance, physical_device)? {
+            println!("Skipping a device without non-uniform indexing of sampled image arrays");
+            return Ok(false);
+        }
+
+        let queue_families = QueueFamilies::init(instance, physical_device, surfaces)?;
+
+        Ok(
+            queue_families.graphics_index.is_some()
+                && queue_families.present_index.is_some()
+                && queue_families.transfer_index.is_some()
+                && queue_families.compute_index.is_some()
+        )
+    }
+
+    // The textured fragment shader indexes its texture array with a per-instance index
+    fn non_uniform_indexing_supported(
+        instance: &Instance,
+        physical_device: vk::PhysicalDevice,
+    ) -> Result<bool, vk::Result> {
+        if !Self::device_extension_supported(instance, physical_device, vk::ExtDescriptorIndexingFn::name())? {
+            return Ok(false);
+        }
+
+        let mut indexing_features = vk::PhysicalDeviceDescriptorIndexingFeatures::default();
+        let mut features = vk::PhysicalDeviceFeatures2::builder()
+            .push_next(&mut indexing_features);
+
+        unsafe {
+            instance.get_physical_device_features2(physical_device, &mut features);
+        }
+
+        Ok(indexing_features.shader_sampled_image_array_non_uniform_indexing == vk::TRUE)
+    }
+
+    // Only the features the builder asked for are enabled, so devices lacking the others still work
+    fn choose_features(
+        supported: &vk::PhysicalDeviceFeatures,
+        builder: &VulkanEngineBuilder,
+    ) -> Result<vk::PhysicalDeviceFeatures, EngineError> {
+        let requested = [
+            ("fill_mode_non_solid", builder.wireframe, supported.fill_mode_non_solid),
+            ("sampler_anisotropy", builder.sampler_anisotropy, supported.sampler_anisotropy),
+            ("wide_lines", builder.wide_lines, supported.wide_lines),
+        ];
+
+        for (name, requested, supported) in requested {
+            if requested && supported != vk::TRUE {
+                return Err(EngineError::MissingFeature(name));
+            }
+        }
+
+        Ok(vk::PhysicalDeviceFeatures {
+            fill_mode_non_solid: builder.wireframe.into(),
+            sampler_anisotropy: builder.sampler_anisotropy.into(),
+            wide_lines: builder.wide_lines.into(),
+            ..Default::default()
+        })
+    }
+
     fn init_device_queues(
         instance: &Instance,
         physical_device: vk::PhysicalDevice,
         queue_families: &QueueFamilies,
         layer_names: &[&str],
+        enable_present_wait: bool,
+        features: &vk::PhysicalDeviceFeatures,
+        optional_extensions: &[&CStr],
     ) -> Result<(Device, Queues), vk::Result> {
         let layer_names: Vec<CString> = layer_names
             .iter()
@@ -298,27 +981,66 @@ impl VulkanEngine {
             .map(|layer_name| layer_name.as_ptr())
             .collect();
 
-        // possible problem: index 0 and 1 are the same, if this is the case we need only one DeviceQueueCreateInfo
-        let priorities = [1.0f32];
-        let queue_infos = [
-            vk::DeviceQueueCreateInfo::builder()
-                .queue_family_index(queue_families.graphics_index.unwrap())
-                .queue_priorities(&priorities)
-                .build(),
-            vk::DeviceQueueCreateInfo::builder()
-                .queue_family_index(queue_families.transfer_index.unwrap())
-                .queue_priorities(&priorities)
-                .build(),
+        // Each family may only be requested once, even when several queues come from it
+        let mut family_indices = vec![
+            queue_families.graphics_index.unwrap(),
+            queue_families.present_index.unwrap(),
+            queue_families.transfer_index.unwrap(),
+            queue_families.compute_index.unwrap(),
         ];
+        family_indices.sort_unstable();
+        family_indices.dedup();
 
-        let device_extensions_name_pts: Vec<*const i8> = vec![
-            ash::extensions::khr::Swapchain::name().as_ptr()
+        let priorities = [1.0f32];
+        let queue_infos: Vec<vk::DeviceQueueCreateInfo> = family_indices
+            .iter()
+            .map(|&index| {
+                vk::DeviceQueueCreateInfo::builder()
+                    .queue_family_index(index)
+                    .queue_priorities(&priorities)
+                    .build()
+            })
+            .collect();
+
+        let mut device_extensions_name_pts: Vec<*const i8> = vec![
+            ash::extensions::khr::Swapchain::name().as_ptr(),
+            vk::ExtDescriptorIndexingFn::name().as_ptr(),
         ];
 
-        let device_create_info = vk::DeviceCreateInfo::builder()
+        // Portability implementations such as MoltenVK require the subset extension to be enabled
+        if Self::device_extension_supported(instance, physical_device, vk::KhrPortabilitySubsetFn::name())? {
+            device_extensions_name_pts.push(vk::KhrPortabilitySubsetFn::name().as_ptr());
+        }
+
+        if enable_present_wait {
+            device_extensions_name_pts.push(vk::KhrPresentIdFn::name().as_ptr());
+            device_extensions_name_pts.push(ash::extensions::khr::PresentWait::name().as_ptr());
+        }
+
+        for name in optional_extensions {
+            device_extensions_name_pts.push(name.as_ptr());
+        }
+
+        let mut present_id_features = vk::PhysicalDevicePresentIdFeaturesKHR::builder()
+            .present_id(true);
+        let mut present_wait_features = vk::PhysicalDevicePresentWaitFeaturesKHR::builder()
+            .present_wait(true);
+
+        let mut indexing_features = vk::PhysicalDeviceDescriptorIndexingFeatures::builder()
+            .shader_sampled_image_array_non_uniform_indexing(true);
+
+        let mut device_create_info = vk::DeviceCreateInfo::builder()
             .queue_create_infos(&queue_infos)
             .enabled_extension_names(&device_extensions_name_pts)
-            .enabled_layer_names(&layer_name_pts);
+            .enabled_features(features)
+            .enabled_layer_names(&layer_name_pts)
+            .push_next(&mut indexing_features);
+
+        if enable_present_wait {
+            device_create_info = device_create_info
+                .push_next(&mut present_id_features)
+                .push_next(&mut present_wait_features);
+        }
 
         let device = unsafe {
             instance.create_device(physical_device, &device_create_info, None)?
@@ -327,120 +1049,1495 @@ impl VulkanEngine {
         let graphics_queue = unsafe {
             device.get_device_queue(queue_families.graphics_index.unwrap(), 0)
         };
+        let present_queue = unsafe {
+            device.get_device_queue(queue_families.present_index.unwrap(), 0)
+        };
         let transfer_queue = unsafe {
             device.get_device_queue(queue_families.transfer_index.unwrap(), 0)
         };
+        let compute_queue = unsafe {
+            device.get_device_queue(queue_families.compute_index.unwrap(), 0)
+        };
 
         Ok((device, Queues {
             graphics: graphics_queue,
-            transfer: transfer_queue
+            present: present_queue,
+            transfer: transfer_queue,
+            compute: compute_queue,
         }))
     }
 
-    pub fn recreate_swapchain(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        unsafe {
-            self.device.device_wait_idle()
-                .expect("Failed to wait_idle");
+    fn device_extension_supported(
+        instance: &Instance,
+        physical_device: vk::PhysicalDevice,
+        name: &CStr,
+    ) -> Result<bool, vk::Result> {
+        let extensions = unsafe {
+            instance.enumerate_device_extension_properties(physical_device)
+        }?;
+
+        Ok(extensions.iter().any(|extension| {
+            let extension_name = unsafe { CStr::from_ptr(extension.extension_name.as_ptr()) };
+            extension_name == name
+        }))
+    }
 
-            self.swapchain.cleanup(&self.device);
+    fn present_wait_supported(
+        instance: &Instance,
+        physical_device: vk::PhysicalDevice,
+    ) -> Result<bool, vk::Result> {
+        if !Self::device_extension_supported(instance, physical_device, vk::KhrPresentIdFn::name())?
+            || !Self::device_extension_supported(instance, physical_device, ash::extensions::khr::PresentWait::name())? {
+            return Ok(false);
         }
 
-        self.swapchain = EngineSwapchain::init(
-            &self.instance,
-            self.physical_device,
-            &self.device,
-            &self.surfaces,
-            &self.queue_families,
+        let mut present_id_features = vk::PhysicalDevicePresentIdFeaturesKHR::default();
+        let mut present_wait_features = vk::PhysicalDevicePresentWaitFeaturesKHR::default();
+
+        {
+            let mut features = vk::PhysicalDeviceFeatures2::builder()
+                .push_next(&mut present_id_features)
+                .push_next(&mut present_wait_features);
+
+            unsafe {
+                instance.get_physical_device_features2(physical_device, &mut features)
+            };
+        }
+
+        Ok(present_id_features.present_id == vk::TRUE && present_wait_features.present_wait == vk::TRUE)
+    }
+
+    // Id of the latest present, None unless the engine was built with present_wait enabled
+    pub fn last_present_id(&self) -> Option<u64> {
+        self.present_ids.last()
+    }
+
+    // Blocks until the presentation engine has actually displayed the given present, see
+    // last_present_id. Returns right away when present wait isn't enabled.
+    pub fn wait_for_present(&self, present_id: u64) -> Result<(), vk::Result> {
+        if let Some(present_wait) = &self.present_wait {
+            unsafe {
+                present_wait.wait_for_present(self.swapchain.swapchain, present_id, u64::MAX)
+            }?;
+        }
+
+        Ok(())
+    }
+
+    // Acquires the next image, records and submits its command buffer and presents it. An out of
+    // date or suboptimal swapchain, or framebuffer_resized being set, recreates the swapchain and
+    // updates the camera's aspect ratio; nothing is drawn while the window is minimized.
+    pub fn draw_frame(&mut self, camera: &mut Camera) -> Result<(), EngineError> {
+        if self.is_minimized()? {
+            return Ok(());
+        }
+
+        if let Some((samples, sampled_depth)) = self.pending_render_pass {
+            self.recreate_render_pass(samples, sampled_depth)?;
+        }
+
+        self.swapchain.advance_frame();
+
+        let frame = self.swapchain.current_frame;
+
+        unsafe {
+            self.device.wait_for_fences(&[self.swapchain.may_begin_drawing[frame]], true, u64::MAX)?;
+        }
+
+        self.finish_capture(frame);
+
+        let acquire_result = unsafe {
+            self.swapchain.loader.acquire_next_image(
+                self.swapchain.swapchain,
+                u64::MAX,
+                self.swapchain.image_available[frame],
+                vk::Fence::null()
+            )
+        };
+
+        let image_index = match acquire_result {
+            Ok((image_index, _)) => image_index,
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
+                return self.recreate_swapchain_for(camera);
+            }
+            Err(error) => return Err(error.into()),
+        };
+
+        self.swapchain.claim_image(&self.device, image_index as usize)?;
+
+        unsafe {
+            self.device.reset_fences(&[self.swapchain.may_begin_drawing[frame]])?;
+        }
+
+        camera.update_buffer(&mut self.allocator, &mut self.uniform_buffer)?;
+
+        let frustum = camera.frustum();
+        let eye = camera.position();
+        let mut transparent_distances = vec![];
+
+        for (i, m) in self.models.iter_mut().enumerate() {
+            m.cull(&frustum);
+
+            if m.transparent {
+                if let Some(distance) = m.sort_back_to_front(&eye) {
+                    transparent_distances.push((i, distance));
+                }
+            }
+
+            m.update_instance_buffer(&mut self.allocator)?;
+        }
+
+        // Whole models are ordered by their farthest instance, which is only exact when they don't overlap
+        transparent_distances.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+        self.transparent_order = transparent_distances.into_iter().map(|(i, _)| i).collect();
+
+        for m in &mut self.lit_models {
+            m.cull(&frustum);
+            m.update_instance_buffer(&mut self.allocator)?;
+        }
+
+        if self.frame_capture.is_none() {
+            if let Some(path) = self.capture_path.take() {
+                match self.begin_capture(path, frame, image_index as usize) {
+                    Ok(capture) => self.frame_capture = Some(capture),
+                    Err(error) => println!("Failed to capture frame: {}", error),
+                }
+            }
+        }
+
+        self.update_command_buffer(image_index as usize)?;
+
+        let semaphores_available = [self.swapchain.image_available[frame]];
+        let waiting_stages = [vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
+        let semaphores_finished = [self.swapchain.rendering_finished[frame]];
+        let command_buffers = [self.graphics_command_buffers[image_index as usize]];
+
+        let submit_info = [
+            vk::SubmitInfo::builder()
+                .wait_semaphores(&semaphores_available)
+                .wait_dst_stage_mask(&waiting_stages)
+                .command_buffers(&command_buffers)
+                .signal_semaphores(&semaphores_finished)
+                .build()
+        ];
+
+        let submit_result = unsafe {
+            self.device.queue_submit(
+                self.queues.graphics,
+                &submit_info,
+                self.swapchain.may_begin_drawing[frame]
+            )
+        };
+
+        if let Err(error) = submit_result {
+            if error == vk::Result::ERROR_DEVICE_LOST {
+                self.report_device_lost();
+            }
+
+            return Err(error.into());
+        }
+
+        let swapchains = [self.swapchain.swapchain];
+        let indices = [image_index];
+        let present_id = self.present_ids.next();
+        let present_ids = [present_id.unwrap_or(0)];
+        let mut present_id_info = vk::PresentIdKHR::builder()
+            .present_ids(&present_ids);
+        let mut present_info = vk::PresentInfoKHR::builder()
+            .wait_semaphores(&semaphores_finished)
+            .swapchains(&swapchains)
+            .image_indices(&indices);
+
+        if present_id.is_some() {
+            present_info = present_info.push_next(&mut present_id_info);
+        }
+
+        let present_result = unsafe {
+            self.swapchain.loader.queue_present(self.queues.present, &present_info)
+        };
+
+        let out_of_date = match present_result {
+            Ok(suboptimal) => suboptimal,
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => true,
+            Err(error) => {
+                if error == vk::Result::ERROR_DEVICE_LOST {
+                    self.report_device_lost();
+                }
+
+                return Err(error.into());
+            }
+        };
+
+        if out_of_date || self.framebuffer_resized {
+            self.recreate_swapchain_for(camera)?;
+        }
+
+        Ok(())
+    }
+
+    // Saves the next drawn frame as a PNG, see FrameCapture
+    pub fn request_capture<P: Into<PathBuf>>(&mut self, path: P) {
+        self.capture_path = Some(path.into());
+    }
+
+    fn recreate_swapchain_for(&mut self, camera: &mut Camera) -> Result<(), EngineError> {
+        self.framebuffer_resized = false;
+
+        self.recreate_swapchain()?;
+
+        camera.set_aspect(self.swapchain.extent.width as f32 / self.swapchain.extent.height as f32);
+        camera.update_buffer(&mut self.allocator, &mut self.uniform_buffer)?;
+
+        Ok(())
+    }
+
+    // A minimized window has a zero extent, which no swapchain can be created with
+    pub fn is_minimized(&self) -> Result<bool, vk::Result> {
+        let capabilities = self.surfaces.capabilities(self.physical_device)?;
+        let extent = EngineSwapchain::choose_extent(&capabilities, Self::window_extent(&self.window));
+
+        Ok(extent.width == 0 || extent.height == 0)
+    }
+
+    pub fn recreate_swapchain(&mut self) -> Result<(), EngineError> {
+        // The old swapchain is kept until the window is restored
+        if self.is_minimized()? {
+            return Ok(());
+        }
+
+        // The render pass and pipelines stay, they don't depend on the extent
+        unsafe {
+            self.device.device_wait_idle()?;
+        }
+
+        // The frame numbering may change along with the sync objects
+        if let Some(frame) = self.frame_capture.as_ref().map(|capture| capture.frame) {
+            self.finish_capture(frame);
+        }
+
+        let config = SwapchainConfig {
+            extent: Self::window_extent(&self.window),
+            ..self.swapchain.config
+        };
+
+        self.swapchain.recreate(
+            &self.instance,
+            self.physical_device,
+            &self.device,
+            &self.surfaces,
+            &self.queue_families,
             &mut self.allocator,
+            config,
         )?;
 
         self.swapchain.create_framebuffers(&self.device, self.render_pass)?;
 
-        self.pipeline.cleanup(&self.device);
+        self.recreate_post_process_target()?;
+
+        self.name_objects()?;
+
+        Ok(())
+    }
+
+    // Debug names for the objects validation messages mention most. Recreated objects come
+    // without names, so this runs again whenever the swapchain or the pipelines are rebuilt.
+    fn name_objects(&self) -> Result<(), vk::Result> {
+        let debug = &self.debug;
+        let device = &self.device;
+
+        debug.set_object_name(device, self.render_pass, "Render Pass")?;
+        debug.set_object_name(device, self.pipeline.pipeline, "Textured Pipeline")?;
+        debug.set_object_name(device, self.wireframe_pipeline.pipeline, "Wireframe Pipeline")?;
+        debug.set_object_name(device, self.transparent_pipeline.pipeline, "Transparent Pipeline")?;
+        debug.set_object_name(device, self.skybox_pipeline.pipeline, "Skybox Pipeline")?;
+        debug.set_object_name(device, self.lit_pipeline.pipeline, "Lit Pipeline")?;
+        debug.set_object_name(device, self.uniform_buffer.buffer, "Camera Uniform Buffer")?;
+
+        for (i, &semaphore) in self.swapchain.image_available.iter().enumerate() {
+            debug.set_object_name(device, semaphore, &format!("Image Available {}", i))?;
+        }
+
+        for (i, &semaphore) in self.swapchain.rendering_finished.iter().enumerate() {
+            debug.set_object_name(device, semaphore, &format!("Rendering Finished {}", i))?;
+        }
+
+        for (i, &fence) in self.swapchain.may_begin_drawing.iter().enumerate() {
+            debug.set_object_name(device, fence, &format!("May Begin Drawing {}", i))?;
+        }
+
+        for (i, &command_buffer) in self.graphics_command_buffers.iter().enumerate() {
+            debug.set_object_name(device, command_buffer, &format!("Graphics Command Buffer {}", i))?;
+        }
+
+        Ok(())
+    }
+
+    // Pipelines depend on the render pass, the sample count and the rasterization options,
+    // but not on the swapchain extent since viewport and scissor are dynamic
+    fn recreate_pipelines(&mut self) -> Result<(), EngineError> {
+        unsafe {
+            self.device.device_wait_idle()?;
+        }
+
+        // The new pipelines are built before the old ones are destroyed, so a shader
+        // that fails to load leaves the engine drawing with the previous ones
+        let pipeline = self.create_textured_pipeline(vk::PolygonMode::FILL, true)?;
+
+        let wireframe_pipeline = match self.create_textured_pipeline(wireframe_polygon_mode(&self.enabled_features), true) {
+            Ok(wireframe_pipeline) => wireframe_pipeline,
+            Err(error) => {
+                pipeline.cleanup(&self.device);
+                return Err(error);
+            }
+        };
+
+        let transparent_pipeline = match self.create_textured_pipeline(vk::PolygonMode::FILL, false) {
+            Ok(transparent_pipeline) => transparent_pipeline,
+            Err(error) => {
+                pipeline.cleanup(&self.device);
+                wireframe_pipeline.cleanup(&self.device);
+                return Err(error);
+            }
+        };
+
+        let skybox_pipeline = match EnginePipeline::init_skybox(
+            &self.device,
+            &self.swapchain,
+            self.scene_render_pass(),
+            self.pipeline_cache,
+        ) {
+            Ok(skybox_pipeline) => skybox_pipeline,
+            Err(error) => {
+                pipeline.cleanup(&self.device);
+                wireframe_pipeline.cleanup(&self.device);
+                transparent_pipeline.cleanup(&self.device);
+                return Err(error.into());
+            }
+        };
+
+        let lit_pipeline = match EnginePipeline::init(
+            &self.device,
+            &self.swapchain,
+            self.scene_render_pass(),
+            vk::PolygonMode::FILL,
+            self.pipeline_cache,
+        ) {
+            Ok(lit_pipeline) => lit_pipeline,
+            Err(error) => {
+                pipeline.cleanup(&self.device);
+                wireframe_pipeline.cleanup(&self.device);
+                transparent_pipeline.cleanup(&self.device);
+                skybox_pipeline.cleanup(&self.device);
+                return Err(error.into());
+            }
+        };
+
+        self.pipeline.cleanup(&self.device);
+        self.wireframe_pipeline.cleanup(&self.device);
+        self.transparent_pipeline.cleanup(&self.device);
+        self.skybox_pipeline.cleanup(&self.device);
+        self.lit_pipeline.cleanup(&self.device);
+
+        self.pipeline = pipeline;
+        self.wireframe_pipeline = wireframe_pipeline;
+        self.transparent_pipeline = transparent_pipeline;
+        self.skybox_pipeline = skybox_pipeline;
+        self.lit_pipeline = lit_pipeline;
+
+        if let Some(post_process) = &mut self.post_process {
+            post_process.recreate_pipeline(
+                &self.device,
+                &self.swapchain,
+                self.render_pass,
+                self.pipeline_cache,
+            )?;
+        }
+
+        self.name_objects()?;
+
+        Ok(())
+    }
+
+    fn create_textured_pipeline(
+        &self,
+        polygon_mode: vk::PolygonMode,
+        depth_write: bool,
+    ) -> Result<EnginePipeline, EngineError> {
+        let conservative_rasterization = self.conservative_rasterization
+            && self.conservative_rasterization_supported;
+
+        let pipeline = match &self.shader_paths {
+            Some((vert_spv, frag_spv)) => EnginePipeline::init_from_spirv(
+                &self.device,
+                vert_spv,
+                frag_spv,
+                &self.swapchain,
+                self.scene_render_pass(),
+                polygon_mode,
+                depth_write,
+                conservative_rasterization,
+                self.max_textures,
+                self.pipeline_cache,
+            )?,
+            None => EnginePipeline::init_textured(
+                &self.device,
+                &self.swapchain,
+                self.scene_render_pass(),
+                polygon_mode,
+                depth_write,
+                None,
+                conservative_rasterization,
+                self.max_textures,
+                self.pipeline_cache,
+            )?,
+        };
+
+        Ok(pipeline)
+    }
+
+    // Makes the pipelines load their shaders from SPIR-V files instead of the ones
+    // compiled into the binary, takes effect on the next reload_pipeline
+    pub fn set_shader_paths<P: Into<PathBuf>>(&mut self, vert_spv: P, frag_spv: P) {
+        self.shader_paths = Some((vert_spv.into(), frag_spv.into()));
+    }
+
+    pub fn reload_pipeline(&mut self) -> Result<(), EngineError> {
+        self.recreate_pipelines()?;
+
+        self.fill_command_buffers();
+        self.command_buffers_dirty = false;
+
+        Ok(())
+    }
+
+    fn window_extent(window: &Window) -> vk::Extent2D {
+        let size = window.inner_size();
+
+        vk::Extent2D {
+            width: size.width,
+            height: size.height,
+        }
+    }
+
+    // Starts from the cache saved by the last run if it was written by the same device and driver
+    fn init_pipeline_cache(
+        device: &Device,
+        properties: &vk::PhysicalDeviceProperties,
+    ) -> Result<vk::PipelineCache, vk::Result> {
+        let data = std::fs::read(PIPELINE_CACHE_PATH).unwrap_or_default();
+
+        let data = if Self::pipeline_cache_compatible(&data, properties) {
+            data
+        } else {
+            vec![]
+        };
+
+        let cache_info = vk::PipelineCacheCreateInfo::builder()
+            .initial_data(&data);
+
+        match unsafe { device.create_pipeline_cache(&cache_info, None) } {
+            Ok(cache) => Ok(cache),
+            Err(_) if !data.is_empty() => {
+                println!("Pipeline cache at {} rejected, starting fresh", PIPELINE_CACHE_PATH);
+
+                unsafe {
+                    device.create_pipeline_cache(&vk::PipelineCacheCreateInfo::default(), None)
+                }
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    // Header layout: length, version, vendor id, device id (u32 each), then the cache UUID
+    fn pipeline_cache_compatible(data: &[u8], properties: &vk::PhysicalDeviceProperties) -> bool {
+        if data.len() < 32 {
+            return false;
+        }
+
+        let read_u32 = |offset: usize| u32::from_ne_bytes(data[offset..offset + 4].try_into().unwrap());
+
+        read_u32(0) >= 32
+            && read_u32(4) == vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32
+            && read_u32(8) == properties.vendor_id
+            && read_u32(12) == properties.device_id
+            && data[16..32] == properties.pipeline_cache_uuid
+    }
+
+    fn save_pipeline_cache(&self) {
+        let data = unsafe {
+            self.device.get_pipeline_cache_data(self.pipeline_cache)
+        };
+
+        match data {
+            Ok(data) => {
+                if let Err(err) = std::fs::write(PIPELINE_CACHE_PATH, data) {
+                    println!("Failed to save pipeline cache: {}", err);
+                }
+            }
+            Err(err) => println!("Failed to read pipeline cache: {:?}", err),
+        }
+    }
+
+    fn choose_depth_format(
+        instance: &ash::Instance,
+        physical_device: vk::PhysicalDevice,
+    ) -> Result<vk::Format, vk::Result> {
+        let candidates = [
+            vk::Format::D32_SFLOAT,
+            vk::Format::D32_SFLOAT_S8_UINT,
+            vk::Format::D24_UNORM_S8_UINT,
+        ];
+
+        candidates
+            .into_iter()
+            .find(|&format| {
+                let properties = unsafe {
+                    instance.get_physical_device_format_properties(physical_device, format)
+                };
+
+                properties.optimal_tiling_features
+                    .contains(vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT)
+            })
+            .ok_or(vk::Result::ERROR_FORMAT_NOT_SUPPORTED)
+    }
+
+    pub fn depth_format(&self) -> vk::Format {
+        self.swapchain.config.depth_format
+    }
+
+    fn init_render_pass(
+        device: &Device,
+        physical_device: vk::PhysicalDevice,
+        surfaces: &EngineSurface,
+        config: &SwapchainConfig,
+    ) -> Result<vk::RenderPass, vk::Result> {
+        let depth_final_layout = if config.sampled_depth {
+            vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL
+        } else {
+            vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL
+        };
+
+        let multisampled = config.samples != vk::SampleCountFlags::TYPE_1;
+
+        let color_format = surfaces.formats(physical_device)?
+            .first()
+            .unwrap()
+            .format;
+
+        // With MSAA the multisampled image is only needed within the pass, the swapchain image
+        // becomes the resolve target
+        let color_final_layout = if multisampled {
+            vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL
+        } else {
+            vk::ImageLayout::PRESENT_SRC_KHR
+        };
+
+        let color_store_op = if multisampled {
+            vk::AttachmentStoreOp::DONT_CARE
+        } else {
+            vk::AttachmentStoreOp::STORE
+        };
+
+        let mut attachments = vec![
+            vk::AttachmentDescription::builder()
+                .format(color_format)
+                .load_op(vk::AttachmentLoadOp::CLEAR)
+                .store_op(color_store_op)
+                .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+                .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .initial_layout(vk::ImageLayout::UNDEFINED)
+                .final_layout(color_final_layout)
+                .samples(config.samples)
+                .build(),
+            vk::AttachmentDescription::builder()
+                .format(config.depth_format)
+                .load_op(vk::AttachmentLoadOp::CLEAR)
+                .store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+                .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .initial_layout(vk::ImageLayout::UNDEFINED)
+                .final_layout(depth_final_layout)
+                .samples(config.samples)
+                .build()
+        ];
+
+        if multisampled {
+            attachments.push(
+                vk::AttachmentDescription::builder()
+                    .format(color_format)
+                    .load_op(vk::AttachmentLoadOp::DONT_CARE)
+                    .store_op(vk::AttachmentStoreOp::STORE)
+                    .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+                    .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+                    .initial_layout(vk::ImageLayout::UNDEFINED)
+                    .final_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+                    .samples(vk::SampleCountFlags::TYPE_1)
+                    .build()
+            );
+        }
+
+        let color_attachment_refs = [
+            vk::AttachmentReference {
+                attachment: 0,
+                layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            }
+        ];
+
+        let depth_attachment_refs = [
+            vk::AttachmentReference {
+                attachment: 1,
+                layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL
+            }
+        ];
+
+        let resolve_attachment_refs = [
+            vk::AttachmentReference {
+                attachment: 2,
+                layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            }
+        ];
+
+        let mut subpass = vk::SubpassDescription::builder()
+            .color_attachments(&color_attachment_refs)
+            .depth_stencil_attachment(&depth_attachment_refs[0])
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS);
+
+        if multisampled {
+            subpass = subpass.resolve_attachments(&resolve_attachment_refs);
+        }
+
+        let subpasses = [subpass.build()];
+
+        let mut subpass_dependencies = vec![
+            vk::SubpassDependency::builder()
+                .src_subpass(vk::SUBPASS_EXTERNAL)
+                .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+                .dst_subpass(0)
+                .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+                .dst_access_mask(
+                    vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                )
+                .build()
+        ];
+
+        // Make depth writes visible to shaders sampling the depth image afterwards
+        if config.sampled_depth {
+            subpass_dependencies.push(
+                vk::SubpassDependency::builder()
+                    .src_subpass(0)
+                    .src_stage_mask(vk::PipelineStageFlags::LATE_FRAGMENT_TESTS)
+                    .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+                    .dst_subpass(vk::SUBPASS_EXTERNAL)
+                    .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+                    .dst_access_mask(vk::AccessFlags::SHADER_READ)
+                    .build()
+            );
+        }
+
+        let render_pass_info = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments)
+            .subpasses(&subpasses)
+            .dependencies(&subpass_dependencies);
+
+        unsafe {
+            device.create_render_pass(&render_pass_info, None)
+        }
+    }
+
+    // 1 gives the lowest input latency, higher values trade latency for throughput
+    pub fn set_frames_in_flight(&mut self, frames_in_flight: usize) -> Result<(), EngineError> {
+        self.swapchain.config.frames_in_flight = frames_in_flight;
+        self.recreate_swapchain()
+    }
+
+    pub fn set_sampled_depth(&mut self, sampled_depth: bool) -> Result<(), EngineError> {
+        let (samples, _) = self.render_pass_config();
+        self.recreate_render_pass(samples, sampled_depth)
+    }
+
+    // The requested count is clamped to what the device supports for color and depth attachments
+    pub fn set_samples(&mut self, samples: vk::SampleCountFlags) -> Result<(), EngineError> {
+        let samples = Self::supported_samples(&self.physical_device_properties, samples);
+        let (_, sampled_depth) = self.render_pass_config();
+        self.recreate_render_pass(samples, sampled_depth)
+    }
+
+    // Including changes still waiting for the window to be restored
+    fn render_pass_config(&self) -> (vk::SampleCountFlags, bool) {
+        self.pending_render_pass
+            .unwrap_or((self.swapchain.config.samples, self.swapchain.config.sampled_depth))
+    }
+
+    fn supported_samples(
+        properties: &vk::PhysicalDeviceProperties,
+        requested: vk::SampleCountFlags,
+    ) -> vk::SampleCountFlags {
+        let supported = properties.limits.framebuffer_color_sample_counts
+            & properties.limits.framebuffer_depth_sample_counts;
+
+        [
+            vk::SampleCountFlags::TYPE_64,
+            vk::SampleCountFlags::TYPE_32,
+            vk::SampleCountFlags::TYPE_16,
+            vk::SampleCountFlags::TYPE_8,
+            vk::SampleCountFlags::TYPE_4,
+            vk::SampleCountFlags::TYPE_2,
+        ]
+            .into_iter()
+            .find(|&samples| samples.as_raw() <= requested.as_raw() && supported.contains(samples))
+            .unwrap_or(vk::SampleCountFlags::TYPE_1)
+    }
+
+    // A minimized window keeps its old swapchain, see recreate_swapchain. Its framebuffers and
+    // images have to stay in sync with the render pass, so the whole rebuild waits until
+    // draw_frame finds the window restored.
+    fn recreate_render_pass(
+        &mut self,
+        samples: vk::SampleCountFlags,
+        sampled_depth: bool,
+    ) -> Result<(), EngineError> {
+        if self.is_minimized()? {
+            self.pending_render_pass = Some((samples, sampled_depth));
+            return Ok(());
+        }
+
+        self.pending_render_pass = None;
+        self.swapchain.config.samples = samples;
+        self.swapchain.config.sampled_depth = sampled_depth;
+
+        unsafe {
+            self.device.device_wait_idle()?;
+            self.device.destroy_render_pass(self.render_pass, None);
+        }
+
+        self.render_pass = Self::init_render_pass(
+            &self.device,
+            self.physical_device,
+            &self.surfaces,
+            &self.swapchain.config
+        )?;
+
+        self.recreate_swapchain()?;
+        self.recreate_pipelines()
+    }
+
+    // Only has a visible effect if the window was created with transparency enabled
+    pub fn set_transparent(&mut self, transparent: bool) -> Result<(), EngineError> {
+        self.swapchain.config.transparent = transparent;
+        self.recreate_swapchain()
+    }
+
+    // Creates the buffer the swapchain image is copied into, see record_capture
+    fn begin_capture(
+        &mut self,
+        path: PathBuf,
+        frame: usize,
+        image_index: usize,
+    ) -> Result<FrameCapture, EngineError> {
+        if !self.swapchain.image_usage.contains(vk::ImageUsageFlags::TRANSFER_SRC) {
+            return Err(CaptureError::TransferNotSupported.into());
+        }
+
+        let format = self.swapchain.surface_format.format;
+
+        let bgra = match format {
+            vk::Format::B8G8R8A8_UNORM | vk::Format::B8G8R8A8_SRGB => true,
+            vk::Format::R8G8B8A8_UNORM | vk::Format::R8G8B8A8_SRGB => false,
+            _ => return Err(CaptureError::UnsupportedFormat(format).into()),
+        };
+
+        let extent = self.swapchain.extent;
+
+        let buffer = EngineBuffer::new(
+            &mut self.allocator,
+            extent.width as u64 * extent.height as u64 * 4,
+            vk::BufferUsageFlags::TRANSFER_DST,
+            gpu_allocator::MemoryLocation::GpuToCpu
+        )?;
+
+        Ok(FrameCapture {
+            path,
+            buffer,
+            extent,
+            bgra,
+            frame,
+            image_index,
+        })
+    }
+
+    // Copies the swapchain image into the capture buffer after the last pass, before it's presented
+    fn record_capture(&self, command_buffer: vk::CommandBuffer, capture: &FrameCapture) {
+        let image = self.swapchain.images[capture.image_index];
+        let vk::Extent2D { width, height } = capture.extent;
+
+        let subresource_range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+
+        let to_transfer = vk::ImageMemoryBarrier::builder()
+            .image(image)
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .old_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+            .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .subresource_range(subresource_range)
+            .build();
+
+        // Presenting waits on the semaphore signaled after the whole submission, so no access
+        // has to be made visible to it
+        let to_present = vk::ImageMemoryBarrier::builder()
+            .image(image)
+            .src_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .dst_access_mask(vk::AccessFlags::empty())
+            .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+            .subresource_range(subresource_range)
+            .build();
+
+        let to_host = vk::BufferMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::HOST_READ)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .buffer(capture.buffer.buffer)
+            .offset(0)
+            .size(vk::WHOLE_SIZE)
+            .build();
+
+        // A row length of 0 means rows are tightly packed at width texels each
+        let region = vk::BufferImageCopy {
+            buffer_offset: 0,
+            buffer_row_length: 0,
+            buffer_image_height: 0,
+            image_subresource: vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: 0,
+                base_array_layer: 0,
+                layer_count: 1,
+            },
+            image_offset: vk::Offset3D { x: 0, y: 0, z: 0 },
+            image_extent: vk::Extent3D { width, height, depth: 1 },
+        };
+
+        unsafe {
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[to_transfer],
+            );
+
+            self.device.cmd_copy_image_to_buffer(
+                command_buffer,
+                image,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                capture.buffer.buffer,
+                &[region],
+            );
+
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::HOST | vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[to_host],
+                &[to_present],
+            );
+        }
+    }
+
+    // Writes the capture recorded for this frame, its fence has to have signaled
+    fn finish_capture(&mut self, frame: usize) {
+        match &self.frame_capture {
+            Some(capture) if capture.frame == frame => {}
+            _ => return,
+        }
+
+        if let Some(mut capture) = self.frame_capture.take() {
+            let result = self.save_capture(&capture);
+
+            unsafe {
+                capture.buffer.cleanup(&mut self.allocator);
+            }
+
+            if let Err(error) = result {
+                println!("Failed to capture frame: {}", error);
+            }
+        }
+    }
+
+    fn save_capture(&self, capture: &FrameCapture) -> Result<(), EngineError> {
+        let vk::Extent2D { width, height } = capture.extent;
+        let mut data = capture.buffer.read::<u8>()?;
+
+        let row_pitch = width as usize * 4;
+
+        for row in data.chunks_exact_mut(row_pitch) {
+            for pixel in row.chunks_exact_mut(4) {
+                if capture.bgra {
+                    pixel.swap(0, 2);
+                }
+
+                // With opaque composition the alpha channel is whatever the shaders left in it
+                if !self.swapchain.config.transparent {
+                    pixel[3] = 255;
+                }
+            }
+        }
+
+        image::RgbaImage::from_raw(width, height, data)
+            .expect("Capture buffer has the size of the image")
+            .save(&capture.path)?;
+
+        Ok(())
+    }
+
+    // GPU time between the start and the end of the render pass of the most recently finished
+    // frame, None without timestamp support or before the first frame finished
+    pub fn last_gpu_frame_ms(&self) -> Option<f32> {
+        self.gpu_timer.as_ref().and_then(|gpu_timer| gpu_timer.last_frame_ms())
+    }
+
+    // Puts the texture at index in the texture array, for the instances with that texture_index.
+    // The sets aren't created with UPDATE_AFTER_BIND, so this waits for in-flight frames first.
+    pub fn write_texture_descriptors(
+        &self,
+        index: u32,
+        image_view: vk::ImageView,
+        sampler: vk::Sampler,
+    ) -> Result<(), EngineError> {
+        if index >= self.max_textures {
+            return Err(EngineError::InvalidTextureIndex(index));
+        }
+
+        unsafe {
+            self.device.device_wait_idle()?;
+        }
+
+        self.write_texture_binding(0, index, image_view, sampler);
+
+        Ok(())
+    }
+
+    // The normal map has to come from Texture::normal_map_from_file, sRGB decoding would bend its
+    // normals. Waits for in-flight frames like write_texture_descriptors.
+    pub fn write_normal_map_descriptors(
+        &self,
+        image_view: vk::ImageView,
+        sampler: vk::Sampler,
+    ) -> Result<(), EngineError> {
+        unsafe {
+            self.device.device_wait_idle()?;
+        }
+
+        self.write_texture_binding(1, 0, image_view, sampler);
+
+        Ok(())
+    }
+
+    fn write_texture_binding(
+        &self,
+        binding: u32,
+        array_element: u32,
+        image_view: vk::ImageView,
+        sampler: vk::Sampler,
+    ) {
+        let image_infos = [vk::DescriptorImageInfo {
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            image_view,
+            sampler,
+        }];
+
+        let desc_sets_write: Vec<vk::WriteDescriptorSet> = self.descriptor_sets_texture
+            .iter()
+            .map(|desc_set| vk::WriteDescriptorSet::builder()
+                .dst_set(*desc_set)
+                .dst_binding(binding)
+                .dst_array_element(array_element)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(&image_infos)
+                .build())
+            .collect();
+
+        unsafe { self.device.update_descriptor_sets(&desc_sets_write, &[]) };
+    }
+
+    // Replaces the current skybox, if any, and takes care of destroying it
+    pub fn set_skybox(&mut self, skybox: Skybox) -> Result<(), vk::Result> {
+        unsafe {
+            self.device.device_wait_idle()?;
+        }
+
+        let image_infos = [vk::DescriptorImageInfo {
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            image_view: skybox.texture.image_view,
+            sampler: skybox.texture.sampler,
+        }];
+
+        let desc_sets_write = [
+            vk::WriteDescriptorSet::builder()
+                .dst_set(self.descriptor_set_skybox)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(&image_infos)
+                .build()
+        ];
+
+        unsafe { self.device.update_descriptor_sets(&desc_sets_write, &[]) };
+
+        if let Some(mut old_skybox) = self.skybox.replace(skybox) {
+            unsafe {
+                old_skybox.cleanup(&mut self.allocator, &self.device);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Falls back to FIFO when the surface doesn't support the requested mode
+    pub fn set_present_mode(&mut self, present_mode: PresentMode) -> Result<(), EngineError> {
+        self.swapchain.config.present_mode = present_mode;
+        self.recreate_swapchain()?;
+
+        if self.swapchain.present_mode != present_mode.to_vk() {
+            println!("Surface doesn't support {:?}, presenting with {:?}", present_mode, self.swapchain.present_mode);
+        }
+
+        Ok(())
+    }
+
+    // The camera has to be switched over too, see Camera::set_reverse_z
+    pub fn set_reverse_z(&mut self, reverse_z: bool) -> Result<(), EngineError> {
+        self.swapchain.config.reverse_z = reverse_z;
+        self.clear_depth = if reverse_z { 0.0 } else { 1.0 };
+        self.command_buffers_dirty = true;
+        self.recreate_pipelines()
+    }
+
+    // Ignored on devices without VK_EXT_conservative_rasterization
+    pub fn set_conservative_rasterization(&mut self, enabled: bool) -> Result<(), EngineError> {
+        self.conservative_rasterization = enabled;
+        self.recreate_pipelines()
+    }
+
+    // For textures and device local buffers, e.g. Texture::from_file
+    pub fn upload_queues(&self) -> UploadQueues {
+        Self::upload_queues_for(&self.pools, &self.queues, &self.queue_families)
+    }
+
+    fn upload_queues_for(pools: &Pools, queues: &Queues, queue_families: &QueueFamilies) -> UploadQueues {
+        UploadQueues {
+            transfer_pool: pools.command_pool_transfer,
+            transfer_queue: queues.transfer,
+            transfer_family: queue_families.transfer_index.unwrap(),
+            graphics_pool: pools.command_pool_graphics,
+            graphics_queue: queues.graphics,
+            graphics_family: queue_families.graphics_index.unwrap(),
+        }
+    }
+
+    // Clamps the desired anisotropy to what the device supports, 1.0 disables anisotropic filtering
+    pub fn sampler_anisotropy(&self, desired: f32) -> f32 {
+        desired.clamp(1.0, self.max_sampler_anisotropy)
+    }
+
+    // Draws every model with the wireframe pipeline, on top of the per model setting
+    pub fn set_wireframe(&mut self, wireframe: bool) {
+        if wireframe && self.enabled_features.fill_mode_non_solid != vk::TRUE {
+            println!("Wireframe wasn't enabled in the builder, models stay filled");
+        }
+
+        self.wireframe = wireframe;
+    }
+
+    // Returns the index of the model in lit_models
+    pub fn add_lit_model(&mut self, model: Model<VertexData, InstanceData>) -> usize {
+        self.lit_models.push(model);
+        self.command_buffers_dirty = true;
+
+        self.lit_models.len() - 1
+    }
+
+    // Uploads the lights to the light buffer, call after changing them
+    pub fn update_lights(&mut self) -> Result<(), EngineError> {
+        self.lights.update_buffer(&mut self.allocator, &mut self.light_buffer)
+    }
+
+    // Sets the ambient light of every lit model and uploads the lights
+    pub fn set_ambient(&mut self, color: [f32; 3], intensity: f32) -> Result<(), EngineError> {
+        self.lights.set_ambient(color, intensity);
+        self.update_lights()
+    }
+
+    // Returns the index of the model in models
+    pub fn add_model(&mut self, model: Model<TexturedVertexData, TexturedInstanceData>) -> usize {
+        self.models.push(model);
+        self.command_buffers_dirty = true;
+
+        self.models.len() - 1
+    }
+
+    // The returned model keeps its CPU side data, its buffers are freed once the GPU is done with them
+    pub fn remove_model(
+        &mut self,
+        index: usize,
+    ) -> Result<Model<TexturedVertexData, TexturedInstanceData>, EngineError> {
+        if index >= self.models.len() {
+            return Err(EngineError::InvalidModelIndex(index));
+        }
+
+        unsafe {
+            self.device.device_wait_idle()?;
+        }
+
+        let mut model = self.models.remove(index);
+
+        unsafe {
+            model.cleanup(&mut self.allocator);
+        }
+
+        self.command_buffers_dirty = true;
+
+        Ok(model)
+    }
+
+    // Records every command buffer again if models were added or removed since the last recording.
+    // Not needed when update_command_buffer records each frame anyway.
+    pub fn refresh_command_buffers(&mut self) -> Result<(), vk::Result> {
+        if !self.command_buffers_dirty {
+            return Ok(());
+        }
+
+        unsafe {
+            self.device.device_wait_idle()?;
+        }
+
+        self.fill_command_buffers();
+        self.command_buffers_dirty = false;
+
+        Ok(())
+    }
+
+    pub fn set_model_wireframe(&mut self, index: usize, wireframe: bool) {
+        if let Some(model) = self.models.get_mut(index) {
+            model.wireframe = wireframe;
+        }
+    }
+
+    // Transparent models are sorted and drawn after the opaque ones from the next frame on
+    pub fn set_model_transparent(&mut self, index: usize, transparent: bool) {
+        if let Some(model) = self.models.get_mut(index) {
+            model.transparent = transparent;
+        }
+    }
+
+    // Takes effect the next time a command buffer is recorded
+    pub fn set_clear_color(&mut self, clear_color: [f32; 4]) {
+        self.clear_color = clear_color;
+        self.command_buffers_dirty = true;
+    }
+
+    // Uses the engine's formats and sample count, so it has to be created again after set_samples
+    // or after a post-process is set or cleared. The caller owns it and has to clean it up before
+    // the engine is dropped.
+    pub fn create_render_target(&mut self, extent: vk::Extent2D) -> Result<RenderTarget, EngineError> {
+        let color_format = self.scene_color_format();
+
+        RenderTarget::init(
+            &self.device,
+            &mut self.allocator,
+            extent,
+            color_format,
+            self.swapchain.config.depth_format,
+            self.swapchain.config.samples,
+        )
+    }
+
+    // Draws the scene into the target with the camera of the last frame and waits until it's done
+    pub fn render_to_target(&self, target: &RenderTarget) -> Result<(), EngineError> {
+        buffer::submit_once(
+            &self.device,
+            self.pools.command_pool_graphics,
+            self.queues.graphics,
+            |command_buffer| unsafe {
+                self.begin_render_pass(command_buffer, target.render_pass, target.framebuffer, target.extent);
+
+                self.draw_scene(command_buffer, 0);
+
+                self.device.cmd_end_render_pass(command_buffer);
+            },
+        )?;
+
+        Ok(())
+    }
+
+    // Draws the scene into an offscreen target and then the target through the shader into the
+    // swapchain image. The shader samples set 0, binding 0 with the uv at location 0, see
+    // shaders/grayscale.frag.
+    pub fn set_post_process<P: AsRef<std::path::Path>>(&mut self, shader_path: P) -> Result<(), EngineError> {
+        self.install_post_process(|engine, target| PostProcess::new(
+            &engine.device,
+            shader_path,
+            target,
+            &engine.swapchain,
+            engine.render_pass,
+            engine.pipeline_cache,
+        ))
+    }
+
+    // Maps the scene's light values into the displayable range with the ACES curve, see set_exposure
+    pub fn set_tonemap_post_process(&mut self) -> Result<(), EngineError> {
+        self.install_post_process(|engine, target| PostProcess::tonemap(
+            &engine.device,
+            target,
+            &engine.swapchain,
+            engine.render_pass,
+            engine.pipeline_cache,
+        ))
+    }
+
+    pub fn set_grayscale_post_process(&mut self) -> Result<(), EngineError> {
+        self.install_post_process(|engine, target| PostProcess::grayscale(
+            &engine.device,
+            target,
+            &engine.swapchain,
+            engine.render_pass,
+            engine.pipeline_cache,
+        ))
+    }
+
+    fn install_post_process(
+        &mut self,
+        create: impl FnOnce(&Self, &RenderTarget) -> Result<PostProcess, EngineError>,
+    ) -> Result<(), EngineError> {
+        unsafe {
+            self.device.device_wait_idle()?;
+        }
+
+        let target_created = self.post_process_target.is_none();
+
+        let mut target = match self.post_process_target.take() {
+            Some(target) => target,
+            None => self.create_post_process_target()?,
+        };
+
+        let post_process = match create(self, &target) {
+            Ok(post_process) => post_process,
+            Err(error) => {
+                // A target created for this post-process would otherwise be kept and resized
+                // without anything reading it
+                if target_created {
+                    unsafe {
+                        target.cleanup(&self.device, &mut self.allocator);
+                    }
+                } else {
+                    self.post_process_target = Some(target);
+                }
+
+                return Err(error);
+            }
+        };
+
+        self.post_process_target = Some(target);
+
+        if let Some(old) = self.post_process.replace(post_process) {
+            old.cleanup(&self.device);
+        }
+
+        self.command_buffers_dirty = true;
+
+        // The scene pipelines move over to the target's render pass
+        if target_created {
+            self.recreate_pipelines()?;
+        }
+
+        Ok(())
+    }
+
+    // Goes back to drawing the scene straight into the swapchain images
+    pub fn clear_post_process(&mut self) -> Result<(), EngineError> {
+        unsafe {
+            self.device.device_wait_idle()?;
+        }
+
+        if let Some(post_process) = self.post_process.take() {
+            post_process.cleanup(&self.device);
+        }
+
+        let mut target = match self.post_process_target.take() {
+            Some(target) => target,
+            None => return Ok(()),
+        };
+
+        // The pipelines have to be back on the engine's render pass before the target's goes away
+        let result = self.recreate_pipelines();
+
+        unsafe {
+            target.cleanup(&self.device, &mut self.allocator);
+        }
+
+        self.command_buffers_dirty = true;
+
+        result
+    }
+
+    // Only has an effect with a post-process that reads it, like set_tonemap_post_process
+    pub fn set_exposure(&mut self, exposure: f32) {
+        self.exposure = exposure;
+        self.command_buffers_dirty = true;
+    }
+
+    fn create_post_process_target(&mut self) -> Result<RenderTarget, EngineError> {
+        RenderTarget::init(
+            &self.device,
+            &mut self.allocator,
+            self.swapchain.extent,
+            HDR_FORMAT,
+            self.swapchain.config.depth_format,
+            self.swapchain.config.samples,
+        )
+    }
+
+    // The render pass the scene is drawn with, which the scene pipelines have to be compatible with
+    fn scene_render_pass(&self) -> vk::RenderPass {
+        match &self.post_process_target {
+            Some(target) => target.render_pass,
+            None => self.render_pass,
+        }
+    }
+
+    fn scene_color_format(&self) -> vk::Format {
+        match &self.post_process_target {
+            Some(_) => HDR_FORMAT,
+            None => self.swapchain.surface_format.format,
+        }
+    }
+
+    // The target follows the swapchain's extent and sample count
+    fn recreate_post_process_target(&mut self) -> Result<(), EngineError> {
+        let mut old = match self.post_process_target.take() {
+            Some(old) => old,
+            None => return Ok(()),
+        };
+
+        unsafe {
+            old.cleanup(&self.device, &mut self.allocator);
+        }
+
+        let target = self.create_post_process_target()?;
+
+        if let Some(post_process) = &self.post_process {
+            post_process.set_input(&self.device, &target);
+        }
 
-        self.pipeline = EnginePipeline::init_textured(
-            &self.device,
-            &self.swapchain,
-            self.render_pass
-        )?;
+        self.post_process_target = Some(target);
 
         Ok(())
     }
 
-    fn init_render_pass(
-        device: &Device,
-        physical_device: vk::PhysicalDevice,
-        surfaces: &EngineSurface
-    ) -> Result<vk::RenderPass, vk::Result> {
-        let attachments = [
-            vk::AttachmentDescription::builder()
-                .format(
-                    surfaces.formats(physical_device)?
-                        .first()
-                        .unwrap()
-                        .format
-                )
-                .load_op(vk::AttachmentLoadOp::CLEAR)
-                .store_op(vk::AttachmentStoreOp::STORE)
-                .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
-                .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
-                .initial_layout(vk::ImageLayout::UNDEFINED)
-                .final_layout(vk::ImageLayout::PRESENT_SRC_KHR)
-                .samples(vk::SampleCountFlags::TYPE_1)
-                .build(),
-            vk::AttachmentDescription::builder()
-                .format(vk::Format::D32_SFLOAT)
-                .load_op(vk::AttachmentLoadOp::CLEAR)
-                .store_op(vk::AttachmentStoreOp::DONT_CARE)
-                .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
-                .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
-                .initial_layout(vk::ImageLayout::UNDEFINED)
-                .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
-                .samples(vk::SampleCountFlags::TYPE_1)
-                .build()
-        ];
+    fn begin_render_pass(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        render_pass: vk::RenderPass,
+        framebuffer: vk::Framebuffer,
+        extent: vk::Extent2D,
+    ) {
+        let clear_values = self.clear_values();
 
-        let color_attachment_refs = [
-            vk::AttachmentReference {
-                attachment: 0,
-                layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(render_pass)
+            .framebuffer(framebuffer)
+            .render_area(vk::Rect2D {
+                offset: vk::Offset2D {
+                    x: 0,
+                    y: 0,
+                },
+                extent,
+            })
+            .clear_values(&clear_values);
+
+        unsafe {
+            self.device.cmd_begin_render_pass(
+                command_buffer,
+                &render_pass_begin_info,
+                vk::SubpassContents::INLINE
+            );
+        }
+
+        self.set_viewport_and_scissor(command_buffer, extent);
+    }
+
+    // Shared by every pass begin_render_pass starts, so targets and frames clear to the same background
+    fn clear_values(&self) -> [vk::ClearValue; 2] {
+        [
+            vk::ClearValue {
+                color: vk::ClearColorValue {
+                    float32: if self.swapchain.config.transparent {
+                        [0.0, 0.0, 0.0, 0.0]
+                    } else {
+                        self.clear_color
+                    },
+                }
+            },
+            vk::ClearValue {
+                depth_stencil: vk::ClearDepthStencilValue {
+                    depth: self.clear_depth,
+                    stencil: self.clear_stencil,
+                }
             }
-        ];
+        ]
+    }
 
-        let depth_attachment_refs = [
-            vk::AttachmentReference {
-                attachment: 1,
-                layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL
+    fn set_viewport_and_scissor(&self, command_buffer: vk::CommandBuffer, extent: vk::Extent2D) {
+        let viewports = [
+            vk::Viewport {
+                x: 0.0,
+                y: 0.0,
+                width: extent.width as f32,
+                height: extent.height as f32,
+                min_depth: 0.0,
+                max_depth: 1.0,
             }
         ];
-
-        let subpasses = [
-            vk::SubpassDescription::builder()
-                .color_attachments(&color_attachment_refs)
-                .depth_stencil_attachment(&depth_attachment_refs[0])
-                .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
-                .build()
+        let scissors = [
+            vk::Rect2D {
+                offset: vk::Offset2D {
+                    x: 0,
+                    y: 0,
+                },
+                extent
+            }
         ];
 
-        let subpass_dependencies = [
-            vk::SubpassDependency::builder()
-                .src_subpass(vk::SUBPASS_EXTERNAL)
-                .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
-                .dst_subpass(0)
-                .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
-                .dst_access_mask(
-                    vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE
-                )
-                .build()
-        ];
+        unsafe {
+            self.device.cmd_set_viewport(command_buffer, 0, &viewports);
+            self.device.cmd_set_scissor(command_buffer, 0, &scissors);
+        }
+    }
 
-        let render_pass_info = vk::RenderPassCreateInfo::builder()
-            .attachments(&attachments)
-            .subpasses(&subpasses)
-            .dependencies(&subpass_dependencies);
+    fn mark(&self, command_buffer: vk::CommandBuffer, checkpoint: Checkpoint) {
+        if let Some(breadcrumbs) = &self.breadcrumbs {
+            breadcrumbs.mark(command_buffer, checkpoint);
+        }
+    }
 
-        unsafe {
-            device.create_render_pass(&render_pass_info, None)
+    // Meant to be called after ERROR_DEVICE_LOST, prints how far the graphics queue got
+    pub fn report_device_lost(&self) {
+        match &self.breadcrumbs {
+            Some(breadcrumbs) => {
+                for (checkpoint, stage) in breadcrumbs.last_checkpoints(self.queues.graphics) {
+                    println!("[Device lost] last checkpoint: {:?} at {:?}", checkpoint, stage);
+                }
+            }
+            None => println!("[Device lost] no breadcrumbs available"),
         }
     }
 
@@ -452,39 +2549,80 @@ impl VulkanEngine {
             self.device.begin_command_buffer(command_buffer, &command_buffer_begin_info)
         }?;
 
-        let clear_values = [
-            vk::ClearValue {
-                color: vk::ClearColorValue {
-                    float32: [0.0, 0.0, 0.08, 1.0],
-                }
-            },
-            vk::ClearValue {
-                depth_stencil: vk::ClearDepthStencilValue {
-                    depth: 1.0,
-                    stencil: 0,
-                }
+        self.mark(command_buffer, Checkpoint::BeginCommandBuffer);
+
+        if let Some(gpu_timer) = &mut self.gpu_timer {
+            gpu_timer.begin(&self.device, command_buffer, index);
+        }
+
+        self.record_passes(command_buffer, index);
+
+        if let Some(gpu_timer) = &self.gpu_timer {
+            gpu_timer.end(&self.device, command_buffer, index);
+        }
+
+        // Only in the frame the capture was requested for, see finish_capture
+        if let Some(capture) = &self.frame_capture {
+            if capture.frame == self.swapchain.current_frame && capture.image_index == index {
+                self.record_capture(command_buffer, capture);
             }
-        ];
+        }
 
-        let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
-            .render_pass(self.render_pass)
-            .framebuffer(self.swapchain.framebuffers[index])
-            .render_area(vk::Rect2D {
-                offset: vk::Offset2D {
-                    x: 0,
-                    y: 0,
-                },
-                extent: self.swapchain.extent,
-            })
-            .clear_values(&clear_values);
+        unsafe {
+            self.device.end_command_buffer(command_buffer)?;
+        }
+
+        Ok(())
+    }
 
+    // The passes of a frame, shared by update_command_buffer and fill_command_buffers
+    fn record_passes(&self, command_buffer: vk::CommandBuffer, index: usize) {
         unsafe {
-            self.device.cmd_begin_render_pass(
+            // With a post-process the scene goes into its target first, which is then drawn
+            // into the swapchain image through the post-process shader
+            let post_process = match (&self.post_process, &self.post_process_target) {
+                (Some(post_process), Some(target)) => {
+                    self.begin_render_pass(command_buffer, target.render_pass, target.framebuffer, target.extent);
+
+                    self.draw_scene(command_buffer, index);
+
+                    self.device.cmd_end_render_pass(command_buffer);
+
+                    Some(post_process)
+                }
+                _ => None,
+            };
+
+            self.begin_render_pass(
                 command_buffer,
-                &render_pass_begin_info,
-                vk::SubpassContents::INLINE
+                self.render_pass,
+                self.swapchain.framebuffers[index],
+                self.swapchain.extent,
             );
 
+            self.mark(command_buffer, Checkpoint::BeginRenderPass);
+
+            match post_process {
+                Some(post_process) => post_process.draw(&self.device, command_buffer, self.exposure),
+                None => self.draw_scene(command_buffer, index),
+            }
+
+            self.device.cmd_end_render_pass(command_buffer);
+        }
+
+        self.mark(command_buffer, Checkpoint::EndRenderPass);
+    }
+
+    // Blended models come last, so everything opaque behind them is already in the image
+    fn draw_scene(&self, command_buffer: vk::CommandBuffer, index: usize) {
+        self.draw_skybox(command_buffer, index);
+        self.draw_models(command_buffer, index);
+        self.draw_lit_models(command_buffer, index);
+        self.draw_transparent_models(command_buffer, index);
+    }
+
+    fn draw_models(&self, command_buffer: vk::CommandBuffer, index: usize) {
+        unsafe {
             self.device.cmd_bind_pipeline(
                 command_buffer,
                 vk::PipelineBindPoint::GRAPHICS,
@@ -502,112 +2640,247 @@ impl VulkanEngine {
                 ],
                 &[],
             );
+        }
 
-            for m in &self.models {
-                m.draw(&self.device, command_buffer);
+        let mut wireframe_bound = false;
+
+        self.mark(command_buffer, Checkpoint::DrawModels);
+
+        for m in self.models.iter().filter(|m| !m.transparent) {
+            let wireframe = self.wireframe || m.wireframe;
+
+            if wireframe != wireframe_bound {
+                let pipeline = if wireframe {
+                    &self.wireframe_pipeline
+                } else {
+                    &self.pipeline
+                };
+
+                unsafe {
+                    self.device.cmd_bind_pipeline(
+                        command_buffer,
+                        vk::PipelineBindPoint::GRAPHICS,
+                        pipeline.pipeline
+                    );
+                }
+
+                wireframe_bound = wireframe;
             }
 
-            self.device.cmd_end_render_pass(command_buffer);
-            self.device.end_command_buffer(command_buffer)?;
+            m.draw(&self.device, command_buffer);
         }
-
-        Ok(())
     }
 
-    fn fill_command_buffers(&self, models: &[Model<TexturedVertexData, TexturedInstanceData>]) {
-        for (i, &command_buffer) in self.graphics_command_buffers.iter().enumerate() {
-            let command_buffer_begin_info = vk::CommandBufferBeginInfo::builder();
+    // Back to front in the order draw_frame sorted them, without writing depth so ones further
+    // back don't hide the ones in front. Wireframe mode draws them like opaque models.
+    fn draw_transparent_models(&self, command_buffer: vk::CommandBuffer, index: usize) {
+        if self.transparent_order.is_empty() {
+            return;
+        }
 
-            unsafe {
-                self.device.begin_command_buffer(command_buffer, &command_buffer_begin_info).unwrap();
-            }
+        let pipeline = if self.wireframe {
+            &self.wireframe_pipeline
+        } else {
+            &self.transparent_pipeline
+        };
 
-            let clear_values = [
-                vk::ClearValue {
-                    color: vk::ClearColorValue {
-                        float32: [0.0, 0.0, 0.0, 1.0],
-                    }
-                },
-                vk::ClearValue {
-                    depth_stencil: vk::ClearDepthStencilValue {
-                        depth: 1.0,
-                        stencil: 0,
-                    }
-                }
-            ];
-
-            let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
-                .render_pass(self.render_pass)
-                .framebuffer(self.swapchain.framebuffers[i])
-                .render_area(vk::Rect2D {
-                    offset: vk::Offset2D {
-                        x: 0,
-                        y: 0,
-                    },
-                    extent: self.swapchain.extent
-                })
-                .clear_values(&clear_values);
+        unsafe {
+            self.device.cmd_bind_pipeline(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                pipeline.pipeline
+            );
+
+            self.device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                pipeline.layout,
+                0,
+                &[
+                    self.descriptor_sets_cam[index],
+                    self.descriptor_sets_texture[index]
+                ],
+                &[],
+            );
+        }
+
+        for &i in &self.transparent_order {
+            if let Some(m) = self.models.get(i) {
+                m.draw(&self.device, command_buffer);
+            }
+        }
+    }
 
+    // Drawn before the models, so anything they draw ends up in front of it
+    fn draw_skybox(&self, command_buffer: vk::CommandBuffer, index: usize) {
+        if let Some(skybox) = &self.skybox {
             unsafe {
-                self.device.cmd_begin_render_pass(
+                self.device.cmd_bind_pipeline(
                     command_buffer,
-                    &render_pass_begin_info,
-                    vk::SubpassContents::INLINE
+                    vk::PipelineBindPoint::GRAPHICS,
+                    self.skybox_pipeline.pipeline
                 );
 
-                self.device.cmd_bind_pipeline(
+                self.device.cmd_bind_descriptor_sets(
                     command_buffer,
                     vk::PipelineBindPoint::GRAPHICS,
-                    self.pipeline.pipeline
+                    self.skybox_pipeline.layout,
+                    0,
+                    &[
+                        self.descriptor_sets_cam[index],
+                        self.descriptor_set_skybox
+                    ],
+                    &[],
                 );
+            }
 
-                //draw models
-                for model in models {
-                    model.draw(&self.device, command_buffer);
-                }
+            skybox.model.draw(&self.device, command_buffer);
+        }
+    }
 
-                self.device.cmd_end_render_pass(command_buffer);
+    // The lit pipeline shares the camera set and the depth buffer with the textured one,
+    // so both kinds of models interleave correctly within the render pass
+    fn draw_lit_models(&self, command_buffer: vk::CommandBuffer, index: usize) {
+        if self.lit_models.is_empty() {
+            return;
+        }
+
+        unsafe {
+            self.device.cmd_bind_pipeline(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.lit_pipeline.pipeline
+            );
+
+            self.device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.lit_pipeline.layout,
+                0,
+                &[
+                    self.descriptor_sets_cam[index],
+                    self.descriptor_sets_light[index]
+                ],
+                &[],
+            );
+        }
+
+        self.lit_pipeline.push_constants(&self.device, command_buffer, &PushConstants::instanced());
+
+        for m in &self.lit_models {
+            match &m.push_constant_matrix {
+                Some(model_matrix) => m.draw_with_push_constant(
+                    &self.device,
+                    command_buffer,
+                    &self.lit_pipeline,
+                    &self.default_instance_buffer,
+                    model_matrix,
+                ),
+                None => m.draw(&self.device, command_buffer),
+            }
+        }
+    }
+
+    fn fill_command_buffers(&self) {
+        for (i, &command_buffer) in self.graphics_command_buffers.iter().enumerate() {
+            let command_buffer_begin_info = vk::CommandBufferBeginInfo::builder();
+
+            unsafe {
+                self.device.begin_command_buffer(command_buffer, &command_buffer_begin_info).unwrap();
+            }
 
+            self.mark(command_buffer, Checkpoint::BeginCommandBuffer);
+
+            self.record_passes(command_buffer, i);
+
+            unsafe {
                 self.device.end_command_buffer(command_buffer).unwrap();
             }
         }
     }
 }
 
+// Drawing lines needs fill_mode_non_solid, without it the wireframe pipeline stays filled
+fn wireframe_polygon_mode(enabled_features: &vk::PhysicalDeviceFeatures) -> vk::PolygonMode {
+    if enabled_features.fill_mode_non_solid == vk::TRUE {
+        vk::PolygonMode::LINE
+    } else {
+        vk::PolygonMode::FILL
+    }
+}
+
 impl Drop for VulkanEngine{
     fn drop(&mut self) {
         unsafe {
             self.device.device_wait_idle().expect("Failed to wait?");
 
-            //self.light_buffer.cleanup(&mut self.allocator, &self.device);
+            self.light_buffer.cleanup(&mut self.allocator);
+            self.default_instance_buffer.cleanup(&mut self.allocator);
+
+            if let Some(capture) = &mut self.frame_capture {
+                capture.buffer.cleanup(&mut self.allocator);
+            }
+
+            if let Some(post_process) = &self.post_process {
+                post_process.cleanup(&self.device);
+            }
+
+            if let Some(target) = &mut self.post_process_target {
+                target.cleanup(&self.device, &mut self.allocator);
+            }
 
             self.device.destroy_descriptor_pool(self.descriptor_pool, None);
 
             self.uniform_buffer.cleanup(&mut self.allocator);
 
+            if let Some(breadcrumbs) = &mut self.breadcrumbs {
+                breadcrumbs.cleanup(&mut self.allocator);
+            }
+
             for m in &mut self.models {
-                if let Some(vb) = &mut m.vertex_buffer {
-                    vb.cleanup(&mut self.allocator);
-                }
+                m.cleanup(&mut self.allocator);
+            }
 
-                if let Some(ib) = &mut m.index_buffer {
-                    ib.cleanup(&mut self.allocator);
-                }
+            for m in &mut self.lit_models {
+                m.cleanup(&mut self.allocator);
+            }
 
-                if let Some(ib) = &mut m.instance_buffer {
-                    ib.cleanup(&mut self.allocator);
-                }
+            // Textures free their memory through the allocator, so they have to go before it
+            for t in &mut self.textures {
+                t.cleanup(&mut self.allocator, &self.device);
+            }
+
+            self.default_normal_map.cleanup(&mut self.allocator, &self.device);
+            self.default_texture.cleanup(&mut self.allocator, &self.device);
+
+            if let Some(skybox) = &mut self.skybox {
+                skybox.cleanup(&mut self.allocator, &self.device);
+            }
+
+            self.swapchain.cleanup(&self.device, &mut self.allocator);
+
+            if self.allocator.allocation_count() > 0 {
+                println!("{} allocations were never freed", self.allocator.allocation_count());
             }
 
             self.allocator.cleanup();
 
+            if let Some(gpu_timer) = &self.gpu_timer {
+                gpu_timer.cleanup(&self.device);
+            }
+
             self.pools.cleanup(&self.device);
 
             self.pipeline.cleanup(&self.device);
+            self.wireframe_pipeline.cleanup(&self.device);
+            self.transparent_pipeline.cleanup(&self.device);
+            self.skybox_pipeline.cleanup(&self.device);
+            self.lit_pipeline.cleanup(&self.device);
 
-            self.device.destroy_render_pass(self.render_pass, None);
+            self.save_pipeline_cache();
+            self.device.destroy_pipeline_cache(self.pipeline_cache, None);
 
-            self.swapchain.cleanup(&self.device);
+            self.device.destroy_render_pass(self.render_pass, None);
 
             ManuallyDrop::drop(&mut self.surfaces);
 
@@ -620,7 +2893,44 @@ impl Drop for VulkanEngine{
     }
 }
 
+#[allow(dead_code)]
 pub struct Queues {
     pub graphics: vk::Queue,
+    pub present: vk::Queue,
     pub transfer: vk::Queue,
-}
\ No newline at end of file
+    pub compute: vk::Queue,
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    #[ignore = "needs a display and a Vulkan device"]
+    fn remove_model_frees_its_buffers() {
+        use winit::platform::unix::EventLoopExtUnix;
+
+        let event_loop = winit::event_loop::EventLoop::<()>::new_any_thread();
+        let window = winit::window::WindowBuilder::new()
+            .with_visible(false)
+            .build(&event_loop)
+            .unwrap();
+
+        let mut engine = VulkanEngine::builder().validation(false).build(window).unwrap();
+        let baseline = engine.allocator.allocation_count();
+
+        let mut model = Model::quad();
+        model.insert_visibly(TexturedInstanceData::from_matrix(na::Matrix4::identity()));
+        model.update_vertex_buffer(&mut engine.allocator).unwrap();
+        model.update_index_buffer(&mut engine.allocator).unwrap();
+        model.update_instance_buffer(&mut engine.allocator).unwrap();
+
+        let index = engine.add_model(model);
+        assert_eq!(engine.allocator.allocation_count(), baseline + 3);
+
+        engine.remove_model(index).unwrap();
+        assert_eq!(engine.allocator.allocation_count(), baseline);
+
+        assert!(matches!(engine.remove_model(index), Err(EngineError::InvalidModelIndex(_))));
+    }
+}
@@ -1,10 +1,15 @@
 use std::collections::HashMap;
 use gpu_allocator::vulkan::Allocator;
-use super::buffer::EngineBuffer;
+use super::buffer::{EngineBuffer, UploadQueues};
 use ash::vk;
 use crate::engine::allocator::VkAllocator;
+use crate::engine::camera::Frustum;
+use crate::engine::error::EngineError;
+use crate::engine::pipeline::{EnginePipeline, PushConstants};
 use crate::na;
 
+pub const MAX_SPHERE_REFINEMENTS: u32 = 8;
+
 #[derive(Debug, Clone)]
 pub struct InvalidHandle;
 
@@ -19,58 +24,324 @@ impl std::error::Error for InvalidHandle {
     }
 }
 
+#[derive(Copy, Clone, Debug)]
+pub struct Transform {
+    pub translation: na::Vector3<f32>,
+    pub rotation: na::UnitQuaternion<f32>,
+    pub scale: na::Vector3<f32>,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Transform {
+            translation: na::Vector3::zeros(),
+            rotation: na::UnitQuaternion::identity(),
+            scale: na::Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl Transform {
+    pub fn matrix(&self) -> na::Matrix4<f32> {
+        na::Matrix4::new_translation(&self.translation)
+            * self.rotation.to_homogeneous()
+            * na::Matrix4::new_nonuniform_scaling(&self.scale)
+    }
+
+    // Splits a T * R * S matrix back up, any shear is lost. A zero scale axis gets the
+    // unrotated axis, a reflection ends up as a negative x scale.
+    pub fn from_matrix(matrix: &na::Matrix4<f32>) -> Transform {
+        let translation = matrix.fixed_slice::<3, 1>(0, 3).into_owned();
+        let mut linear = matrix.fixed_slice::<3, 3>(0, 0).into_owned();
+
+        let mut scale = na::Vector3::new(
+            linear.column(0).norm(),
+            linear.column(1).norm(),
+            linear.column(2).norm(),
+        );
+
+        for i in 0..3 {
+            if scale[i] != 0.0 {
+                let column = linear.column(i) / scale[i];
+                linear.set_column(i, &column);
+            } else {
+                linear.set_column(i, &na::Vector3::ith(i, 1.0));
+            }
+        }
+
+        if linear.determinant() < 0.0 {
+            scale.x = -scale.x;
+            let column = -linear.column(0);
+            linear.set_column(0, &column);
+        }
+
+        Transform {
+            translation,
+            rotation: na::UnitQuaternion::from_matrix(&linear),
+            scale,
+        }
+    }
+
+    // (T * R * S)^-1 = S^-1 * R^T * T^-1, a zero scale axis maps to zero instead of panicking
+    pub fn inverse_matrix(&self) -> na::Matrix4<f32> {
+        let inverse_scale = self.scale.map(|s| if s != 0.0 { 1.0 / s } else { 0.0 });
+
+        na::Matrix4::new_nonuniform_scaling(&inverse_scale)
+            * self.rotation.inverse().to_homogeneous()
+            * na::Matrix4::new_translation(&-self.translation)
+    }
+}
+
+// Degenerate transforms, e.g. a zero scale, have no inverse. Their normals come out wrong, but
+// nothing panics over them.
+fn inverse_or_identity(model_matrix: &na::Matrix4<f32>) -> na::Matrix4<f32> {
+    model_matrix.try_inverse().unwrap_or_else(|| {
+        println!("model matrix {:?} isn't invertible, using the identity as its inverse", model_matrix);
+        na::Matrix4::identity()
+    })
+}
+
+// Gives the vertex position used for the bounding radius
+pub trait Vertex {
+    fn position(&self) -> [f32; 3];
+}
+
+impl Vertex for [f32; 3] {
+    fn position(&self) -> [f32; 3] {
+        *self
+    }
+}
+
+// Converts an instance to the layout the vertex shader reads from the instance buffer
+pub trait ToGpu {
+    type Gpu;
+
+    fn to_gpu(&self) -> Self::Gpu;
+}
+
+#[allow(dead_code)]
+pub trait Transformable {
+    fn apply_transform(&mut self, transform: &Transform);
+
+    fn transform(&self) -> Transform;
+
+    fn model_matrix(&self) -> na::Matrix4<f32>;
+
+    fn set_translation(&mut self, translation: na::Vector3<f32>) {
+        let transform = Transform { translation, ..self.transform() };
+        self.apply_transform(&transform);
+    }
+
+    fn set_rotation(&mut self, rotation: na::UnitQuaternion<f32>) {
+        let transform = Transform { rotation, ..self.transform() };
+        self.apply_transform(&transform);
+    }
+
+    fn set_scale(&mut self, scale: na::Vector3<f32>) {
+        let transform = Transform { scale, ..self.transform() };
+        self.apply_transform(&transform);
+    }
+
+    fn set_transform(
+        &mut self,
+        translation: na::Vector3<f32>,
+        rotation: na::UnitQuaternion<f32>,
+        scale: na::Vector3<f32>,
+    ) {
+        self.apply_transform(&Transform { translation, rotation, scale });
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 #[repr(C)]
 pub struct TexturedVertexData {
     pub position: [f32; 3],
     pub texcoord: [f32; 2],
+    pub normal: [f32; 3],
+    // xyz points along increasing u, w is the sign of the bitangent, see compute_tangents
+    pub tangent: [f32; 4],
+}
+
+impl Vertex for TexturedVertexData {
+    fn position(&self) -> [f32; 3] {
+        self.position
+    }
 }
 
+#[derive(Copy, Clone)]
 #[repr(C)]
 pub struct TexturedInstanceData {
     pub model_matrix: [[f32; 4]; 4],
     pub inverse_model_matrix: [[f32; 4]; 4],
+    // Element of the engine's texture array, see VulkanEngine::write_texture_descriptors
+    pub texture_index: u32,
 }
 
+#[allow(dead_code)]
 impl TexturedInstanceData {
     pub fn from_matrix(model_matrix: na::Matrix4<f32>) -> TexturedInstanceData {
         TexturedInstanceData {
             model_matrix: model_matrix.into(),
-            inverse_model_matrix: model_matrix.try_inverse().unwrap().into(),
+            inverse_model_matrix: inverse_or_identity(&model_matrix).into(),
+            texture_index: 0,
         }
     }
+
+    pub fn from_transform(transform: &Transform) -> TexturedInstanceData {
+        TexturedInstanceData {
+            model_matrix: transform.matrix().into(),
+            inverse_model_matrix: transform.inverse_matrix().into(),
+            texture_index: 0,
+        }
+    }
+
+    pub fn with_texture_index(mut self, texture_index: u32) -> TexturedInstanceData {
+        self.texture_index = texture_index;
+        self
+    }
 }
 
+impl ToGpu for TexturedInstanceData {
+    type Gpu = TexturedInstanceData;
+
+    fn to_gpu(&self) -> TexturedInstanceData {
+        *self
+    }
+}
+
+impl Transformable for TexturedInstanceData {
+    fn apply_transform(&mut self, transform: &Transform) {
+        self.model_matrix = transform.matrix().into();
+        self.inverse_model_matrix = transform.inverse_matrix().into();
+    }
+
+    fn transform(&self) -> Transform {
+        Transform::from_matrix(&self.model_matrix.into())
+    }
+
+    fn model_matrix(&self) -> na::Matrix4<f32> {
+        self.model_matrix.into()
+    }
+}
+
+#[allow(dead_code)]
 impl Model<TexturedVertexData, TexturedInstanceData> {
     pub fn quad() -> Self {
+        Self::quad_sized(2.0, 2.0)
+    }
+
+    // Centered at the origin in the XY plane
+    pub fn quad_sized(width: f32, height: f32) -> Self {
+        Self::quad_with_uvs(width, height, [0.0, 0.0], [1.0, 1.0])
+    }
+
+    // uv_min is the texture coordinate of the top left corner, uv_max the one of the bottom right,
+    // e.g. a single frame of a sprite sheet
+    pub fn quad_with_uvs(width: f32, height: f32, uv_min: [f32; 2], uv_max: [f32; 2]) -> Self {
+        let (x, y) = (0.5 * width, 0.5 * height);
+
+        // Facing the -z side, which is where the front face is seen from
+        let normal = [0.0, 0.0, -1.0];
+        let tangent = [0.0; 4];
+
         let lb = TexturedVertexData {
-            position: [-1.0, 1.0, 0.0],
-            texcoord: [0.0, 1.0],
+            position: [-x, y, 0.0],
+            texcoord: [uv_min[0], uv_max[1]],
+            normal,
+            tangent,
         }; //lb: left-bottom
         let lt = TexturedVertexData {
-            position: [-1.0, -1.0, 0.0],
-            texcoord: [0.0, 0.0],
+            position: [-x, -y, 0.0],
+            texcoord: [uv_min[0], uv_min[1]],
+            normal,
+            tangent,
         };
         let rb = TexturedVertexData {
-            position: [1.0, 1.0, 0.0],
-            texcoord: [1.0, 1.0],
+            position: [x, y, 0.0],
+            texcoord: [uv_max[0], uv_max[1]],
+            normal,
+            tangent,
         };
         let rt = TexturedVertexData {
-            position: [1.0, -1.0, 0.0],
-            texcoord: [1.0, 0.0],
+            position: [x, -y, 0.0],
+            texcoord: [uv_max[0], uv_min[1]],
+            normal,
+            tangent,
         };
 
-        Model {
-            vertex_data: vec![lb, lt, rb, rt],
-            index_data: vec![0, 2, 1, 1, 2, 3],
-            handle_to_index: std::collections::HashMap::new(),
-            handles: Vec::new(),
-            instances: Vec::new(),
-            first_invisible: 0,
-            next_handle: 0,
-            vertex_buffer: None,
-            index_buffer: None,
-            instance_buffer: None,
+        let mut model = Self::from_mesh(vec![lb, lt, rb, rt], vec![0, 2, 1, 1, 2, 3]);
+
+        model.compute_tangents();
+
+        model
+    }
+
+    // The sphere of uv_sphere with texture coordinates, u going around and v from top to bottom
+    pub fn textured_uv_sphere(stacks: u32, slices: u32) -> Self {
+        let (stacks, slices) = (stacks.max(2), slices.max(3));
+
+        let vertex_data = uv_sphere_points(stacks, slices)
+            .into_iter()
+            .map(|(position, texcoord)| TexturedVertexData {
+                position,
+                texcoord,
+                normal: position,
+                tangent: [0.0; 4],
+            })
+            .collect();
+
+        let mut model = Self::from_mesh(vertex_data, uv_sphere_indices(stacks, slices));
+
+        model.compute_tangents();
+
+        model
+    }
+
+    // Tangents from the texture coordinates of the triangles around each vertex, made orthogonal
+    // to the normal. w is -1 where the texture is mirrored, so cross(normal, tangent) * w always
+    // points along increasing v.
+    pub fn compute_tangents(&mut self) {
+        let mut tangents = vec![na::Vector3::zeros(); self.vertex_data.len()];
+        let mut bitangents = vec![na::Vector3::zeros(); self.vertex_data.len()];
+
+        for triangle in self.index_data.chunks_exact(3) {
+            let [a, b, c] = [triangle[0] as usize, triangle[1] as usize, triangle[2] as usize];
+            let [va, vb, vc] = [self.vertex_data[a], self.vertex_data[b], self.vertex_data[c]];
+
+            let e1 = na::Vector3::from(vb.position) - na::Vector3::from(va.position);
+            let e2 = na::Vector3::from(vc.position) - na::Vector3::from(va.position);
+            let (du1, dv1) = (vb.texcoord[0] - va.texcoord[0], vb.texcoord[1] - va.texcoord[1]);
+            let (du2, dv2) = (vc.texcoord[0] - va.texcoord[0], vc.texcoord[1] - va.texcoord[1]);
+
+            // A triangle without any area in texture space says nothing about the directions
+            let det = du1 * dv2 - du2 * dv1;
+            if det.abs() <= f32::EPSILON {
+                continue;
+            }
+
+            let tangent = (e1 * dv2 - e2 * dv1) / det;
+            let bitangent = (e2 * du1 - e1 * du2) / det;
+
+            for i in [a, b, c] {
+                tangents[i] += tangent;
+                bitangents[i] += bitangent;
+            }
+        }
+
+        for (vertex, (tangent, bitangent)) in self.vertex_data.iter_mut().zip(tangents.iter().zip(&bitangents)) {
+            let normal = na::Vector3::from(vertex.normal);
+            let tangent = tangent - normal * normal.dot(tangent);
+
+            // Any direction along the surface does when the texture coordinates don't give one
+            let tangent = tangent.try_normalize(f32::EPSILON).unwrap_or_else(|| {
+                let axis = if normal.x.abs() < 0.9 { na::Vector3::x() } else { na::Vector3::y() };
+                normal.cross(&axis).normalize()
+            });
+
+            let w = if normal.cross(&tangent).dot(bitangent) < 0.0 { -1.0 } else { 1.0 };
+
+            vertex.tangent = [tangent.x, tangent.y, tangent.z, w];
         }
     }
 }
@@ -82,6 +353,12 @@ pub struct VertexData {
     pub normal: [f32; 3],
 }
 
+impl Vertex for VertexData {
+    fn position(&self) -> [f32; 3] {
+        self.position
+    }
+}
+
 impl VertexData {
     fn midpoint(a: &VertexData, b: &VertexData) -> VertexData {
         VertexData {
@@ -104,8 +381,18 @@ impl VertexData {
     }
 }
 
-#[repr(C)]
+// The inverse model matrix the shaders need for normals is only computed by to_gpu,
+// so changing the model matrix is cheap until the instance buffer is updated
+#[derive(Copy, Clone, Debug)]
 pub struct InstanceData {
+    pub model_matrix: na::Matrix4<f32>,
+    pub color: [f32; 3],
+    pub metallic: f32,
+    pub roughness: f32,
+}
+
+#[repr(C)]
+pub struct GpuInstanceData {
     pub model_matrix: [[f32; 4]; 4],
     pub inverse_model_matrix: [[f32; 4]; 4],
     pub color: [f32; 3],
@@ -113,6 +400,23 @@ pub struct InstanceData {
     pub roughness: f32,
 }
 
+impl ToGpu for InstanceData {
+    type Gpu = GpuInstanceData;
+
+    fn to_gpu(&self) -> GpuInstanceData {
+        let inverse_model_matrix = inverse_or_identity(&self.model_matrix);
+
+        GpuInstanceData {
+            model_matrix: self.model_matrix.into(),
+            inverse_model_matrix: inverse_model_matrix.into(),
+            color: self.color,
+            metallic: self.metallic,
+            roughness: self.roughness,
+        }
+    }
+}
+
+#[allow(dead_code)]
 impl InstanceData {
     pub fn from_props(
         model_matrix: na::Matrix4<f32>,
@@ -121,8 +425,21 @@ impl InstanceData {
         roughness: f32,
     ) -> InstanceData {
         InstanceData {
-            model_matrix: model_matrix.into(),
-            inverse_model_matrix: model_matrix.try_inverse().unwrap().into(),
+            model_matrix,
+            color,
+            metallic,
+            roughness
+        }
+    }
+
+    pub fn from_transform(
+        transform: &Transform,
+        color: [f32; 3],
+        metallic: f32,
+        roughness: f32,
+    ) -> InstanceData {
+        InstanceData {
+            model_matrix: transform.matrix(),
             color,
             metallic,
             roughness
@@ -130,6 +447,20 @@ impl InstanceData {
     }
 }
 
+impl Transformable for InstanceData {
+    fn apply_transform(&mut self, transform: &Transform) {
+        self.model_matrix = transform.matrix();
+    }
+
+    fn transform(&self) -> Transform {
+        Transform::from_matrix(&self.model_matrix)
+    }
+
+    fn model_matrix(&self) -> na::Matrix4<f32> {
+        self.model_matrix
+    }
+}
+
 pub struct Model<V, I> {
     pub vertex_data: Vec<V>,
     pub index_data: Vec<u32>,
@@ -137,14 +468,59 @@ pub struct Model<V, I> {
     pub handles: Vec<usize>,
     pub instances: Vec<I>,
     pub first_invisible: usize,
+    // Handles made invisible by the last cull, they're made visible again by the next one
+    pub culled: Vec<usize>,
+    // bounding_radius as of the last cull, dropped whenever the vertex buffer is updated
+    pub cached_radius: Option<f32>,
     pub next_handle: usize,
     pub vertex_buffer: Option<EngineBuffer>,
     pub index_buffer: Option<EngineBuffer>,
     pub instance_buffer: Option<EngineBuffer>,
+    pub indirect_buffer: Option<EngineBuffer>,
+    // Set when the number of instances changes, every instance is uploaded again
+    pub instance_buffer_dirty: bool,
+    // Slots whose contents changed since the last upload, e.g. by a visibility swap. Only
+    // these are written unless instance_buffer_dirty is set.
+    pub dirty_instances: Vec<usize>,
+    pub wireframe: bool,
+    // Drawn after the opaque models without writing depth, see sort_back_to_front
+    pub transparent: bool,
+    // Lit models with a matrix here are drawn once with it, see draw_with_push_constant
+    pub push_constant_matrix: Option<na::Matrix4<f32>>,
+}
+
+impl<V, I> Model<V, I> {
+    // A model without any instances or buffers yet
+    pub(super) fn from_mesh(vertex_data: Vec<V>, index_data: Vec<u32>) -> Self {
+        Model {
+            vertex_data,
+            index_data,
+            handle_to_index: HashMap::new(),
+            handles: Vec::new(),
+            instances: Vec::new(),
+            first_invisible: 0,
+            culled: Vec::new(),
+            cached_radius: None,
+            next_handle: 0,
+            vertex_buffer: None,
+            index_buffer: None,
+            instance_buffer: None,
+            indirect_buffer: None,
+            instance_buffer_dirty: true,
+            dirty_instances: Vec::new(),
+            wireframe: false,
+            transparent: false,
+            push_constant_matrix: None,
+        }
+    }
 }
 
 #[allow(dead_code)]
 impl<V, I> Model<V, I> {
+    pub fn contains(&self, handle: usize) -> bool {
+        self.handle_to_index.contains_key(&handle)
+    }
+
     pub fn get(&self, handle: usize) -> Option<&I> {
         if let Some(&index) = self.handle_to_index.get(&handle) {
             self.instances.get(index)
@@ -156,6 +532,7 @@ impl<V, I> Model<V, I> {
 
     pub fn get_mut(&mut self, handle: usize) -> Option<&mut I> {
         if let Some(&index) = self.handle_to_index.get(&handle) {
+            self.dirty_instances.push(index);
             self.instances.get_mut(index)
         } else {
             None
@@ -173,8 +550,9 @@ impl<V, I> Model<V, I> {
         ) {
             self.handles.swap(i1, i2);
             self.instances.swap(i1, i2);
-            self.handle_to_index.insert(i1, h2);
-            self.handle_to_index.insert(i2, h1);
+            self.dirty_instances.extend([i1, i2]);
+            self.handle_to_index.insert(h1, i2);
+            self.handle_to_index.insert(h2, i1);
             Ok(())
         }
         else {
@@ -191,8 +569,9 @@ impl<V, I> Model<V, I> {
         let handle2 = self.handles[index2];
         self.handles.swap(index1, index2);
         self.instances.swap(index1, index2);
-        self.handle_to_index.insert(index1, handle2);
-        self.handle_to_index.insert(index2, handle1);
+        self.dirty_instances.extend([index1, index2]);
+        self.handle_to_index.insert(handle1, index2);
+        self.handle_to_index.insert(handle2, index1);
     }
 
     pub fn is_visible(&self, handle: usize) -> Result<bool, InvalidHandle> {
@@ -220,6 +599,9 @@ impl<V, I> Model<V, I> {
 
     pub fn make_invisible(&mut self, handle: usize) -> Result<(), InvalidHandle> {
         if let Some(&index) = self.handle_to_index.get(&handle) {
+            // Hidden on purpose now, the next cull mustn't bring it back
+            self.culled.retain(|&culled| culled != handle);
+
             if index >= self.first_invisible {
                 return Ok(());
             }
@@ -238,6 +620,7 @@ impl<V, I> Model<V, I> {
 
         let index = self.instances.len();
         self.instances.push(element);
+        self.instance_buffer_dirty = true;
         self.handles.push(handle);
         self.handle_to_index.insert(handle, index);
 
@@ -250,27 +633,62 @@ impl<V, I> Model<V, I> {
         new_handle
     }
 
+    // A visible instance is first moved to the end of the visible range, so the instance
+    // taking its place at the end of the vector keeps the visible range contiguous
     pub fn remove(&mut self, handle: usize) -> Result<I, InvalidHandle> {
         if let Some(&index) = self.handle_to_index.get(&handle) {
+            let mut index = index;
+
             if index < self.first_invisible {
-                self.swap_by_index(index, self.first_invisible - 1);
-                self.first_invisible -= 1;
+                let last_visible = self.first_invisible - 1;
+
+                self.handles.swap(index, last_visible);
+                self.instances.swap(index, last_visible);
+                self.handle_to_index.insert(self.handles[index], index);
+
+                self.first_invisible = last_visible;
+                index = last_visible;
             }
 
-            self.swap_by_index(self.first_invisible, self.instances.len() - 1);
-            self.handles.pop();
+            let element = self.instances.swap_remove(index);
+            self.handles.swap_remove(index);
             self.handle_to_index.remove(&handle);
+            self.culled.retain(|&culled| culled != handle);
 
-            Ok(self.instances.pop().unwrap())
+            if let Some(&moved) = self.handles.get(index) {
+                self.handle_to_index.insert(moved, index);
+            }
+
+            self.instance_buffer_dirty = true;
+
+            Ok(element)
         } else {
             Err(InvalidHandle)
         }
     }
 
+    // Frees the GPU buffers, they're created again by the update_* methods
+    pub unsafe fn cleanup(&mut self, allocator: &mut VkAllocator) {
+        for buffer in [
+            &mut self.vertex_buffer,
+            &mut self.index_buffer,
+            &mut self.instance_buffer,
+            &mut self.indirect_buffer,
+        ] {
+            if let Some(mut buffer) = buffer.take() {
+                buffer.cleanup(allocator);
+            }
+        }
+
+        self.instance_buffer_dirty = true;
+    }
+
     pub fn update_vertex_buffer(
         &mut self,
         allocator: &mut VkAllocator
-    ) -> Result<(), gpu_allocator::AllocationError> {
+    ) -> Result<(), EngineError> {
+        self.cached_radius = None;
+
         if let Some(buffer) = &mut self.vertex_buffer {
             buffer.fill(allocator, &self.vertex_data)?;
             Ok(())
@@ -290,10 +708,37 @@ impl<V, I> Model<V, I> {
         }
     }
 
+    // For meshes that never change after upload. The vertices live in device local memory and
+    // are copied through a staging buffer on the transfer queue, see EngineBuffer::fill_staged.
+    pub fn update_vertex_buffer_device_local(
+        &mut self,
+        device: &ash::Device,
+        allocator: &mut VkAllocator,
+        upload_queues: &UploadQueues,
+    ) -> Result<(), EngineError> {
+        self.cached_radius = None;
+
+        if self.vertex_buffer.is_none() {
+            let bytes = (self.vertex_data.len() * std::mem::size_of::<V>()) as u64;
+
+            self.vertex_buffer = Some(EngineBuffer::new_device_local(
+                allocator,
+                bytes,
+                vk::BufferUsageFlags::VERTEX_BUFFER,
+            )?);
+        }
+
+        if let Some(buffer) = &mut self.vertex_buffer {
+            buffer.fill_staged(device, allocator, upload_queues, &self.vertex_data)?;
+        }
+
+        Ok(())
+    }
+
     pub fn update_index_buffer(
         &mut self,
         allocator: &mut VkAllocator
-    ) -> Result<(), gpu_allocator::AllocationError> {
+    ) -> Result<(), EngineError> {
         if let Some(buffer) = &mut self.index_buffer {
             buffer.fill(allocator, &self.index_data)?;
             Ok(())
@@ -313,26 +758,134 @@ impl<V, I> Model<V, I> {
         }
     }
 
-    pub fn update_instance_buffer(
+    // Writes the parameters draw_indirect reads, has to be called again whenever the
+    // indices or the number of visible instances change
+    pub fn update_indirect_buffer(
         &mut self,
         allocator: &mut VkAllocator
-    ) -> Result<(), gpu_allocator::AllocationError> {
-        if let Some(buffer) = &mut self.instance_buffer {
-            buffer.fill(allocator, &self.instances[0..self.first_invisible])?;
-            Ok(())
+    ) -> Result<(), EngineError> {
+        let commands = [vk::DrawIndexedIndirectCommand {
+            index_count: self.index_data.len() as u32,
+            instance_count: self.first_invisible as u32,
+            first_index: 0,
+            vertex_offset: 0,
+            first_instance: 0,
+        }];
+
+        if let Some(buffer) = &mut self.indirect_buffer {
+            buffer.fill(allocator, &commands)?;
         } else {
-            let bytes = (self.first_invisible * std::mem::size_of::<I>()) as u64;
             let mut buffer = EngineBuffer::new(
                 allocator,
-                bytes,
-                vk::BufferUsageFlags::VERTEX_BUFFER,
+                std::mem::size_of_val(&commands) as u64,
+                vk::BufferUsageFlags::INDIRECT_BUFFER,
                 gpu_allocator::MemoryLocation::CpuToGpu,
             )?;
 
-            buffer.fill(allocator, &self.instances[0..self.first_invisible])?;
-            self.instance_buffer = Some(buffer);
+            buffer.fill(allocator, &commands)?;
+            self.indirect_buffer = Some(buffer);
+        }
 
-            Ok(())
+        Ok(())
+    }
+
+    // Like draw, but the draw parameters are sourced from the indirect buffer, so they can
+    // also be written on the GPU, e.g. by a culling compute shader
+    pub fn draw_indirect(&self, device: &ash::Device, command_buffer: vk::CommandBuffer) {
+        if let (
+            Some(vertex_buffer),
+            Some(index_buffer),
+            Some(instance_buffer),
+            Some(indirect_buffer),
+        ) = (
+            &self.vertex_buffer,
+            &self.index_buffer,
+            &self.instance_buffer,
+            &self.indirect_buffer,
+        ) {
+            unsafe {
+                device.cmd_bind_vertex_buffers(
+                    command_buffer,
+                    0,
+                    &[vertex_buffer.buffer],
+                    &[0]
+                );
+
+                device.cmd_bind_vertex_buffers(
+                    command_buffer,
+                    1,
+                    &[instance_buffer.buffer],
+                    &[0]
+                );
+
+                device.cmd_bind_index_buffer(
+                    command_buffer,
+                    index_buffer.buffer,
+                    0,
+                    vk::IndexType::UINT32,
+                );
+
+                device.cmd_draw_indexed_indirect(
+                    command_buffer,
+                    indirect_buffer.buffer,
+                    0,
+                    1,
+                    std::mem::size_of::<vk::DrawIndexedIndirectCommand>() as u32,
+                );
+            }
+        }
+    }
+
+    // Draws a single copy of the model with the matrix pushed as a constant, for pipelines
+    // built with EnginePipeline::init. Color and material still come from the first instance,
+    // so a model without an instance buffer borrows fallback_instances. The constants are
+    // reset afterwards so later draws use their instance matrices again.
+    pub fn draw_with_push_constant(
+        &self,
+        device: &ash::Device,
+        command_buffer: vk::CommandBuffer,
+        pipeline: &EnginePipeline,
+        fallback_instances: &EngineBuffer,
+        model_matrix: &na::Matrix4<f32>,
+    ) {
+        if let (Some(vertex_buffer), Some(index_buffer)) = (&self.vertex_buffer, &self.index_buffer) {
+            let instance_buffer = self.instance_buffer.as_ref().unwrap_or(fallback_instances);
+
+            pipeline.push_constants(device, command_buffer, &PushConstants::from_matrix(model_matrix));
+
+            unsafe {
+                device.cmd_bind_vertex_buffers(
+                    command_buffer,
+                    0,
+                    &[vertex_buffer.buffer],
+                    &[0]
+                );
+
+                device.cmd_bind_vertex_buffers(
+                    command_buffer,
+                    1,
+                    &[instance_buffer.buffer],
+                    &[0]
+                );
+
+                device.cmd_bind_index_buffer(
+                    command_buffer,
+                    index_buffer.buffer,
+                    0,
+                    vk::IndexType::UINT32,
+                );
+
+                device.cmd_draw_indexed(
+                    command_buffer,
+                    self.index_data.len() as u32,
+                    1,
+                    0,
+                    0,
+                    0,
+                );
+            }
+
+            pipeline.push_constants(device, command_buffer, &PushConstants::instanced());
         }
     }
 
@@ -379,6 +932,176 @@ impl<V, I> Model<V, I> {
     }
 }
 
+impl<V, I: ToGpu> Model<V, I> {
+    // Visibility changes only reorder the instances. One that needs no swap, e.g. hiding the
+    // last visible instance, only changes the instance count draw() reads on the CPU side; one
+    // that does swap rewrites just the two swapped slots. Every instance is only uploaded again
+    // after inserts and removals.
+    pub fn update_instance_buffer(
+        &mut self,
+        allocator: &mut VkAllocator
+    ) -> Result<(), EngineError> {
+        if !self.needs_instance_upload() {
+            return Ok(());
+        }
+
+        match &mut self.instance_buffer {
+            Some(buffer) if !self.instance_buffer_dirty => {
+                self.dirty_instances.sort_unstable();
+                self.dirty_instances.dedup();
+
+                let size = std::mem::size_of::<I::Gpu>() as u64;
+
+                for &index in &self.dirty_instances {
+                    buffer.fill_at(index as u64 * size, &[self.instances[index].to_gpu()])?;
+                }
+            }
+            _ => {
+                let instances: Vec<I::Gpu> = self.instances.iter().map(ToGpu::to_gpu).collect();
+
+                if let Some(buffer) = &mut self.instance_buffer {
+                    buffer.fill(allocator, &instances)?;
+                } else {
+                    let bytes = std::mem::size_of_val(instances.as_slice()) as u64;
+                    let mut buffer = EngineBuffer::new(
+                        allocator,
+                        bytes,
+                        vk::BufferUsageFlags::VERTEX_BUFFER,
+                        gpu_allocator::MemoryLocation::CpuToGpu,
+                    )?;
+
+                    buffer.fill(allocator, &instances)?;
+                    self.instance_buffer = Some(buffer);
+                }
+            }
+        }
+
+        self.instance_buffer_dirty = false;
+        self.dirty_instances.clear();
+
+        Ok(())
+    }
+
+    // Without visible instances nothing is drawn, and a model without any instances would need
+    // a zero sized buffer, which can't be allocated. The model stays dirty, so the upload happens
+    // once an instance is made visible.
+    fn needs_instance_upload(&self) -> bool {
+        let dirty = self.instance_buffer_dirty || !self.dirty_instances.is_empty();
+
+        dirty && self.first_invisible > 0
+    }
+}
+
+#[allow(dead_code)]
+impl<V, I: Transformable> Model<V, I> {
+    // Textured instances build their inverses from the TRS parts, avoiding a general 4x4 inversion
+    pub fn update_transforms(&mut self, transforms: &[(usize, Transform)]) -> Result<(), InvalidHandle> {
+        for (handle, transform) in transforms {
+            match self.handle_to_index.get(handle) {
+                Some(&index) => {
+                    self.instances[index].apply_transform(transform);
+                    self.dirty_instances.push(index);
+                }
+                None => return Err(InvalidHandle),
+            }
+        }
+
+        Ok(())
+    }
+
+    // Orders the visible instances from farthest to nearest, so blended instances are drawn over
+    // the ones behind them. Returns the distance of the farthest, None if none are visible.
+    pub fn sort_back_to_front(&mut self, eye: &na::Vector3<f32>) -> Option<f32> {
+        let distances: Vec<f32> = self.instances[..self.first_invisible]
+            .iter()
+            .map(|instance| (instance.model_matrix().fixed_slice::<3, 1>(0, 3) - eye).norm())
+            .collect();
+
+        let mut order: Vec<usize> = (0..distances.len()).collect();
+        order.sort_by(|&a, &b| distances[b].partial_cmp(&distances[a]).unwrap_or(std::cmp::Ordering::Equal));
+
+        // Applied with swaps so the handles follow their instances. at[i] is the original index
+        // of the instance now at i, position_of the inverse.
+        let mut at: Vec<usize> = (0..order.len()).collect();
+        let mut position_of = at.clone();
+
+        for (i, &original) in order.iter().enumerate() {
+            let current = position_of[original];
+
+            if current != i {
+                self.swap_by_index(i, current);
+
+                let displaced = at[i];
+                at[i] = original;
+                at[current] = displaced;
+                position_of[original] = i;
+                position_of[displaced] = current;
+            }
+        }
+
+        order.first().map(|&farthest| distances[farthest])
+    }
+}
+
+#[allow(dead_code)]
+impl<V: Vertex, I: Transformable> Model<V, I> {
+    // Radius of a sphere around the model origin enclosing every vertex
+    pub fn bounding_radius(&self) -> f32 {
+        self.vertex_data
+            .iter()
+            .map(|vertex| na::Vector3::from(vertex.position()).norm())
+            .fold(0.0, f32::max)
+    }
+
+    // Moves the visible instances whose bounding spheres are entirely outside the frustum
+    // behind first_invisible and brings back the ones culled earlier that are inside again.
+    // Instances that stay on the same side aren't touched, so they aren't uploaded again.
+    pub fn cull(&mut self, frustum: &Frustum) {
+        let radius = match self.cached_radius {
+            Some(radius) => radius,
+            None => {
+                let radius = self.bounding_radius();
+                self.cached_radius = Some(radius);
+                radius
+            }
+        };
+
+        // Straight from the matrix, the largest column norm bounds the scale along any axis
+        let outside = |instance: &I| {
+            let matrix = instance.model_matrix();
+            let center = matrix.fixed_slice::<3, 1>(0, 3).into_owned();
+            let scale = (0..3)
+                .map(|i| matrix.fixed_slice::<3, 1>(0, i).norm())
+                .fold(0.0, f32::max);
+
+            !frustum.contains_sphere(&center, radius * scale)
+        };
+
+        let leaving: Vec<usize> = self.handles[..self.first_invisible]
+            .iter()
+            .zip(&self.instances)
+            .filter(|(_, instance)| outside(instance))
+            .map(|(&handle, _)| handle)
+            .collect();
+
+        let (returning, still_culled): (Vec<usize>, Vec<usize>) = self.culled
+            .iter()
+            .copied()
+            .partition(|handle| !outside(&self.instances[self.handle_to_index[handle]]));
+
+        self.culled = still_culled;
+
+        for handle in returning {
+            self.make_visible(handle).ok();
+        }
+
+        for handle in leaving {
+            self.make_invisible(handle).ok();
+            self.culled.push(handle);
+        }
+    }
+}
+
 #[allow(dead_code)]
 impl Model<VertexData, InstanceData> {
     pub fn refine(&mut self) {
@@ -451,10 +1174,162 @@ impl Model<VertexData, InstanceData> {
             handles: Vec::new(),
             instances: Vec::new(),
             first_invisible: 0,
+            culled: Vec::new(),
+            cached_radius: None,
             next_handle: 0,
             vertex_buffer: None,
             index_buffer: None,
             instance_buffer: None,
+            indirect_buffer: None,
+            instance_buffer_dirty: true,
+            dirty_instances: Vec::new(),
+            wireframe: false,
+            transparent: false,
+            push_constant_matrix: None,
+        }
+    }
+
+    // A grid of subdivisions x subdivisions quads on the XZ plane, spanning -1..1 and facing up (-y)
+    pub fn plane(subdivisions: u32) -> Self {
+        let cells = subdivisions.max(1);
+        let step = 2.0 / cells as f32;
+
+        let mut vertex_data = Vec::with_capacity(((cells + 1) * (cells + 1)) as usize);
+
+        for row in 0..=cells {
+            for column in 0..=cells {
+                vertex_data.push(VertexData {
+                    position: [-1.0 + column as f32 * step, 0.0, -1.0 + row as f32 * step],
+                    normal: [0.0, -1.0, 0.0],
+                });
+            }
+        }
+
+        let mut index_data = Vec::with_capacity((cells * cells * 6) as usize);
+
+        for row in 0..cells {
+            for column in 0..cells {
+                let near_left = row * (cells + 1) + column;
+                let near_right = near_left + 1;
+                let far_left = near_left + cells + 1;
+                let far_right = far_left + 1;
+
+                index_data.extend_from_slice(&[
+                    near_left, near_right, far_left,
+                    near_right, far_right, far_left,
+                ]);
+            }
+        }
+
+        Self::from_mesh(vertex_data, index_data)
+    }
+
+    // Radius 1 around the y axis, from y = -1 (top) to y = 1 (bottom)
+    pub fn cylinder(segments: u32) -> Self {
+        let segments = segments.max(3);
+
+        let mut vertex_data = vec![];
+        let mut index_data = vec![];
+
+        for i in 0..segments {
+            let (x, z) = Self::rim_point(i, segments);
+
+            vertex_data.push(VertexData {
+                position: [x, -1.0, z],
+                normal: [x, 0.0, z],
+            });
+            vertex_data.push(VertexData {
+                position: [x, 1.0, z],
+                normal: [x, 0.0, z],
+            });
+        }
+
+        for i in 0..segments {
+            let top = 2 * i;
+            let bottom = top + 1;
+            let next_top = 2 * ((i + 1) % segments);
+            let next_bottom = next_top + 1;
+
+            index_data.extend_from_slice(&[top, bottom, next_top, bottom, next_bottom, next_top]);
+        }
+
+        Self::push_cap(&mut vertex_data, &mut index_data, -1.0, segments);
+        Self::push_cap(&mut vertex_data, &mut index_data, 1.0, segments);
+
+        Self::from_mesh(vertex_data, index_data)
+    }
+
+    // Apex at y = -1, base of radius 1 at y = 1
+    pub fn cone(segments: u32) -> Self {
+        let segments = segments.max(3);
+
+        let mut vertex_data = vec![];
+        let mut index_data = vec![];
+
+        // Height 2 over radius 1, so the side normals lean up by atan(1 / 2)
+        let side_normal = |x: f32, z: f32| VertexData::normalize([2.0 * x, -1.0, 2.0 * z]);
+
+        for i in 0..segments {
+            let (x, z) = Self::rim_point(i, segments);
+
+            vertex_data.push(VertexData {
+                position: [x, 1.0, z],
+                normal: side_normal(x, z),
+            });
+        }
+
+        // The apex is repeated per segment, each copy taking the normal halfway between its rim points
+        for i in 0..segments {
+            let (x, z) = Self::rim_point(2 * i + 1, 2 * segments);
+
+            vertex_data.push(VertexData {
+                position: [0.0, -1.0, 0.0],
+                normal: side_normal(x, z),
+            });
+
+            index_data.extend_from_slice(&[segments + i, i, (i + 1) % segments]);
+        }
+
+        Self::push_cap(&mut vertex_data, &mut index_data, 1.0, segments);
+
+        Self::from_mesh(vertex_data, index_data)
+    }
+
+    fn rim_point(i: u32, segments: u32) -> (f32, f32) {
+        let angle = 2.0 * std::f32::consts::PI * i as f32 / segments as f32;
+
+        (angle.cos(), angle.sin())
+    }
+
+    // A flat disc of radius 1 at height y as a fan around its center, facing away from y = 0.
+    // Its vertices are separate from the side ones since they need a different normal.
+    fn push_cap(vertex_data: &mut Vec<VertexData>, index_data: &mut Vec<u32>, y: f32, segments: u32) {
+        let normal = [0.0, y.signum(), 0.0];
+        let center = vertex_data.len() as u32;
+
+        vertex_data.push(VertexData {
+            position: [0.0, y, 0.0],
+            normal,
+        });
+
+        for i in 0..segments {
+            let (x, z) = Self::rim_point(i, segments);
+
+            vertex_data.push(VertexData {
+                position: [x, y, z],
+                normal,
+            });
+        }
+
+        for i in 0..segments {
+            let current = center + 1 + i;
+            let next = center + 1 + (i + 1) % segments;
+
+            if y < 0.0 {
+                index_data.extend_from_slice(&[center, current, next]);
+            } else {
+                index_data.extend_from_slice(&[center, next, current]);
+            }
         }
     }
 
@@ -549,14 +1424,52 @@ impl Model<VertexData, InstanceData> {
             handles: Vec::new(),
             instances: Vec::new(),
             first_invisible: 0,
+            culled: Vec::new(),
+            cached_radius: None,
             next_handle: 0,
             vertex_buffer: None,
             index_buffer: None,
             instance_buffer: None,
+            indirect_buffer: None,
+            instance_buffer_dirty: true,
+            dirty_instances: Vec::new(),
+            wireframe: false,
+            transparent: false,
+            push_constant_matrix: None,
         }
     }
 
+    // Latitude/longitude sphere of radius 1, stacks run from the top pole (y = -1) to the bottom one
+    pub fn uv_sphere(stacks: u32, slices: u32) -> Self {
+        let (stacks, slices) = (stacks.max(2), slices.max(3));
+
+        let vertex_data = uv_sphere_points(stacks, slices)
+            .into_iter()
+            .map(|(position, _)| VertexData { position, normal: position })
+            .collect();
+
+        Self::from_mesh(vertex_data, uv_sphere_indices(stacks, slices))
+    }
+
     pub fn sphere(refinements: u32) -> Self {
+        Self::sphere_with_limit(refinements, MAX_SPHERE_REFINEMENTS)
+    }
+
+    // Every refinement quadruples the triangle count, so large values quickly exhaust memory
+    pub fn sphere_with_limit(refinements: u32, max_refinements: u32) -> Self {
+        let refinements = if refinements > max_refinements {
+            println!(
+                "sphere with {} refinements would need {} vertices, clamping to {} refinements",
+                refinements,
+                Self::sphere_vertex_count(refinements),
+                max_refinements
+            );
+
+            max_refinements
+        } else {
+            refinements
+        };
+
         let mut model = Model::icosahedron();
 
         for _ in 0..refinements {
@@ -569,4 +1482,261 @@ impl Model<VertexData, InstanceData> {
 
         model
     }
-}
\ No newline at end of file
+
+    pub fn sphere_vertex_count(refinements: u32) -> u64 {
+        4u64.saturating_pow(refinements).saturating_mul(10).saturating_add(2)
+    }
+
+    pub fn sphere_index_count(refinements: u32) -> u64 {
+        4u64.saturating_pow(refinements).saturating_mul(60)
+    }
+}
+// Positions and texture coordinates of a uv sphere. Every stack has slices + 1 points since
+// the seam needs both u = 0 and u = 1; the poles are repeated the same way.
+fn uv_sphere_points(stacks: u32, slices: u32) -> Vec<([f32; 3], [f32; 2])> {
+    let mut points = Vec::with_capacity(((stacks + 1) * (slices + 1)) as usize);
+
+    for stack in 0..=stacks {
+        let v = stack as f32 / stacks as f32;
+        let polar = std::f32::consts::PI * v;
+
+        for slice in 0..=slices {
+            let u = slice as f32 / slices as f32;
+            let azimuth = 2.0 * std::f32::consts::PI * u;
+
+            points.push((
+                [polar.sin() * azimuth.cos(), -polar.cos(), polar.sin() * azimuth.sin()],
+                [u, v],
+            ));
+        }
+    }
+
+    points
+}
+
+fn uv_sphere_indices(stacks: u32, slices: u32) -> Vec<u32> {
+    let mut indices = vec![];
+
+    for stack in 0..stacks {
+        for slice in 0..slices {
+            let top = stack * (slices + 1) + slice;
+            let bottom = top + slices + 1;
+
+            // The triangle touching a pole along its whole edge would be degenerate
+            if stack != 0 {
+                indices.extend_from_slice(&[top, bottom, top + 1]);
+            }
+
+            if stack != stacks - 1 {
+                indices.extend_from_slice(&[bottom, bottom + 1, top + 1]);
+            }
+        }
+    }
+
+    indices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The id ends up in the color, so every instance can be told apart after being moved around
+    fn instance(id: usize) -> InstanceData {
+        InstanceData::from_props(
+            na::Matrix4::new_translation(&na::Vector3::new(id as f32, 0.0, 0.0)),
+            [id as f32, 0.0, 0.0],
+            0.0,
+            1.0,
+        )
+    }
+
+    fn id_of(model: &Model<[f32; 3], InstanceData>, handle: usize) -> Option<usize> {
+        model.get(handle).map(|instance| instance.color[0] as usize)
+    }
+
+    // Every handle has to point at its own slot, and every slot back at its handle
+    fn assert_consistent(model: &Model<[f32; 3], InstanceData>) {
+        assert_eq!(model.handles.len(), model.instances.len());
+        assert_eq!(model.handle_to_index.len(), model.handles.len());
+        assert!(model.first_invisible <= model.instances.len());
+
+        for (&handle, &index) in &model.handle_to_index {
+            assert_eq!(model.handles[index], handle);
+        }
+    }
+
+    // Handles 0..visible are visible, the rest invisible, and each instance's id is its handle
+    fn model_with(visible: usize, invisible: usize) -> Model<[f32; 3], InstanceData> {
+        let mut model = Model::cube();
+
+        for id in 0..visible {
+            assert_eq!(model.insert_visibly(instance(id)), id);
+        }
+
+        for id in visible..visible + invisible {
+            assert_eq!(model.insert(instance(id)), id);
+        }
+
+        model
+    }
+
+    #[test]
+    fn degenerate_matrices_get_the_identity_as_inverse() {
+        let flat = na::Matrix4::new_nonuniform_scaling(&na::Vector3::new(1.0, 0.0, 1.0));
+        let identity: [[f32; 4]; 4] = na::Matrix4::identity().into();
+
+        assert_eq!(TexturedInstanceData::from_matrix(flat).inverse_model_matrix, identity);
+        assert_eq!(InstanceData::from_props(flat, [1.0; 3], 0.0, 1.0).to_gpu().inverse_model_matrix, identity);
+    }
+
+    #[test]
+    fn remove_visible_keeps_survivors() {
+        let mut model = model_with(5, 2);
+
+        let removed = model.remove(2).unwrap();
+        assert_eq!(removed.color[0] as usize, 2);
+
+        assert_consistent(&model);
+        assert_eq!(model.first_invisible, 4);
+        assert!(model.get(2).is_none());
+
+        for handle in [0, 1, 3, 4] {
+            assert_eq!(id_of(&model, handle), Some(handle));
+            assert!(model.is_visible(handle).unwrap());
+        }
+
+        for handle in [5, 6] {
+            assert_eq!(id_of(&model, handle), Some(handle));
+            assert!(!model.is_visible(handle).unwrap());
+        }
+    }
+
+    #[test]
+    fn remove_invisible_keeps_survivors() {
+        let mut model = model_with(3, 4);
+
+        let removed = model.remove(4).unwrap();
+        assert_eq!(removed.color[0] as usize, 4);
+
+        assert_consistent(&model);
+        assert_eq!(model.first_invisible, 3);
+        assert!(model.get(4).is_none());
+
+        for handle in [0, 1, 2] {
+            assert_eq!(id_of(&model, handle), Some(handle));
+            assert!(model.is_visible(handle).unwrap());
+        }
+
+        for handle in [3, 5, 6] {
+            assert_eq!(id_of(&model, handle), Some(handle));
+            assert!(!model.is_visible(handle).unwrap());
+        }
+    }
+
+    #[test]
+    fn remove_until_empty() {
+        let mut model = model_with(2, 2);
+
+        for handle in [1, 3, 0, 2] {
+            model.remove(handle).unwrap();
+            assert_consistent(&model);
+            assert!(model.remove(handle).is_err());
+        }
+
+        assert!(model.instances.is_empty());
+        assert_eq!(model.first_invisible, 0);
+    }
+
+    #[test]
+    fn swap_by_handle_keeps_instances_with_their_handles() {
+        let mut model = model_with(3, 2);
+
+        // Across the visible range and within it
+        model.swap_by_handle(1, 4).unwrap();
+        model.swap_by_handle(0, 2).unwrap();
+
+        assert_consistent(&model);
+
+        for handle in 0..5 {
+            assert_eq!(id_of(&model, handle), Some(handle));
+        }
+
+        model.swap_by_handle(3, 3).unwrap();
+        assert_eq!(id_of(&model, 3), Some(3));
+
+        assert!(model.swap_by_handle(1, 7).is_err());
+        assert_consistent(&model);
+    }
+
+    #[test]
+    fn no_upload_without_visible_instances() {
+        let empty: Model<[f32; 3], InstanceData> = Model::cube();
+        assert!(!empty.needs_instance_upload());
+
+        let mut model = model_with(0, 3);
+        assert!(!model.needs_instance_upload());
+        assert!(model.instance_buffer.is_none());
+
+        model.make_visible(1).unwrap();
+        assert!(model.needs_instance_upload());
+    }
+
+    #[test]
+    fn visibility_changes_only_dirty_swapped_slots() {
+        let mut model = model_with(4, 2);
+
+        // As if the instance buffer had just been uploaded
+        model.instance_buffer_dirty = false;
+        model.dirty_instances.clear();
+
+        // The last visible instance and the first invisible one stay where they are
+        model.make_invisible(3).unwrap();
+        model.make_visible(3).unwrap();
+
+        assert_eq!(model.first_invisible, 4);
+        assert!(!model.needs_instance_upload());
+
+        // Hiding the first instance swaps it with the last visible one
+        model.make_invisible(0).unwrap();
+
+        assert!(!model.instance_buffer_dirty);
+        model.dirty_instances.sort_unstable();
+        assert_eq!(model.dirty_instances, vec![0, 3]);
+        assert_consistent(&model);
+    }
+
+    #[test]
+    fn cull_only_moves_instances_that_changed_sides() {
+        // Clip space is the frustum: -1..1 in x and y, 0..1 in z. With the cube's radius of
+        // sqrt(3), the instances at x = 0, 1 and 2 are inside, the ones at 3, 4 and 5 outside.
+        let frustum = Frustum::from_matrix(&na::Matrix4::identity());
+        let mut model = model_with(6, 0);
+
+        model.cull(&frustum);
+
+        assert_eq!(model.cached_radius, Some(3.0f32.sqrt()));
+        assert_eq!(model.first_invisible, 3);
+        assert_consistent(&model);
+
+        for handle in 0..6 {
+            assert_eq!(model.is_visible(handle).unwrap(), handle < 3);
+            assert_eq!(id_of(&model, handle), Some(handle));
+        }
+
+        model.instance_buffer_dirty = false;
+        model.dirty_instances.clear();
+
+        model.cull(&frustum);
+
+        assert!(model.dirty_instances.is_empty());
+        assert_eq!(model.first_invisible, 3);
+
+        // Moved into view, the next cull brings it back
+        model.update_transforms(&[(4, Transform::default())]).unwrap();
+        model.cull(&frustum);
+
+        assert!(model.is_visible(4).unwrap());
+        assert_eq!(model.first_invisible, 4);
+        assert_consistent(&model);
+    }
+}
@@ -1,18 +1,48 @@
 use std::ffi::CString;
 use ash::vk;
 use super::swapchain::EngineSwapchain;
+use crate::engine::error::EngineError;
+use nalgebra as na;
 
 pub struct EnginePipeline {
     pub pipeline: vk::Pipeline,
     pub layout: vk::PipelineLayout,
-    pub descriptor_set_layouts: Vec<vk::DescriptorSetLayout>
+    pub descriptor_set_layouts: Vec<vk::DescriptorSetLayout>,
+    pub immutable_sampler: Option<vk::Sampler>,
+    pub push_constant_ranges: Vec<vk::PushConstantRange>,
+}
+
+// The push constants of the pipeline built by EnginePipeline::init. With use_push_constant
+// set the vertex shader takes model_matrix instead of the instance's matrix.
+#[repr(C)]
+pub struct PushConstants {
+    pub model_matrix: [[f32; 4]; 4],
+    pub use_push_constant: u32,
+}
+
+impl PushConstants {
+    pub fn instanced() -> PushConstants {
+        PushConstants {
+            model_matrix: na::Matrix4::identity().into(),
+            use_push_constant: 0,
+        }
+    }
+
+    pub fn from_matrix(model_matrix: &na::Matrix4<f32>) -> PushConstants {
+        PushConstants {
+            model_matrix: (*model_matrix).into(),
+            use_push_constant: 1,
+        }
+    }
 }
 
 impl EnginePipeline {
     pub fn init(
         device: &ash::Device,
         swapchain: &EngineSwapchain,
-        render_pass: vk::RenderPass
+        render_pass: vk::RenderPass,
+        polygon_mode: vk::PolygonMode,
+        pipeline_cache: vk::PipelineCache,
     ) -> Result<EnginePipeline, vk::Result> {
         let vertex_shader_create_info = vk::ShaderModuleCreateInfo::builder()
             .code(
@@ -80,8 +110,18 @@ impl EnginePipeline {
 
         let desc_layouts = vec![descriptor_set_layout_cam, descriptor_set_layout_light];
 
+        // See PushConstants and Model::draw_with_push_constant
+        let push_constant_ranges = vec![
+            vk::PushConstantRange {
+                stage_flags: vk::ShaderStageFlags::VERTEX,
+                offset: 0,
+                size: std::mem::size_of::<PushConstants>() as u32,
+            }
+        ];
+
         let pipeline_layout_info = vk::PipelineLayoutCreateInfo::builder()
-            .set_layouts(&desc_layouts);
+            .set_layouts(&desc_layouts)
+            .push_constant_ranges(&push_constant_ranges);
 
         let vertex_attrib_descs = [
             vk::VertexInputAttributeDescription {
@@ -184,38 +224,24 @@ impl EnginePipeline {
         let input_assembly_info = vk::PipelineInputAssemblyStateCreateInfo::builder()
             .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
 
-        let viewports = [
-            vk::Viewport {
-                x: 0.0,
-                y: 0.0,
-                width: swapchain.extent.width as f32,
-                height: swapchain.extent.height as f32,
-                min_depth: 0.0,
-                max_depth: 1.0,
-            }
-        ];
-        let scissors = [
-            vk::Rect2D {
-                offset: vk::Offset2D {
-                    x: 0,
-                    y: 0,
-                },
-                extent: swapchain.extent
-            }
-        ];
-
+        // Viewport and scissor are set while recording, so resizing doesn't invalidate the pipeline
         let viewport_info = vk::PipelineViewportStateCreateInfo::builder()
-            .viewports(&viewports)
-            .scissors(&scissors);
+            .viewport_count(1)
+            .scissor_count(1);
+
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+
+        let dynamic_state_info = vk::PipelineDynamicStateCreateInfo::builder()
+            .dynamic_states(&dynamic_states);
 
         let rasterizer_info = vk::PipelineRasterizationStateCreateInfo::builder()
             .line_width(1.0)
             .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
             .cull_mode(vk::CullModeFlags::BACK)
-            .polygon_mode(vk::PolygonMode::FILL);
+            .polygon_mode(polygon_mode);
 
         let multisampler_info = vk::PipelineMultisampleStateCreateInfo::builder()
-            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+            .rasterization_samples(swapchain.config.samples);
 
         let colorblend_attachments = [
             vk::PipelineColorBlendAttachmentState::builder()
@@ -223,7 +249,7 @@ impl EnginePipeline {
                 .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
                 .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
                 .color_blend_op(vk::BlendOp::ADD)
-                .src_alpha_blend_factor(vk::BlendFactor::SRC_ALPHA)
+                .src_alpha_blend_factor(vk::BlendFactor::ONE)
                 .dst_alpha_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
                 .alpha_blend_op(vk::BlendOp::ADD)
                 .color_write_mask(
@@ -245,13 +271,14 @@ impl EnginePipeline {
         let depth_stencil_info = vk::PipelineDepthStencilStateCreateInfo::builder()
             .depth_test_enable(true)
             .depth_write_enable(true)
-            .depth_compare_op(vk::CompareOp::LESS_OR_EQUAL);
+            .depth_compare_op(depth_compare_op(swapchain));
 
         let pipeline_info = vk::GraphicsPipelineCreateInfo::builder()
             .stages(&shader_stages)
             .vertex_input_state(&vertex_input_info)
             .input_assembly_state(&input_assembly_info)
             .viewport_state(&viewport_info)
+            .dynamic_state(&dynamic_state_info)
             .rasterization_state(&rasterizer_info)
             .multisample_state(&multisampler_info)
             .depth_stencil_state(&depth_stencil_info)
@@ -262,7 +289,7 @@ impl EnginePipeline {
 
         let graphics_pipeline = unsafe {
             device.create_graphics_pipelines(
-                vk::PipelineCache::null(),
+                pipeline_cache,
                 &[pipeline_info.build()],
                 None
             ).expect("Failed to create graphics pipeline")
@@ -276,29 +303,100 @@ impl EnginePipeline {
         Ok(EnginePipeline {
             pipeline: graphics_pipeline,
             layout: pipeline_layout,
-            descriptor_set_layouts: desc_layouts
+            descriptor_set_layouts: desc_layouts,
+            immutable_sampler: None,
+            push_constant_ranges,
         })
     }
 
+    // max_textures is the length of the texture array instances pick from with their texture_index.
+    // Transparent models are drawn without depth_write, so they don't hide what's drawn after them.
+    #[allow(clippy::too_many_arguments)]
     pub fn init_textured(
         device: &ash::Device,
         swapchain: &EngineSwapchain,
-        render_pass: vk::RenderPass
+        render_pass: vk::RenderPass,
+        polygon_mode: vk::PolygonMode,
+        depth_write: bool,
+        immutable_sampler_info: Option<&vk::SamplerCreateInfo>,
+        conservative_rasterization: bool,
+        max_textures: u32,
+        pipeline_cache: vk::PipelineCache,
+    ) -> Result<EnginePipeline, vk::Result> {
+        Self::init_textured_with_code(
+            device,
+            swapchain,
+            render_pass,
+            polygon_mode,
+            depth_write,
+            immutable_sampler_info,
+            conservative_rasterization,
+            max_textures,
+            pipeline_cache,
+            (
+                vk_shader_macros::include_glsl!("./shaders/shader_textured.vert"),
+                vk_shader_macros::include_glsl!("./shaders/shader_textured.frag"),
+            ),
+        )
+    }
+
+    // Same as init_textured, but the shaders are read as SPIR-V at runtime so they can be
+    // recompiled (e.g. with glslc) and reloaded without rebuilding the crate
+    #[allow(clippy::too_many_arguments)]
+    pub fn init_from_spirv<P: AsRef<std::path::Path>>(
+        device: &ash::Device,
+        vert_spv: P,
+        frag_spv: P,
+        swapchain: &EngineSwapchain,
+        render_pass: vk::RenderPass,
+        polygon_mode: vk::PolygonMode,
+        depth_write: bool,
+        conservative_rasterization: bool,
+        max_textures: u32,
+        pipeline_cache: vk::PipelineCache,
+    ) -> Result<EnginePipeline, EngineError> {
+        let vertex_code = ash::util::read_spv(&mut std::fs::File::open(vert_spv)?)?;
+        let fragment_code = ash::util::read_spv(&mut std::fs::File::open(frag_spv)?)?;
+
+        let pipeline = Self::init_textured_with_code(
+            device,
+            swapchain,
+            render_pass,
+            polygon_mode,
+            depth_write,
+            None,
+            conservative_rasterization,
+            max_textures,
+            pipeline_cache,
+            (&vertex_code, &fragment_code),
+        )?;
+
+        Ok(pipeline)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn init_textured_with_code(
+        device: &ash::Device,
+        swapchain: &EngineSwapchain,
+        render_pass: vk::RenderPass,
+        polygon_mode: vk::PolygonMode,
+        depth_write: bool,
+        immutable_sampler_info: Option<&vk::SamplerCreateInfo>,
+        conservative_rasterization: bool,
+        max_textures: u32,
+        pipeline_cache: vk::PipelineCache,
+        (vertex_code, fragment_code): (&[u32], &[u32]),
     ) -> Result<EnginePipeline, vk::Result> {
         // Loading Shaders
 
         let vertex_shader_create_info = vk::ShaderModuleCreateInfo::builder()
-            .code(
-                vk_shader_macros::include_glsl!("./shaders/shader_textured.vert")
-            );
+            .code(vertex_code);
         let vertex_shader_module = unsafe {
             device.create_shader_module(&vertex_shader_create_info, None)?
         };
 
         let fragment_shader_create_info = vk::ShaderModuleCreateInfo::builder()
-            .code(
-                vk_shader_macros::include_glsl!("./shaders/shader_textured.frag")
-            );
+            .code(fragment_code);
         let fragment_shader_module = unsafe {
             device.create_shader_module(&fragment_shader_create_info, None)?
         };
@@ -308,10 +406,24 @@ impl EnginePipeline {
             .stage(vk::ShaderStageFlags::VERTEX)
             .module(vertex_shader_module)
             .name(&entry_point);
+        // constant_id 0 sizes the texture array in the fragment shader
+        let specialization_entries = [
+            vk::SpecializationMapEntry {
+                constant_id: 0,
+                offset: 0,
+                size: 4,
+            }
+        ];
+        let specialization_data = max_textures.to_ne_bytes();
+        let specialization_info = vk::SpecializationInfo::builder()
+            .map_entries(&specialization_entries)
+            .data(&specialization_data);
+
         let fragment_shader_stage = vk::PipelineShaderStageCreateInfo::builder()
             .stage(vk::ShaderStageFlags::FRAGMENT)
             .module(fragment_shader_module)
-            .name(&entry_point);
+            .name(&entry_point)
+            .specialization_info(&specialization_info);
         let shader_stages = vec![
             vertex_shader_stage.build(),
             fragment_shader_stage.build()
@@ -337,13 +449,40 @@ impl EnginePipeline {
 
         // Texture Descriptor Set
 
+        let immutable_sampler = match immutable_sampler_info {
+            Some(sampler_info) => Some(unsafe {
+                device.create_sampler(sampler_info, None)
+            }?),
+            None => None,
+        };
+
+        // Every element of the array shares the sampler
+        let immutable_samplers: Vec<vk::Sampler> = immutable_sampler
+            .map(|sampler| vec![sampler; max_textures as usize])
+            .unwrap_or_default();
+
+        let mut descriptor_set_layout_binding_img = vk::DescriptorSetLayoutBinding::builder()
+            .binding(0)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .descriptor_count(max_textures)
+            .stage_flags(vk::ShaderStageFlags::FRAGMENT);
+
+        // The sampler is baked into the layout, so only the image view has to be written per texture
+        if !immutable_samplers.is_empty() {
+            descriptor_set_layout_binding_img = descriptor_set_layout_binding_img
+                .immutable_samplers(&immutable_samplers);
+        }
+
+        // The normal map, always sampled with its own sampler
+        let descriptor_set_layout_binding_normal = vk::DescriptorSetLayoutBinding::builder()
+            .binding(1)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::FRAGMENT);
+
         let descriptor_set_layout_binding_descs_img = [
-            vk::DescriptorSetLayoutBinding::builder()
-                .binding(0)
-                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-                .descriptor_count(1)
-                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
-                .build()
+            descriptor_set_layout_binding_img.build(),
+            descriptor_set_layout_binding_normal.build()
         ];
 
         let descriptor_set_layout_info_img = vk::DescriptorSetLayoutCreateInfo::builder()
@@ -419,17 +558,35 @@ impl EnginePipeline {
                 offset: 112,
                 format: vk::Format::R32G32B32A32_SFLOAT,
             },
+            vk::VertexInputAttributeDescription {
+                binding: 0,
+                location: 10,
+                offset: 20,
+                format: vk::Format::R32G32B32_SFLOAT,
+            },
+            vk::VertexInputAttributeDescription {
+                binding: 0,
+                location: 11,
+                offset: 32,
+                format: vk::Format::R32G32B32A32_SFLOAT,
+            },
+            vk::VertexInputAttributeDescription {
+                binding: 1,
+                location: 12,
+                offset: 128,
+                format: vk::Format::R32_UINT,
+            },
         ];
 
         let vertex_binding_descs = [
             vk::VertexInputBindingDescription {
                 binding: 0,
-                stride: 20,
+                stride: 48,
                 input_rate: vk::VertexInputRate::VERTEX,
             },
             vk::VertexInputBindingDescription {
                 binding: 1,
-                stride: 128,
+                stride: 132,
                 input_rate: vk::VertexInputRate::INSTANCE,
             },
         ];
@@ -441,38 +598,33 @@ impl EnginePipeline {
         let input_assembly_info = vk::PipelineInputAssemblyStateCreateInfo::builder()
             .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
 
-        let viewports = [
-            vk::Viewport {
-                x: 0.0,
-                y: 0.0,
-                width: swapchain.extent.width as f32,
-                height: swapchain.extent.height as f32,
-                min_depth: 0.0,
-                max_depth: 1.0,
-            }
-        ];
-        let scissors = [
-            vk::Rect2D {
-                offset: vk::Offset2D {
-                    x: 0,
-                    y: 0,
-                },
-                extent: swapchain.extent
-            }
-        ];
-
+        // Viewport and scissor are set while recording, so resizing doesn't invalidate the pipeline
         let viewport_info = vk::PipelineViewportStateCreateInfo::builder()
-            .viewports(&viewports)
-            .scissors(&scissors);
+            .viewport_count(1)
+            .scissor_count(1);
 
-        let rasterizer_info = vk::PipelineRasterizationStateCreateInfo::builder()
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+
+        let dynamic_state_info = vk::PipelineDynamicStateCreateInfo::builder()
+            .dynamic_states(&dynamic_states);
+
+        // Overestimation makes every pixel touched by a triangle covered, so thin triangles aren't missed
+        let mut conservative_info = vk::PipelineRasterizationConservativeStateCreateInfoEXT::builder()
+            .conservative_rasterization_mode(vk::ConservativeRasterizationModeEXT::OVERESTIMATE)
+            .extra_primitive_overestimation_size(0.0);
+
+        let mut rasterizer_info = vk::PipelineRasterizationStateCreateInfo::builder()
             .line_width(1.0)
             .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
             .cull_mode(vk::CullModeFlags::BACK)
-            .polygon_mode(vk::PolygonMode::FILL);
+            .polygon_mode(polygon_mode);
+
+        if conservative_rasterization {
+            rasterizer_info = rasterizer_info.push_next(&mut conservative_info);
+        }
 
         let multisampler_info = vk::PipelineMultisampleStateCreateInfo::builder()
-            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+            .rasterization_samples(swapchain.config.samples);
 
         let colorblend_attachments = [
             vk::PipelineColorBlendAttachmentState::builder()
@@ -480,7 +632,7 @@ impl EnginePipeline {
                 .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
                 .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
                 .color_blend_op(vk::BlendOp::ADD)
-                .src_alpha_blend_factor(vk::BlendFactor::SRC_ALPHA)
+                .src_alpha_blend_factor(vk::BlendFactor::ONE)
                 .dst_alpha_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
                 .alpha_blend_op(vk::BlendOp::ADD)
                 .color_write_mask(
@@ -501,14 +653,15 @@ impl EnginePipeline {
 
         let depth_stencil_info = vk::PipelineDepthStencilStateCreateInfo::builder()
             .depth_test_enable(true)
-            .depth_write_enable(true)
-            .depth_compare_op(vk::CompareOp::LESS_OR_EQUAL);
+            .depth_write_enable(depth_write)
+            .depth_compare_op(depth_compare_op(swapchain));
 
         let pipeline_info = vk::GraphicsPipelineCreateInfo::builder()
             .stages(&shader_stages)
             .vertex_input_state(&vertex_input_info)
             .input_assembly_state(&input_assembly_info)
             .viewport_state(&viewport_info)
+            .dynamic_state(&dynamic_state_info)
             .rasterization_state(&rasterizer_info)
             .multisample_state(&multisampler_info)
             .depth_stencil_state(&depth_stencil_info)
@@ -519,7 +672,7 @@ impl EnginePipeline {
 
         let graphics_pipeline = unsafe {
             device.create_graphics_pipelines(
-                vk::PipelineCache::null(),
+                pipeline_cache,
                 &[pipeline_info.build()],
                 None
             ).expect("Failed to create graphics pipeline")
@@ -533,10 +686,329 @@ impl EnginePipeline {
         Ok(EnginePipeline {
             pipeline: graphics_pipeline,
             layout: pipeline_layout,
-            descriptor_set_layouts: desc_layouts
+            descriptor_set_layouts: desc_layouts,
+            immutable_sampler,
+            push_constant_ranges: vec![],
         })
     }
 
+    // Draws a cube around the camera with a cubemap. Depth is tested against the far plane but
+    // never written, and the front face is flipped since the cube is seen from the inside.
+    pub fn init_skybox(
+        device: &ash::Device,
+        swapchain: &EngineSwapchain,
+        render_pass: vk::RenderPass,
+        pipeline_cache: vk::PipelineCache,
+    ) -> Result<EnginePipeline, vk::Result> {
+        // Loading Shaders
+
+        let vertex_shader_create_info = vk::ShaderModuleCreateInfo::builder()
+            .code(
+                vk_shader_macros::include_glsl!("./shaders/skybox.vert")
+            );
+        let vertex_shader_module = unsafe {
+            device.create_shader_module(&vertex_shader_create_info, None)?
+        };
+
+        let fragment_shader_create_info = vk::ShaderModuleCreateInfo::builder()
+            .code(
+                vk_shader_macros::include_glsl!("./shaders/skybox.frag")
+            );
+        let fragment_shader_module = unsafe {
+            device.create_shader_module(&fragment_shader_create_info, None)?
+        };
+
+        let entry_point = CString::new("main").unwrap();
+        let vertex_shader_stage = vk::PipelineShaderStageCreateInfo::builder()
+            .stage(vk::ShaderStageFlags::VERTEX)
+            .module(vertex_shader_module)
+            .name(&entry_point);
+        let fragment_shader_stage = vk::PipelineShaderStageCreateInfo::builder()
+            .stage(vk::ShaderStageFlags::FRAGMENT)
+            .module(fragment_shader_module)
+            .name(&entry_point);
+        let shader_stages = vec![
+            vertex_shader_stage.build(),
+            fragment_shader_stage.build()
+        ];
+
+        // Camera Descriptor Set
+
+        let descriptor_set_layout_binding_descs_cam = [
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(0)
+                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::VERTEX)
+                .build()
+        ];
+
+        let descriptor_set_layout_info_cam = vk::DescriptorSetLayoutCreateInfo::builder()
+            .bindings(&descriptor_set_layout_binding_descs_cam);
+
+        let descriptor_set_layout_cam = unsafe {
+            device.create_descriptor_set_layout(&descriptor_set_layout_info_cam, None)
+        }?;
+
+        // Cubemap Descriptor Set
+
+        let descriptor_set_layout_binding_descs_cube = [
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                .build()
+        ];
+
+        let descriptor_set_layout_info_cube = vk::DescriptorSetLayoutCreateInfo::builder()
+            .bindings(&descriptor_set_layout_binding_descs_cube);
+
+        let descriptor_set_layout_cube = unsafe {
+            device.create_descriptor_set_layout(&descriptor_set_layout_info_cube, None)
+        }?;
+
+        let desc_layouts = vec![descriptor_set_layout_cam, descriptor_set_layout_cube];
+
+        let pipeline_layout_info = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(&desc_layouts);
+
+        // Only positions are read, the instance buffer a Model binds is ignored
+        let vertex_attrib_descs = [
+            vk::VertexInputAttributeDescription {
+                binding: 0,
+                location: 0,
+                offset: 0,
+                format: vk::Format::R32G32B32_SFLOAT,
+            },
+        ];
+
+        let vertex_binding_descs = [
+            vk::VertexInputBindingDescription {
+                binding: 0,
+                stride: 12,
+                input_rate: vk::VertexInputRate::VERTEX,
+            },
+        ];
+
+        let vertex_input_info = vk::PipelineVertexInputStateCreateInfo::builder()
+            .vertex_attribute_descriptions(&vertex_attrib_descs)
+            .vertex_binding_descriptions(&vertex_binding_descs);
+
+        let input_assembly_info = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+
+        let viewport_info = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+
+        let dynamic_state_info = vk::PipelineDynamicStateCreateInfo::builder()
+            .dynamic_states(&dynamic_states);
+
+        let rasterizer_info = vk::PipelineRasterizationStateCreateInfo::builder()
+            .line_width(1.0)
+            .front_face(vk::FrontFace::CLOCKWISE)
+            .cull_mode(vk::CullModeFlags::BACK)
+            .polygon_mode(vk::PolygonMode::FILL);
+
+        let multisampler_info = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(swapchain.config.samples);
+
+        let colorblend_attachments = [
+            vk::PipelineColorBlendAttachmentState::builder()
+                .blend_enable(false)
+                .color_write_mask(
+                    vk::ColorComponentFlags::R
+                        | vk::ColorComponentFlags::G
+                        | vk::ColorComponentFlags::B
+                        | vk::ColorComponentFlags::A,
+                )
+                .build(),
+        ];
+
+        let colorblend_info = vk::PipelineColorBlendStateCreateInfo::builder()
+            .attachments(&colorblend_attachments);
+
+        let pipeline_layout = unsafe {
+            device.create_pipeline_layout(&pipeline_layout_info, None)?
+        };
+
+        let depth_stencil_info = vk::PipelineDepthStencilStateCreateInfo::builder()
+            .depth_test_enable(true)
+            .depth_write_enable(false)
+            .depth_compare_op(depth_compare_op(swapchain));
+
+        let pipeline_info = vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&shader_stages)
+            .vertex_input_state(&vertex_input_info)
+            .input_assembly_state(&input_assembly_info)
+            .viewport_state(&viewport_info)
+            .dynamic_state(&dynamic_state_info)
+            .rasterization_state(&rasterizer_info)
+            .multisample_state(&multisampler_info)
+            .depth_stencil_state(&depth_stencil_info)
+            .color_blend_state(&colorblend_info)
+            .layout(pipeline_layout)
+            .render_pass(render_pass)
+            .subpass(0);
+
+        let graphics_pipeline = unsafe {
+            device.create_graphics_pipelines(
+                pipeline_cache,
+                &[pipeline_info.build()],
+                None
+            ).expect("Failed to create graphics pipeline")
+        }[0];
+
+        unsafe {
+            device.destroy_shader_module(fragment_shader_module, None);
+            device.destroy_shader_module(vertex_shader_module, None);
+        }
+
+        Ok(EnginePipeline {
+            pipeline: graphics_pipeline,
+            layout: pipeline_layout,
+            descriptor_set_layouts: desc_layouts,
+            immutable_sampler: None,
+            push_constant_ranges: vec![],
+        })
+    }
+
+    // Pushes the part of the constants each declared range covers, nothing for pipelines
+    // without push constants
+    pub fn push_constants(
+        &self,
+        device: &ash::Device,
+        command_buffer: vk::CommandBuffer,
+        constants: &PushConstants,
+    ) {
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                constants as *const PushConstants as *const u8,
+                std::mem::size_of::<PushConstants>(),
+            )
+        };
+
+        for range in &self.push_constant_ranges {
+            let start = range.offset as usize;
+            let end = (range.offset + range.size) as usize;
+
+            unsafe {
+                device.cmd_push_constants(
+                    command_buffer,
+                    self.layout,
+                    range.stage_flags,
+                    range.offset,
+                    &bytes[start..end],
+                );
+            }
+        }
+    }
+
+    pub fn cleanup(&self, device: &ash::Device) {
+        unsafe {
+            for dsl in &self.descriptor_set_layouts {
+                device.destroy_descriptor_set_layout(*dsl, None);
+            }
+
+            device.destroy_pipeline(self.pipeline, None);
+            device.destroy_pipeline_layout(self.layout, None);
+
+            if let Some(sampler) = self.immutable_sampler {
+                device.destroy_sampler(sampler, None);
+            }
+        }
+    }
+}
+
+pub struct ComputePipeline {
+    pub pipeline: vk::Pipeline,
+    pub layout: vk::PipelineLayout,
+    pub descriptor_set_layouts: Vec<vk::DescriptorSetLayout>,
+}
+
+#[allow(dead_code)]
+impl ComputePipeline {
+    // include_glsl! needs a literal path, so the caller compiles the shader, e.g.
+    // vk_shader_macros::include_glsl!("./shaders/particles.comp"). The pipeline takes
+    // ownership of the descriptor set layouts and destroys them in cleanup.
+    pub fn init(
+        device: &ash::Device,
+        code: &[u32],
+        descriptor_set_layouts: Vec<vk::DescriptorSetLayout>,
+        pipeline_cache: vk::PipelineCache,
+    ) -> Result<ComputePipeline, vk::Result> {
+        let shader_create_info = vk::ShaderModuleCreateInfo::builder()
+            .code(code);
+        let shader_module = unsafe {
+            device.create_shader_module(&shader_create_info, None)?
+        };
+
+        let entry_point = CString::new("main").unwrap();
+        let shader_stage = vk::PipelineShaderStageCreateInfo::builder()
+            .stage(vk::ShaderStageFlags::COMPUTE)
+            .module(shader_module)
+            .name(&entry_point);
+
+        let pipeline_layout_info = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(&descriptor_set_layouts);
+
+        let pipeline_layout = unsafe {
+            device.create_pipeline_layout(&pipeline_layout_info, None)
+        }?;
+
+        let pipeline_info = vk::ComputePipelineCreateInfo::builder()
+            .stage(shader_stage.build())
+            .layout(pipeline_layout);
+
+        let compute_pipeline = unsafe {
+            device.create_compute_pipelines(
+                pipeline_cache,
+                &[pipeline_info.build()],
+                None
+            )
+        }.map_err(|(_, err)| err)?[0];
+
+        unsafe {
+            device.destroy_shader_module(shader_module, None);
+        }
+
+        Ok(ComputePipeline {
+            pipeline: compute_pipeline,
+            layout: pipeline_layout,
+            descriptor_set_layouts,
+        })
+    }
+
+    pub fn dispatch(
+        &self,
+        device: &ash::Device,
+        command_buffer: vk::CommandBuffer,
+        descriptor_sets: &[vk::DescriptorSet],
+        group_counts: [u32; 3],
+    ) {
+        unsafe {
+            device.cmd_bind_pipeline(
+                command_buffer,
+                vk::PipelineBindPoint::COMPUTE,
+                self.pipeline
+            );
+
+            device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::COMPUTE,
+                self.layout,
+                0,
+                descriptor_sets,
+                &[],
+            );
+
+            device.cmd_dispatch(command_buffer, group_counts[0], group_counts[1], group_counts[2]);
+        }
+    }
+
     pub fn cleanup(&self, device: &ash::Device) {
         unsafe {
             for dsl in &self.descriptor_set_layouts {
@@ -547,4 +1019,32 @@ impl EnginePipeline {
             device.destroy_pipeline_layout(self.layout, None);
         }
     }
-}
\ No newline at end of file
+}
+
+// Closer fragments have the larger depth with reverse-Z
+fn depth_compare_op(swapchain: &EngineSwapchain) -> vk::CompareOp {
+    if swapchain.config.reverse_z {
+        vk::CompareOp::GREATER_OR_EQUAL
+    } else {
+        vk::CompareOp::LESS_OR_EQUAL
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_constants_match_shader() {
+        // mat4 model_matrix followed by uint use_push_constant, see shaders/shader.vert
+        assert_eq!(std::mem::size_of::<PushConstants>(), 68);
+
+        let constants = PushConstants::from_matrix(&na::Matrix4::new_scaling(2.0));
+        let base = &constants as *const PushConstants as usize;
+        let flag = &constants.use_push_constant as *const u32 as usize;
+
+        assert_eq!(flag - base, 64);
+        assert_eq!(constants.use_push_constant, 1);
+        assert_eq!(PushConstants::instanced().use_push_constant, 0);
+    }
+}
@@ -4,6 +4,7 @@ use super::queue_families::QueueFamilies;
 pub struct Pools {
     pub command_pool_graphics: vk::CommandPool,
     pub command_pool_transfer: vk::CommandPool,
+    pub command_pool_compute: vk::CommandPool,
 }
 
 impl Pools {
@@ -25,9 +26,17 @@ impl Pools {
             device.create_command_pool(&transfer_command_pool_info, None)
         }?;
 
+        let compute_command_pool_info = vk::CommandPoolCreateInfo::builder()
+            .queue_family_index(queue_families.compute_index.unwrap())
+            .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER);
+        let command_pool_compute = unsafe {
+            device.create_command_pool(&compute_command_pool_info, None)
+        }?;
+
         Ok(Pools {
             command_pool_graphics,
             command_pool_transfer,
+            command_pool_compute,
         })
     }
 
@@ -49,6 +58,7 @@ impl Pools {
         unsafe {
             device.destroy_command_pool(self.command_pool_graphics, None);
             device.destroy_command_pool(self.command_pool_transfer, None);
+            device.destroy_command_pool(self.command_pool_compute, None);
         }
     }
 }
\ No newline at end of file
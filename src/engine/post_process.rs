@@ -0,0 +1,344 @@
+use std::ffi::CString;
+use ash::vk;
+use crate::engine::error::EngineError;
+use crate::engine::render_target::RenderTarget;
+use crate::engine::swapchain::EngineSwapchain;
+
+// The scene is rendered into a float target before post-processing, so the shader can map
+// values above 1.0 down instead of having them clipped
+pub const HDR_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+
+// Draws a fullscreen triangle into the engine's render pass, with a fragment shader that samples
+// the color image of a RenderTarget at set 0, binding 0. The shader gets the uv at location 0
+// and the exposure as a float push constant, which it is free to ignore.
+pub struct PostProcess {
+    pub pipeline: vk::Pipeline,
+    pub layout: vk::PipelineLayout,
+    pub descriptor_set_layout: vk::DescriptorSetLayout,
+    pub descriptor_pool: vk::DescriptorPool,
+    pub descriptor_set: vk::DescriptorSet,
+    fragment_code: Vec<u32>,
+}
+
+#[allow(dead_code)]
+impl PostProcess {
+    pub fn new<P: AsRef<std::path::Path>>(
+        device: &ash::Device,
+        shader_path: P,
+        input: &RenderTarget,
+        swapchain: &EngineSwapchain,
+        render_pass: vk::RenderPass,
+        pipeline_cache: vk::PipelineCache,
+    ) -> Result<PostProcess, EngineError> {
+        let fragment_code = ash::util::read_spv(&mut std::fs::File::open(shader_path)?)?;
+
+        Self::with_code(device, fragment_code, input, swapchain, render_pass, pipeline_cache)
+    }
+
+    pub fn grayscale(
+        device: &ash::Device,
+        input: &RenderTarget,
+        swapchain: &EngineSwapchain,
+        render_pass: vk::RenderPass,
+        pipeline_cache: vk::PipelineCache,
+    ) -> Result<PostProcess, EngineError> {
+        let fragment_code = vk_shader_macros::include_glsl!("./shaders/grayscale.frag").to_vec();
+
+        Self::with_code(device, fragment_code, input, swapchain, render_pass, pipeline_cache)
+    }
+
+    pub fn tonemap(
+        device: &ash::Device,
+        input: &RenderTarget,
+        swapchain: &EngineSwapchain,
+        render_pass: vk::RenderPass,
+        pipeline_cache: vk::PipelineCache,
+    ) -> Result<PostProcess, EngineError> {
+        let fragment_code = vk_shader_macros::include_glsl!("./shaders/tonemap.frag").to_vec();
+
+        Self::with_code(device, fragment_code, input, swapchain, render_pass, pipeline_cache)
+    }
+
+    fn with_code(
+        device: &ash::Device,
+        fragment_code: Vec<u32>,
+        input: &RenderTarget,
+        swapchain: &EngineSwapchain,
+        render_pass: vk::RenderPass,
+        pipeline_cache: vk::PipelineCache,
+    ) -> Result<PostProcess, EngineError> {
+        let descriptor_set_layout_binding_descs = [
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                .build()
+        ];
+
+        let descriptor_set_layout_info = vk::DescriptorSetLayoutCreateInfo::builder()
+            .bindings(&descriptor_set_layout_binding_descs);
+
+        let descriptor_set_layout = unsafe {
+            device.create_descriptor_set_layout(&descriptor_set_layout_info, None)
+        }?;
+
+        let desc_layouts = [descriptor_set_layout];
+
+        let push_constant_ranges = [
+            vk::PushConstantRange {
+                stage_flags: vk::ShaderStageFlags::FRAGMENT,
+                offset: 0,
+                size: 4,
+            }
+        ];
+
+        let pipeline_layout_info = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(&desc_layouts)
+            .push_constant_ranges(&push_constant_ranges);
+
+        let layout = unsafe {
+            device.create_pipeline_layout(&pipeline_layout_info, None)
+        }?;
+
+        let pipeline = Self::create_pipeline(
+            device,
+            &fragment_code,
+            layout,
+            swapchain,
+            render_pass,
+            pipeline_cache,
+        )?;
+
+        let pool_sizes = [
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                descriptor_count: 1,
+            }
+        ];
+
+        let descriptor_pool_info = vk::DescriptorPoolCreateInfo::builder()
+            .max_sets(1)
+            .pool_sizes(&pool_sizes);
+
+        let descriptor_pool = unsafe {
+            device.create_descriptor_pool(&descriptor_pool_info, None)
+        }?;
+
+        let descriptor_set_allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(&desc_layouts);
+
+        let descriptor_set = unsafe {
+            device.allocate_descriptor_sets(&descriptor_set_allocate_info)
+        }?[0];
+
+        let post_process = PostProcess {
+            pipeline,
+            layout,
+            descriptor_set_layout,
+            descriptor_pool,
+            descriptor_set,
+            fragment_code,
+        };
+
+        post_process.set_input(device, input);
+
+        Ok(post_process)
+    }
+
+    fn create_pipeline(
+        device: &ash::Device,
+        fragment_code: &[u32],
+        layout: vk::PipelineLayout,
+        swapchain: &EngineSwapchain,
+        render_pass: vk::RenderPass,
+        pipeline_cache: vk::PipelineCache,
+    ) -> Result<vk::Pipeline, vk::Result> {
+        let vertex_shader_create_info = vk::ShaderModuleCreateInfo::builder()
+            .code(
+                vk_shader_macros::include_glsl!("./shaders/fullscreen.vert")
+            );
+        let vertex_shader_module = unsafe {
+            device.create_shader_module(&vertex_shader_create_info, None)?
+        };
+
+        let fragment_shader_create_info = vk::ShaderModuleCreateInfo::builder()
+            .code(fragment_code);
+        let fragment_shader_module = unsafe {
+            device.create_shader_module(&fragment_shader_create_info, None)?
+        };
+
+        let entry_point = CString::new("main").unwrap();
+        let shader_stages = [
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::VERTEX)
+                .module(vertex_shader_module)
+                .name(&entry_point)
+                .build(),
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::FRAGMENT)
+                .module(fragment_shader_module)
+                .name(&entry_point)
+                .build(),
+        ];
+
+        // The triangle's corners come from gl_VertexIndex, there are no vertex buffers
+        let vertex_input_info = vk::PipelineVertexInputStateCreateInfo::builder();
+
+        let input_assembly_info = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+
+        let viewport_info = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+
+        let dynamic_state_info = vk::PipelineDynamicStateCreateInfo::builder()
+            .dynamic_states(&dynamic_states);
+
+        let rasterizer_info = vk::PipelineRasterizationStateCreateInfo::builder()
+            .line_width(1.0)
+            .cull_mode(vk::CullModeFlags::NONE)
+            .polygon_mode(vk::PolygonMode::FILL);
+
+        let multisampler_info = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(swapchain.config.samples);
+
+        let colorblend_attachments = [
+            vk::PipelineColorBlendAttachmentState::builder()
+                .blend_enable(false)
+                .color_write_mask(
+                    vk::ColorComponentFlags::R
+                        | vk::ColorComponentFlags::G
+                        | vk::ColorComponentFlags::B
+                        | vk::ColorComponentFlags::A,
+                )
+                .build(),
+        ];
+
+        let colorblend_info = vk::PipelineColorBlendStateCreateInfo::builder()
+            .attachments(&colorblend_attachments);
+
+        // The render pass has a depth attachment, the triangle just ignores it
+        let depth_stencil_info = vk::PipelineDepthStencilStateCreateInfo::builder()
+            .depth_test_enable(false)
+            .depth_write_enable(false);
+
+        let pipeline_info = vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&shader_stages)
+            .vertex_input_state(&vertex_input_info)
+            .input_assembly_state(&input_assembly_info)
+            .viewport_state(&viewport_info)
+            .dynamic_state(&dynamic_state_info)
+            .rasterization_state(&rasterizer_info)
+            .multisample_state(&multisampler_info)
+            .depth_stencil_state(&depth_stencil_info)
+            .color_blend_state(&colorblend_info)
+            .layout(layout)
+            .render_pass(render_pass)
+            .subpass(0);
+
+        let pipeline_result = unsafe {
+            device.create_graphics_pipelines(
+                pipeline_cache,
+                &[pipeline_info.build()],
+                None
+            )
+        };
+
+        unsafe {
+            device.destroy_shader_module(fragment_shader_module, None);
+            device.destroy_shader_module(vertex_shader_module, None);
+        }
+
+        pipeline_result
+            .map(|pipelines| pipelines[0])
+            .map_err(|(_, error)| error)
+    }
+
+    // The pipeline depends on the render pass and the sample count, see VulkanEngine::recreate_pipelines
+    pub fn recreate_pipeline(
+        &mut self,
+        device: &ash::Device,
+        swapchain: &EngineSwapchain,
+        render_pass: vk::RenderPass,
+        pipeline_cache: vk::PipelineCache,
+    ) -> Result<(), vk::Result> {
+        let pipeline = Self::create_pipeline(
+            device,
+            &self.fragment_code,
+            self.layout,
+            swapchain,
+            render_pass,
+            pipeline_cache,
+        )?;
+
+        unsafe {
+            device.destroy_pipeline(self.pipeline, None);
+        }
+
+        self.pipeline = pipeline;
+
+        Ok(())
+    }
+
+    // Has to be called again whenever the input target is recreated, e.g. after a resize
+    pub fn set_input(&self, device: &ash::Device, input: &RenderTarget) {
+        let image_infos = [vk::DescriptorImageInfo {
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            image_view: input.image_view,
+            sampler: input.sampler,
+        }];
+
+        let descriptor_write_image = vk::WriteDescriptorSet::builder()
+            .dst_set(self.descriptor_set)
+            .dst_binding(0)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .image_info(&image_infos);
+
+        unsafe {
+            device.update_descriptor_sets(&[descriptor_write_image.build()], &[]);
+        }
+    }
+
+    // Expects a render pass to be active with viewport and scissor set
+    pub fn draw(&self, device: &ash::Device, command_buffer: vk::CommandBuffer, exposure: f32) {
+        unsafe {
+            device.cmd_bind_pipeline(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.pipeline
+            );
+
+            device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.layout,
+                0,
+                &[self.descriptor_set],
+                &[]
+            );
+
+            device.cmd_push_constants(
+                command_buffer,
+                self.layout,
+                vk::ShaderStageFlags::FRAGMENT,
+                0,
+                &exposure.to_ne_bytes(),
+            );
+
+            device.cmd_draw(command_buffer, 3, 1, 0, 0);
+        }
+    }
+
+    pub fn cleanup(&self, device: &ash::Device) {
+        unsafe {
+            device.destroy_descriptor_pool(self.descriptor_pool, None);
+            device.destroy_pipeline(self.pipeline, None);
+            device.destroy_pipeline_layout(self.layout, None);
+            device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+        }
+    }
+}
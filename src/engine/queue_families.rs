@@ -3,9 +3,13 @@ use super::surface::EngineSurface;
 
 pub struct QueueFamilies {
     pub graphics_index: Option<u32>,
+    pub present_index: Option<u32>,
     pub transfer_index: Option<u32>,
+    pub compute_index: Option<u32>,
+    pub properties: Vec<vk::QueueFamilyProperties>,
 }
 
+#[allow(dead_code)]
 impl QueueFamilies {
     pub fn init(
         instance: &ash::Instance,
@@ -16,16 +20,21 @@ impl QueueFamilies {
             instance.get_physical_device_queue_family_properties(physical_device)
         };
 
-        let mut graphics_index = None;
+        let mut graphics_families = Vec::new();
+        let mut present_families = Vec::new();
         let mut transfer_index = None;
+        let mut compute_index = None;
 
         for (i, family) in queue_family_properties.iter().enumerate() {
             if family.queue_count > 0 {
-                if family.queue_flags.contains(vk::QueueFlags::GRAPHICS) && unsafe {
-                    surfaces.surface_loader.get_physical_device_surface_support(physical_device, i as u32, surfaces.surface)?
+                if family.queue_flags.contains(vk::QueueFlags::GRAPHICS) {
+                    graphics_families.push(i as u32);
                 }
-                {
-                    graphics_index = Some(i as u32);
+
+                if unsafe {
+                    surfaces.surface_loader.get_physical_device_surface_support(physical_device, i as u32, surfaces.surface)?
+                } {
+                    present_families.push(i as u32);
                 }
 
                 if family.queue_flags.contains(vk::QueueFlags::TRANSFER) {
@@ -33,12 +42,61 @@ impl QueueFamilies {
                         transfer_index = Some(i as u32);
                     }
                 }
+
+                // A compute-only family can run alongside the graphics work, otherwise compute
+                // shares the graphics family
+                if family.queue_flags.contains(vk::QueueFlags::COMPUTE)
+                    && (compute_index.is_none() || !family.queue_flags.contains(vk::QueueFlags::GRAPHICS))
+                {
+                    compute_index = Some(i as u32);
+                }
             }
         }
 
+        // A family that can do both saves sharing the swapchain images between two families
+        let shared_index = graphics_families
+            .iter()
+            .copied()
+            .find(|index| present_families.contains(index));
+
+        let (graphics_index, present_index) = match shared_index {
+            Some(index) => (Some(index), Some(index)),
+            None => (graphics_families.first().copied(), present_families.first().copied()),
+        };
+
         Ok(QueueFamilies {
-            graphics_index: graphics_index,
-            transfer_index: transfer_index,
+            graphics_index,
+            present_index,
+            transfer_index,
+            compute_index,
+            properties: queue_family_properties,
         })
     }
+
+    pub fn graphics_index(&self) -> Option<u32> {
+        self.graphics_index
+    }
+
+    pub fn present_index(&self) -> Option<u32> {
+        self.present_index
+    }
+
+    pub fn transfer_index(&self) -> Option<u32> {
+        self.transfer_index
+    }
+
+    pub fn compute_index(&self) -> Option<u32> {
+        self.compute_index
+    }
+
+    pub fn properties(&self, index: u32) -> Option<&vk::QueueFamilyProperties> {
+        self.properties.get(index as usize)
+    }
+
+    pub fn find(&self, flags: vk::QueueFlags) -> Option<u32> {
+        self.properties
+            .iter()
+            .position(|family| family.queue_count > 0 && family.queue_flags.contains(flags))
+            .map(|i| i as u32)
+    }
 }
\ No newline at end of file
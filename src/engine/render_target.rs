@@ -0,0 +1,318 @@
+use ash::vk;
+use gpu_allocator::vulkan::Allocation;
+use crate::engine::allocator::VkAllocator;
+use crate::engine::error::EngineError;
+use crate::engine::swapchain::depth_aspect_mask;
+
+// A color and a depth image to render into instead of the swapchain. The color image ends up
+// in SHADER_READ_ONLY_OPTIMAL, so image_view and sampler can be bound just like a Texture's.
+// The render pass matches the engine's formats and sample count, which keeps it compatible
+// with the engine's pipelines.
+pub struct RenderTarget {
+    pub extent: vk::Extent2D,
+    pub color_image: vk::Image,
+    pub color_image_allocation: Option<Allocation>,
+    pub image_view: vk::ImageView,
+    pub sampler: vk::Sampler,
+    pub depth_image: vk::Image,
+    pub depth_image_allocation: Option<Allocation>,
+    pub depth_image_view: vk::ImageView,
+    pub msaa_image: Option<vk::Image>,
+    pub msaa_image_allocation: Option<Allocation>,
+    pub msaa_image_view: Option<vk::ImageView>,
+    pub render_pass: vk::RenderPass,
+    pub framebuffer: vk::Framebuffer,
+}
+
+#[allow(dead_code)]
+impl RenderTarget {
+    pub fn init(
+        device: &ash::Device,
+        allocator: &mut VkAllocator,
+        extent: vk::Extent2D,
+        color_format: vk::Format,
+        depth_format: vk::Format,
+        samples: vk::SampleCountFlags,
+    ) -> Result<RenderTarget, EngineError> {
+        let multisampled = samples != vk::SampleCountFlags::TYPE_1;
+
+        let (color_image, color_image_allocation) = Self::create_image(
+            allocator,
+            extent,
+            color_format,
+            vk::SampleCountFlags::TYPE_1,
+            vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+        )?;
+
+        let image_view = create_view(device, color_image, color_format, vk::ImageAspectFlags::COLOR)?;
+
+        let sampler_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(vk::Filter::LINEAR)
+            .min_filter(vk::Filter::LINEAR)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE);
+
+        let sampler = unsafe {
+            device.create_sampler(&sampler_info, None)
+        }?;
+
+        let (depth_image, depth_image_allocation) = Self::create_image(
+            allocator,
+            extent,
+            depth_format,
+            samples,
+            vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+        )?;
+
+        let depth_image_view = create_view(device, depth_image, depth_format, depth_aspect_mask(depth_format))?;
+
+        // Multisampled color image, resolved into the color image at the end of the pass
+        let (msaa_image, msaa_image_allocation, msaa_image_view) = if multisampled {
+            let (msaa_image, msaa_allocation) = Self::create_image(
+                allocator,
+                extent,
+                color_format,
+                samples,
+                vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT,
+            )?;
+
+            let msaa_image_view = create_view(device, msaa_image, color_format, vk::ImageAspectFlags::COLOR)?;
+
+            (Some(msaa_image), Some(msaa_allocation), Some(msaa_image_view))
+        } else {
+            (None, None, None)
+        };
+
+        let render_pass = Self::create_render_pass(device, color_format, depth_format, samples)?;
+
+        // Attachment order matches the render pass: [color, depth] or [msaa color, depth, resolve]
+        let attachments = match msaa_image_view {
+            Some(msaa_image_view) => vec![msaa_image_view, depth_image_view, image_view],
+            None => vec![image_view, depth_image_view],
+        };
+
+        let framebuffer_info = vk::FramebufferCreateInfo::builder()
+            .render_pass(render_pass)
+            .attachments(&attachments)
+            .width(extent.width)
+            .height(extent.height)
+            .layers(1);
+
+        let framebuffer = unsafe {
+            device.create_framebuffer(&framebuffer_info, None)
+        }?;
+
+        Ok(RenderTarget {
+            extent,
+            color_image,
+            color_image_allocation: Some(color_image_allocation),
+            image_view,
+            sampler,
+            depth_image,
+            depth_image_allocation: Some(depth_image_allocation),
+            depth_image_view,
+            msaa_image,
+            msaa_image_allocation,
+            msaa_image_view,
+            render_pass,
+            framebuffer,
+        })
+    }
+
+    fn create_image(
+        allocator: &mut VkAllocator,
+        extent: vk::Extent2D,
+        format: vk::Format,
+        samples: vk::SampleCountFlags,
+        usage: vk::ImageUsageFlags,
+    ) -> Result<(vk::Image, Allocation), EngineError> {
+        let image_info = vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(format)
+            .extent(vk::Extent3D {
+                width: extent.width,
+                height: extent.height,
+                depth: 1,
+            })
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(samples)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+
+        allocator.allocate_image(&image_info, gpu_allocator::MemoryLocation::GpuOnly, false)
+    }
+
+    // Same attachments as VulkanEngine::init_render_pass, only the color image is left ready
+    // for sampling instead of presenting
+    fn create_render_pass(
+        device: &ash::Device,
+        color_format: vk::Format,
+        depth_format: vk::Format,
+        samples: vk::SampleCountFlags,
+    ) -> Result<vk::RenderPass, vk::Result> {
+        let multisampled = samples != vk::SampleCountFlags::TYPE_1;
+
+        let (color_store_op, color_final_layout) = if multisampled {
+            (vk::AttachmentStoreOp::DONT_CARE, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+        } else {
+            (vk::AttachmentStoreOp::STORE, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+        };
+
+        let mut attachments = vec![
+            vk::AttachmentDescription::builder()
+                .format(color_format)
+                .load_op(vk::AttachmentLoadOp::CLEAR)
+                .store_op(color_store_op)
+                .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+                .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .initial_layout(vk::ImageLayout::UNDEFINED)
+                .final_layout(color_final_layout)
+                .samples(samples)
+                .build(),
+            vk::AttachmentDescription::builder()
+                .format(depth_format)
+                .load_op(vk::AttachmentLoadOp::CLEAR)
+                .store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+                .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .initial_layout(vk::ImageLayout::UNDEFINED)
+                .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+                .samples(samples)
+                .build()
+        ];
+
+        if multisampled {
+            attachments.push(
+                vk::AttachmentDescription::builder()
+                    .format(color_format)
+                    .load_op(vk::AttachmentLoadOp::DONT_CARE)
+                    .store_op(vk::AttachmentStoreOp::STORE)
+                    .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+                    .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+                    .initial_layout(vk::ImageLayout::UNDEFINED)
+                    .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                    .samples(vk::SampleCountFlags::TYPE_1)
+                    .build()
+            );
+        }
+
+        let color_attachment_refs = [
+            vk::AttachmentReference {
+                attachment: 0,
+                layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            }
+        ];
+
+        let depth_attachment_refs = [
+            vk::AttachmentReference {
+                attachment: 1,
+                layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL
+            }
+        ];
+
+        let resolve_attachment_refs = [
+            vk::AttachmentReference {
+                attachment: 2,
+                layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            }
+        ];
+
+        let mut subpass = vk::SubpassDescription::builder()
+            .color_attachments(&color_attachment_refs)
+            .depth_stencil_attachment(&depth_attachment_refs[0])
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS);
+
+        if multisampled {
+            subpass = subpass.resolve_attachments(&resolve_attachment_refs);
+        }
+
+        let subpasses = [subpass.build()];
+
+        let subpass_dependencies = [
+            vk::SubpassDependency::builder()
+                .src_subpass(vk::SUBPASS_EXTERNAL)
+                .src_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+                .src_access_mask(vk::AccessFlags::SHADER_READ)
+                .dst_subpass(0)
+                .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+                .dst_access_mask(
+                    vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                )
+                .build(),
+            // Make the color writes visible to the passes sampling the image afterwards
+            vk::SubpassDependency::builder()
+                .src_subpass(0)
+                .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+                .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                .dst_subpass(vk::SUBPASS_EXTERNAL)
+                .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ)
+                .build(),
+        ];
+
+        let render_pass_info = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments)
+            .subpasses(&subpasses)
+            .dependencies(&subpass_dependencies);
+
+        unsafe {
+            device.create_render_pass(&render_pass_info, None)
+        }
+    }
+
+    pub unsafe fn cleanup(&mut self, device: &ash::Device, allocator: &mut VkAllocator) {
+        device.destroy_framebuffer(self.framebuffer, None);
+        device.destroy_render_pass(self.render_pass, None);
+
+        if let Some(image_view) = self.msaa_image_view {
+            device.destroy_image_view(image_view, None);
+        }
+
+        if let (Some(image), Some(allocation)) = (self.msaa_image, self.msaa_image_allocation.take()) {
+            let destroyer = |device: &ash::Device| device.destroy_image(image, None);
+            allocator.free(allocation, &destroyer);
+        }
+
+        device.destroy_image_view(self.depth_image_view, None);
+
+        if let Some(allocation) = self.depth_image_allocation.take() {
+            let destroyer = |device: &ash::Device| device.destroy_image(self.depth_image, None);
+            allocator.free(allocation, &destroyer);
+        }
+
+        device.destroy_sampler(self.sampler, None);
+        device.destroy_image_view(self.image_view, None);
+
+        if let Some(allocation) = self.color_image_allocation.take() {
+            let destroyer = |device: &ash::Device| device.destroy_image(self.color_image, None);
+            allocator.free(allocation, &destroyer);
+        }
+    }
+}
+
+fn create_view(
+    device: &ash::Device,
+    image: vk::Image,
+    format: vk::Format,
+    aspect_mask: vk::ImageAspectFlags,
+) -> Result<vk::ImageView, vk::Result> {
+    let subresource_range = vk::ImageSubresourceRange::builder()
+        .aspect_mask(aspect_mask)
+        .base_mip_level(0)
+        .level_count(1)
+        .base_array_layer(0)
+        .layer_count(1);
+
+    let image_view_create_info = vk::ImageViewCreateInfo::builder()
+        .image(image)
+        .view_type(vk::ImageViewType::TYPE_2D)
+        .format(format)
+        .subresource_range(*subresource_range);
+
+    unsafe {
+        device.create_image_view(&image_view_create_info, None)
+    }
+}
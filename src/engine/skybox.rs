@@ -0,0 +1,40 @@
+use crate::engine::allocator::VkAllocator;
+use crate::engine::error::EngineError;
+use crate::engine::model::{InstanceData, Model, VertexData};
+use crate::engine::texture::Texture;
+use crate::na;
+
+pub struct Skybox {
+    pub model: Model<[f32; 3], InstanceData>,
+    pub texture: Texture,
+}
+
+#[allow(dead_code)]
+impl Skybox {
+    // The texture has to be a cubemap, see Texture::cubemap_from_files
+    pub fn new(
+        texture: Texture,
+        allocator: &mut VkAllocator,
+    ) -> Result<Skybox, EngineError> {
+        let mut model = Model::<VertexData, InstanceData>::cube();
+
+        // Model::cube spans 0 to 1 along z, the sky needs a cube centered on the camera
+        for position in &mut model.vertex_data {
+            position[2] = position[2] * 2.0 - 1.0;
+        }
+
+        // Only visible instances are drawn, the skybox pipeline doesn't read the instance itself
+        model.insert_visibly(InstanceData::from_props(na::Matrix4::identity(), [1.0, 1.0, 1.0], 0.0, 1.0));
+
+        model.update_vertex_buffer(allocator)?;
+        model.update_index_buffer(allocator)?;
+        model.update_instance_buffer(allocator)?;
+
+        Ok(Skybox { model, texture })
+    }
+
+    pub unsafe fn cleanup(&mut self, allocator: &mut VkAllocator, device: &ash::Device) {
+        self.model.cleanup(allocator);
+        self.texture.cleanup(allocator, device);
+    }
+}
@@ -1,28 +1,126 @@
 use ash::vk;
+use crate::engine::error::EngineError;
 use gpu_allocator::vulkan::{Allocation, AllocationCreateDesc, Allocator};
 use winit::window::CursorIcon::Default;
 use crate::engine::allocator::VkAllocator;
 use super::surface::EngineSurface;
 use super::queue_families::QueueFamilies;
 
+pub const DEFAULT_FRAMES_IN_FLIGHT: usize = 2;
+
+#[allow(dead_code)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PresentMode {
+    Mailbox,
+    Fifo,
+    Immediate,
+}
+
+impl PresentMode {
+    pub fn to_vk(self) -> vk::PresentModeKHR {
+        match self {
+            PresentMode::Mailbox => vk::PresentModeKHR::MAILBOX,
+            PresentMode::Fifo => vk::PresentModeKHR::FIFO,
+            PresentMode::Immediate => vk::PresentModeKHR::IMMEDIATE,
+        }
+    }
+}
+
+// Ids attached to presents through VK_KHR_present_id, so VK_KHR_present_wait can wait for a
+// specific one. They have to increase with every present, 0 means no id.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct PresentIds {
+    enabled: bool,
+    last: u64,
+}
+
+impl PresentIds {
+    pub fn new(enabled: bool) -> PresentIds {
+        PresentIds { enabled, last: 0 }
+    }
+
+    // The id for the next present, None while present ids aren't enabled
+    pub fn next(&mut self) -> Option<u64> {
+        if !self.enabled {
+            return None;
+        }
+
+        self.last += 1;
+        Some(self.last)
+    }
+
+    pub fn last(&self) -> Option<u64> {
+        if self.last > 0 {
+            Some(self.last)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct SwapchainConfig {
+    pub extent: vk::Extent2D,
+    pub frames_in_flight: usize,
+    pub sampled_depth: bool,
+    pub samples: vk::SampleCountFlags,
+    pub depth_format: vk::Format,
+    pub transparent: bool,
+    pub present_mode: PresentMode,
+    // Depth goes from 1 at the near plane to 0 at the far plane, see Camera::reverse_z
+    pub reverse_z: bool,
+}
+
+pub fn has_stencil_component(format: vk::Format) -> bool {
+    matches!(
+        format,
+        vk::Format::D32_SFLOAT_S8_UINT | vk::Format::D24_UNORM_S8_UINT | vk::Format::D16_UNORM_S8_UINT
+    )
+}
+
+pub fn depth_aspect_mask(format: vk::Format) -> vk::ImageAspectFlags {
+    if has_stencil_component(format) {
+        vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL
+    } else {
+        vk::ImageAspectFlags::DEPTH
+    }
+}
+
+#[allow(dead_code)]
+pub struct DepthTexture {
+    pub image_view: vk::ImageView,
+    pub sampler: vk::Sampler,
+}
+
 pub struct EngineSwapchain {
     pub loader: ash::extensions::khr::Swapchain,
     pub swapchain: vk::SwapchainKHR,
     pub images: Vec<vk::Image>,
     pub image_views: Vec<vk::ImageView>,
     pub depth_image: vk::Image,
-    pub depth_image_allocation: Allocation,
+    pub depth_extent: vk::Extent3D,
+    pub depth_image_allocation: Option<Allocation>,
     pub depth_image_view: vk::ImageView,
+    pub depth_sampler: Option<vk::Sampler>,
+    pub depth_sampled_view: Option<vk::ImageView>,
+    pub msaa_image: Option<vk::Image>,
+    pub msaa_image_allocation: Option<Allocation>,
+    pub msaa_image_view: Option<vk::ImageView>,
     pub framebuffers: Vec<vk::Framebuffer>,
     pub surface_format: vk::SurfaceFormatKHR,
+    pub image_usage: vk::ImageUsageFlags,
+    pub present_mode: vk::PresentModeKHR,
     pub extent: vk::Extent2D,
     pub image_available: Vec<vk::Semaphore>,
     pub rendering_finished: Vec<vk::Semaphore>,
     pub may_begin_drawing: Vec<vk::Fence>,
+    pub images_in_flight: Vec<vk::Fence>,
     pub amount_of_images: u32,
-    pub current_image: usize,
+    pub config: SwapchainConfig,
+    pub current_frame: usize,
 }
 
+#[allow(dead_code)]
 impl EngineSwapchain {
     pub fn init(
         instance: &ash::Instance,
@@ -30,14 +128,101 @@ impl EngineSwapchain {
         device: &ash::Device,
         surfaces: &EngineSurface,
         queue_families: &QueueFamilies,
-        allocator: &mut VkAllocator
-    ) -> Result<EngineSwapchain, vk::Result> {
+        allocator: &mut VkAllocator,
+        config: SwapchainConfig,
+    ) -> Result<EngineSwapchain, EngineError> {
+        let mut swapchain = Self::create(
+            instance,
+            physical_device,
+            device,
+            surfaces,
+            queue_families,
+            allocator,
+            config,
+            vk::SwapchainKHR::null(),
+        )?;
+
+        swapchain.create_sync_objects(device)?;
+
+        Ok(swapchain)
+    }
+
+    // Rebuilds the swapchain and everything sized to it at the surface's current extent.
+    // The sync objects are kept unless the number of frames in flight changed, and the old
+    // swapchain is passed on so the presentation engine can reuse its resources.
+    #[allow(clippy::too_many_arguments)]
+    pub fn recreate(
+        &mut self,
+        instance: &ash::Instance,
+        physical_device: vk::PhysicalDevice,
+        device: &ash::Device,
+        surfaces: &EngineSurface,
+        queue_families: &QueueFamilies,
+        allocator: &mut VkAllocator,
+        config: SwapchainConfig,
+    ) -> Result<(), EngineError> {
+        unsafe {
+            self.cleanup_images(device, allocator);
+        }
+
+        let mut swapchain = Self::create(
+            instance,
+            physical_device,
+            device,
+            surfaces,
+            queue_families,
+            allocator,
+            config,
+            self.swapchain,
+        )?;
+
+        unsafe {
+            self.loader.destroy_swapchain(self.swapchain, None);
+        }
+
+        if self.frames_in_flight() == config.frames_in_flight.max(1) {
+            swapchain.image_available = std::mem::take(&mut self.image_available);
+            swapchain.rendering_finished = std::mem::take(&mut self.rendering_finished);
+            swapchain.may_begin_drawing = std::mem::take(&mut self.may_begin_drawing);
+            swapchain.current_frame = self.current_frame;
+        } else {
+            unsafe {
+                self.cleanup_sync_objects(device);
+            }
+
+            swapchain.create_sync_objects(device)?;
+        }
+
+        *self = swapchain;
+
+        // The framebuffers combine the depth image with the color images, their sizes have to match
+        debug_assert_eq!(
+            (self.depth_extent.width, self.depth_extent.height),
+            (self.extent.width, self.extent.height)
+        );
+
+        Ok(())
+    }
+
+    // Everything but the sync objects, which init and recreate take care of
+    #[allow(clippy::too_many_arguments)]
+    fn create(
+        instance: &ash::Instance,
+        physical_device: vk::PhysicalDevice,
+        device: &ash::Device,
+        surfaces: &EngineSurface,
+        queue_families: &QueueFamilies,
+        allocator: &mut VkAllocator,
+        config: SwapchainConfig,
+        old_swapchain: vk::SwapchainKHR,
+    ) -> Result<EngineSwapchain, EngineError> {
         let surface_capabilities = surfaces.capabilities(physical_device)?;
-        let _surface_present_modes = surfaces.present_modes(physical_device)?;
+        let surface_present_modes = surfaces.present_modes(physical_device)?;
         let surface_formats = surfaces.formats(physical_device)?;
 
         let format = surface_formats[0];
-        let extent = surface_capabilities.current_extent;
+        let extent = Self::choose_extent(&surface_capabilities, config.extent);
+        let present_mode = Self::choose_present_mode(&surface_present_modes, config.present_mode);
 
         let extent3d = vk::Extent3D {
             width: extent.width,
@@ -45,19 +230,35 @@ impl EngineSwapchain {
             depth: 1,
         };
 
-        let queue_families = [queue_families.graphics_index.unwrap()];
+        let graphics_index = queue_families.graphics_index.unwrap();
+        let present_index = queue_families.present_index.unwrap();
+
+        let queue_families = [graphics_index];
+
+        // Rendered to by the graphics queue and presented by the present queue
+        let (image_sharing_mode, image_queue_families) = if graphics_index == present_index {
+            (vk::SharingMode::EXCLUSIVE, vec![graphics_index])
+        } else {
+            (vk::SharingMode::CONCURRENT, vec![graphics_index, present_index])
+        };
 
         // Depth image creation & allocation:
 
+        let depth_usage = if config.sampled_depth {
+            vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::SAMPLED
+        } else {
+            vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT
+        };
+
         let depth_image_info = vk::ImageCreateInfo::builder()
             .image_type(vk::ImageType::TYPE_2D)
-            .format(vk::Format::D32_SFLOAT)
+            .format(config.depth_format)
             .extent(extent3d)
             .mip_levels(1)
             .array_layers(1)
-            .samples(vk::SampleCountFlags::TYPE_1)
+            .samples(config.samples)
             .tiling(vk::ImageTiling::OPTIMAL)
-            .usage(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT)
+            .usage(depth_usage)
             .sharing_mode(vk::SharingMode::EXCLUSIVE)
             .queue_family_indices(&queue_families);
 
@@ -65,10 +266,10 @@ impl EngineSwapchain {
             &depth_image_info,
             gpu_allocator::MemoryLocation::GpuOnly,
             false,
-        ).unwrap();
+        )?;
 
         let subresource_range = vk::ImageSubresourceRange::builder()
-            .aspect_mask(vk::ImageAspectFlags::DEPTH)
+            .aspect_mask(depth_aspect_mask(config.depth_format))
             .base_mip_level(0)
             .level_count(1)
             .base_array_layer(0)
@@ -77,15 +278,97 @@ impl EngineSwapchain {
         let image_view_create_info = vk::ImageViewCreateInfo::builder()
             .image(depth_image)
             .view_type(vk::ImageViewType::TYPE_2D)
-            .format(vk::Format::D32_SFLOAT)
+            .format(config.depth_format)
             .subresource_range(*subresource_range);
 
         let depth_image_view = unsafe {
             device.create_image_view(&image_view_create_info, None)
         }?;
 
+        let (depth_sampler, depth_sampled_view) = if config.sampled_depth {
+            // Sampling may only read a single aspect, so shaders get a depth-only view
+            let subresource_range = vk::ImageSubresourceRange::builder()
+                .aspect_mask(vk::ImageAspectFlags::DEPTH)
+                .base_mip_level(0)
+                .level_count(1)
+                .base_array_layer(0)
+                .layer_count(1);
+
+            let image_view_create_info = vk::ImageViewCreateInfo::builder()
+                .image(depth_image)
+                .view_type(vk::ImageViewType::TYPE_2D)
+                .format(config.depth_format)
+                .subresource_range(*subresource_range);
+
+            let depth_sampled_view = unsafe {
+                device.create_image_view(&image_view_create_info, None)
+            }?;
+
+            let sampler_info = vk::SamplerCreateInfo::builder()
+                .mag_filter(vk::Filter::NEAREST)
+                .min_filter(vk::Filter::NEAREST)
+                .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE);
+
+            let depth_sampler = unsafe {
+                device.create_sampler(&sampler_info, None)
+            }?;
+
+            (Some(depth_sampler), Some(depth_sampled_view))
+        } else {
+            (None, None)
+        };
+
+        // Multisampled color image, resolved into the swapchain image at the end of the pass:
+
+        let (msaa_image, msaa_image_allocation, msaa_image_view) = if config.samples != vk::SampleCountFlags::TYPE_1 {
+            let msaa_image_info = vk::ImageCreateInfo::builder()
+                .image_type(vk::ImageType::TYPE_2D)
+                .format(format.format)
+                .extent(extent3d)
+                .mip_levels(1)
+                .array_layers(1)
+                .samples(config.samples)
+                .tiling(vk::ImageTiling::OPTIMAL)
+                .usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE)
+                .queue_family_indices(&queue_families);
+
+            let (msaa_image, msaa_allocation) = allocator.allocate_image(
+                &msaa_image_info,
+                gpu_allocator::MemoryLocation::GpuOnly,
+                false,
+            )?;
+
+            let subresource_range = vk::ImageSubresourceRange::builder()
+                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                .base_mip_level(0)
+                .level_count(1)
+                .base_array_layer(0)
+                .layer_count(1);
+
+            let image_view_create_info = vk::ImageViewCreateInfo::builder()
+                .image(msaa_image)
+                .view_type(vk::ImageViewType::TYPE_2D)
+                .format(format.format)
+                .subresource_range(*subresource_range);
+
+            let msaa_image_view = unsafe {
+                device.create_image_view(&image_view_create_info, None)
+            }?;
+
+            (Some(msaa_image), Some(msaa_allocation), Some(msaa_image_view))
+        } else {
+            (None, None, None)
+        };
+
         // Swapchain creation:
 
+        // Copying out of the images is what VulkanEngine::capture_frame needs
+        let image_usage = vk::ImageUsageFlags::COLOR_ATTACHMENT
+            | (surface_capabilities.supported_usage_flags & vk::ImageUsageFlags::TRANSFER_SRC);
+
         let swapchain_create_info = vk::SwapchainCreateInfoKHR::builder()
             .surface(surfaces.surface)
             .min_image_count(
@@ -96,12 +379,13 @@ impl EngineSwapchain {
             .image_color_space(format.color_space)
             .image_extent(extent)
             .image_array_layers(1)
-            .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT)
-            .image_sharing_mode(vk::SharingMode::EXCLUSIVE)
-            .queue_family_indices(&queue_families)
+            .image_usage(image_usage)
+            .image_sharing_mode(image_sharing_mode)
+            .queue_family_indices(&image_queue_families)
             .pre_transform(surface_capabilities.current_transform)
-            .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
-            .present_mode(vk::PresentModeKHR::FIFO);
+            .composite_alpha(Self::choose_composite_alpha(&surface_capabilities, config.transparent))
+            .present_mode(present_mode)
+            .old_swapchain(old_swapchain);
 
         let swapchain_loader = ash::extensions::khr::Swapchain::new(&instance, &device);
         let swapchain = unsafe { swapchain_loader.create_swapchain(&swapchain_create_info, None)? };
@@ -133,15 +417,42 @@ impl EngineSwapchain {
             swapchain_image_views.push(image_view);
         }
 
-        let mut image_available = vec![];
-        let mut rendering_finished = vec![];
-        let mut may_begin_drawing = vec![];
+        Ok(EngineSwapchain {
+            loader: swapchain_loader,
+            swapchain,
+            images: swapchain_images,
+            image_views: swapchain_image_views,
+            depth_image,
+            depth_extent: extent3d,
+            depth_image_allocation: Some(allocation),
+            depth_image_view,
+            depth_sampler,
+            depth_sampled_view,
+            msaa_image,
+            msaa_image_allocation,
+            msaa_image_view,
+            framebuffers: vec![],
+            surface_format: format,
+            image_usage,
+            present_mode,
+            extent,
+            amount_of_images,
+            config,
+            current_frame: 0,
+            image_available: vec![],
+            rendering_finished: vec![],
+            may_begin_drawing: vec![],
+            images_in_flight: vec![vk::Fence::null(); amount_of_images as usize],
+        })
+    }
 
+    // Sync objects limit how many frames the CPU may record ahead of the GPU
+    fn create_sync_objects(&mut self, device: &ash::Device) -> Result<(), vk::Result> {
         let semaphore_info = vk::SemaphoreCreateInfo::builder();
         let fence_info = vk::FenceCreateInfo::builder()
             .flags(vk::FenceCreateFlags::SIGNALED);
 
-        for _ in 0..amount_of_images {
+        for _ in 0..self.config.frames_in_flight.max(1) {
             let semaphore_available = unsafe {
                 device.create_semaphore(&semaphore_info, None)?
             };
@@ -150,33 +461,79 @@ impl EngineSwapchain {
                 device.create_semaphore(&semaphore_info, None)?
             };
 
-            image_available.push(semaphore_available);
-            rendering_finished.push(semaphore_finished);
+            self.image_available.push(semaphore_available);
+            self.rendering_finished.push(semaphore_finished);
 
             let fence = unsafe {
                 device.create_fence(&fence_info, None)?
             };
 
-            may_begin_drawing.push(fence);
+            self.may_begin_drawing.push(fence);
         }
 
-        Ok(EngineSwapchain {
-            loader: swapchain_loader,
-            swapchain,
-            images: swapchain_images,
-            image_views: swapchain_image_views,
-            depth_image,
-            depth_image_allocation: allocation,
-            depth_image_view,
-            framebuffers: vec![],
-            surface_format: format,
-            extent,
-            amount_of_images,
-            current_image: 0,
-            image_available,
-            rendering_finished,
-            may_begin_drawing
-        })
+        Ok(())
+    }
+
+    // current_extent of u32::MAX means the surface lets the swapchain pick its own size
+    pub fn choose_extent(
+        capabilities: &vk::SurfaceCapabilitiesKHR,
+        desired_extent: vk::Extent2D,
+    ) -> vk::Extent2D {
+        if capabilities.current_extent.width != u32::MAX {
+            return capabilities.current_extent;
+        }
+
+        vk::Extent2D {
+            width: desired_extent.width.clamp(
+                capabilities.min_image_extent.width,
+                capabilities.max_image_extent.width,
+            ),
+            height: desired_extent.height.clamp(
+                capabilities.min_image_extent.height,
+                capabilities.max_image_extent.height,
+            ),
+        }
+    }
+
+    // FIFO is the only mode every implementation has to support
+    pub fn choose_present_mode(
+        present_modes: &[vk::PresentModeKHR],
+        desired_mode: PresentMode,
+    ) -> vk::PresentModeKHR {
+        let desired_mode = desired_mode.to_vk();
+
+        if present_modes.contains(&desired_mode) {
+            desired_mode
+        } else {
+            vk::PresentModeKHR::FIFO
+        }
+    }
+
+    // Transparent swapchains prefer pre-multiplied alpha, which is what the blend state produces
+    pub fn choose_composite_alpha(
+        capabilities: &vk::SurfaceCapabilitiesKHR,
+        transparent: bool,
+    ) -> vk::CompositeAlphaFlagsKHR {
+        let preferred = if transparent {
+            [
+                vk::CompositeAlphaFlagsKHR::PRE_MULTIPLIED,
+                vk::CompositeAlphaFlagsKHR::POST_MULTIPLIED,
+                vk::CompositeAlphaFlagsKHR::INHERIT,
+                vk::CompositeAlphaFlagsKHR::OPAQUE,
+            ]
+        } else {
+            [
+                vk::CompositeAlphaFlagsKHR::OPAQUE,
+                vk::CompositeAlphaFlagsKHR::INHERIT,
+                vk::CompositeAlphaFlagsKHR::PRE_MULTIPLIED,
+                vk::CompositeAlphaFlagsKHR::POST_MULTIPLIED,
+            ]
+        };
+
+        preferred
+            .into_iter()
+            .find(|&mode| capabilities.supported_composite_alpha.contains(mode))
+            .unwrap_or(vk::CompositeAlphaFlagsKHR::OPAQUE)
     }
 
     pub fn create_framebuffers(
@@ -185,7 +542,11 @@ impl EngineSwapchain {
         render_pass: vk::RenderPass
     ) -> Result<(), vk::Result> {
         for image_view in &self.image_views {
-            let image_view = [*image_view, self.depth_image_view];
+            // Attachment order matches the render pass: [color, depth] or [msaa color, depth, resolve]
+            let image_view = match self.msaa_image_view {
+                Some(msaa_image_view) => vec![msaa_image_view, self.depth_image_view, *image_view],
+                None => vec![*image_view, self.depth_image_view],
+            };
 
             let framebuffer_info = vk::FramebufferCreateInfo::builder()
                 .render_pass(render_pass)
@@ -204,24 +565,73 @@ impl EngineSwapchain {
         Ok(())
     }
 
-    pub fn calculate_current_image(&mut self) {
-        self.current_image = (self.current_image + 1) % self.amount_of_images as usize;
+    // Only available when created with sampled_depth; the render pass leaves it in
+    // DEPTH_STENCIL_READ_ONLY_OPTIMAL for later passes to read
+    pub fn depth_texture(&self) -> Option<DepthTexture> {
+        match (self.depth_sampled_view, self.depth_sampler) {
+            (Some(image_view), Some(sampler)) => Some(DepthTexture { image_view, sampler }),
+            _ => None,
+        }
+    }
+
+    // Sync objects are indexed by frame, which is independent of the image index returned by
+    // acquire_next_image since the presentation engine may hand images out in any order
+    pub fn advance_frame(&mut self) {
+        self.current_frame = (self.current_frame + 1) % self.frames_in_flight();
+    }
+
+    pub fn frames_in_flight(&self) -> usize {
+        self.may_begin_drawing.len()
+    }
+
+    // Waits until no earlier frame is rendering to the acquired image (its command buffer is about to
+    // be re-recorded) and hands the image to the current frame. Call before resetting the frame's fence.
+    pub fn claim_image(&mut self, device: &ash::Device, image_index: usize) -> Result<(), vk::Result> {
+        let image_fence = self.images_in_flight[image_index];
+
+        if image_fence != vk::Fence::null() {
+            unsafe {
+                device.wait_for_fences(&[image_fence], true, u64::MAX)?;
+            }
+        }
+
+        self.images_in_flight[image_index] = self.may_begin_drawing[self.current_frame];
+
+        Ok(())
+    }
+
+    pub unsafe fn cleanup(&mut self, device: &ash::Device, allocator: &mut VkAllocator) {
+        self.cleanup_images(device, allocator);
+        self.cleanup_sync_objects(device);
+
+        self.loader.destroy_swapchain(self.swapchain, None)
     }
 
-    pub unsafe fn cleanup(&mut self, device: &ash::Device) {
+    // The depth and multisampled images are sized to the swapchain extent, so they're freed
+    // along with it and created again by create
+    unsafe fn cleanup_images(&mut self, device: &ash::Device, allocator: &mut VkAllocator) {
         device.destroy_image_view(self.depth_image_view, None);
-        device.destroy_image(self.depth_image, None);
 
-        for fence in &self.may_begin_drawing {
-            device.destroy_fence(*fence, None);
+        if let Some(allocation) = self.depth_image_allocation.take() {
+            let destroyer = |device: &ash::Device| device.destroy_image(self.depth_image, None);
+            allocator.free(allocation, &destroyer);
         }
 
-        for semaphore in &self.image_available {
-            device.destroy_semaphore(*semaphore, None);
+        if let Some(sampler) = self.depth_sampler {
+            device.destroy_sampler(sampler, None);
         }
 
-        for semaphore in &self.rendering_finished {
-            device.destroy_semaphore(*semaphore, None);
+        if let Some(image_view) = self.depth_sampled_view {
+            device.destroy_image_view(image_view, None);
+        }
+
+        if let Some(image_view) = self.msaa_image_view {
+            device.destroy_image_view(image_view, None);
+        }
+
+        if let (Some(image), Some(allocation)) = (self.msaa_image, self.msaa_image_allocation.take()) {
+            let destroyer = |device: &ash::Device| device.destroy_image(image, None);
+            allocator.free(allocation, &destroyer);
         }
 
         for fb in &self.framebuffers {
@@ -231,7 +641,42 @@ impl EngineSwapchain {
         for iv in &self.image_views {
             device.destroy_image_view(*iv, None);
         }
+    }
 
-        self.loader.destroy_swapchain(self.swapchain, None)
+    unsafe fn cleanup_sync_objects(&mut self, device: &ash::Device) {
+        for fence in &self.may_begin_drawing {
+            device.destroy_fence(*fence, None);
+        }
+
+        for semaphore in &self.image_available {
+            device.destroy_semaphore(*semaphore, None);
+        }
+
+        for semaphore in &self.rendering_finished {
+            device.destroy_semaphore(*semaphore, None);
+        }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn present_ids_increase_every_frame() {
+        let mut present_ids = PresentIds::new(true);
+        assert_eq!(present_ids.last(), None);
+
+        for frame in 1..=3 {
+            assert_eq!(present_ids.next(), Some(frame));
+            assert_eq!(present_ids.last(), Some(frame));
+        }
+    }
+
+    #[test]
+    fn present_ids_disabled() {
+        let mut present_ids = PresentIds::new(false);
+
+        assert_eq!(present_ids.next(), None);
+        assert_eq!(present_ids.last(), None);
+    }
+}
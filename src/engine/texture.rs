@@ -1,80 +1,630 @@
 use ash::vk;
 use gpu_allocator::vulkan::{Allocation, AllocationCreateDesc, Allocator};
 use crate::engine::allocator::VkAllocator;
-use crate::engine::buffer::EngineBuffer;
+use crate::engine::buffer::{self, EngineBuffer, UploadQueues};
+use crate::engine::error::EngineError;
 
+#[derive(Debug)]
+pub struct InvalidTextureData {
+    pub expected_len: usize,
+    pub actual_len: usize,
+}
+
+impl std::fmt::Display for InvalidTextureData {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.expected_len == 0 {
+            write!(f, "textures can't have a width or height of 0")
+        } else {
+            write!(f, "expected {} bytes of RGBA8 data, got {}", self.expected_len, self.actual_len)
+        }
+    }
+}
+impl std::error::Error for InvalidTextureData {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+// Length of the texture array the textured pipeline samples from
+pub const DEFAULT_MAX_TEXTURES: u32 = 16;
+
+// Defaults to trilinear filtering with repeating address modes.
+// max_anisotropy is expected to be clamped already, see VulkanEngine::sampler_anisotropy
+#[derive(Copy, Clone, Debug)]
+pub struct SamplerConfig {
+    pub filter: vk::Filter,
+    pub mipmap_mode: vk::SamplerMipmapMode,
+    pub address_mode_u: vk::SamplerAddressMode,
+    pub address_mode_v: vk::SamplerAddressMode,
+    pub address_mode_w: vk::SamplerAddressMode,
+    pub border_color: vk::BorderColor,
+    pub max_anisotropy: f32,
+}
+
+impl Default for SamplerConfig {
+    fn default() -> Self {
+        SamplerConfig {
+            filter: vk::Filter::LINEAR,
+            mipmap_mode: vk::SamplerMipmapMode::LINEAR,
+            address_mode_u: vk::SamplerAddressMode::REPEAT,
+            address_mode_v: vk::SamplerAddressMode::REPEAT,
+            address_mode_w: vk::SamplerAddressMode::REPEAT,
+            border_color: vk::BorderColor::FLOAT_TRANSPARENT_BLACK,
+            max_anisotropy: 1.0,
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl SamplerConfig {
+    // Clamp to edge so UI textures don't bleed their opposite border into the edges
+    pub fn clamp_to_edge() -> Self {
+        SamplerConfig {
+            address_mode_u: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+            address_mode_v: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+            address_mode_w: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+            ..Self::default()
+        }
+    }
+
+    // Nearest-neighbor for pixel art
+    pub fn nearest() -> Self {
+        SamplerConfig {
+            filter: vk::Filter::NEAREST,
+            mipmap_mode: vk::SamplerMipmapMode::NEAREST,
+            ..Self::default()
+        }
+    }
+}
+
+// Cubemaps hold their six faces as layers, in the order +X, -X, +Y, -Y, +Z, -Z
 pub struct Texture {
-    pub image: image::RgbaImage,
+    pub layers: Vec<image::RgbaImage>,
     pub width: u32,
     pub height: u32,
     pub vk_image: vk::Image,
     pub image_view: vk::ImageView,
-    pub allocation: Allocation,
+    pub allocation: Option<Allocation>,
     pub sampler: vk::Sampler,
 }
 
+#[allow(dead_code)]
 impl Texture {
     pub fn from_file<P: AsRef<std::path::Path>>(
         path: P,
+        sampler_config: SamplerConfig,
+        device: &ash::Device,
+        allocator: &mut VkAllocator,
+        upload_queues: &UploadQueues,
+    ) -> Result<Self, EngineError> {
+        let image = image::open(path)?.to_rgba8();
+
+        Self::from_layers(
+            vec![image],
+            vk::ImageViewType::TYPE_2D,
+            vk::Format::R8G8B8A8_SRGB,
+            sampler_config,
+            device,
+            allocator,
+            upload_queues,
+        )
+    }
+
+    // All faces have to be square and of the same size
+    pub fn cubemap_from_files<P: AsRef<std::path::Path>>(
+        paths: [P; 6],
+        sampler_config: SamplerConfig,
         device: &ash::Device,
-        allocator: &mut VkAllocator
-    ) -> Self {
-        let image = image::open(path)
-            .expect("Failed to open image")
-            .to_rgba8();
+        allocator: &mut VkAllocator,
+        upload_queues: &UploadQueues,
+    ) -> Result<Self, EngineError> {
+        let mut faces = Vec::with_capacity(6);
 
-        let (width, height) = image.dimensions();
+        for path in paths {
+            faces.push(image::open(path)?.to_rgba8());
+        }
+
+        let (size, _) = faces[0].dimensions();
+        let expected_len = size as usize * size as usize * 4;
+
+        if let Some(face) = faces.iter().find(|face| face.dimensions() != (size, size)) {
+            return Err(EngineError::InvalidTextureData(InvalidTextureData {
+                expected_len,
+                actual_len: face.as_raw().len(),
+            }));
+        }
+
+        Self::from_layers(
+            faces,
+            vk::ImageViewType::CUBE,
+            vk::Format::R8G8B8A8_SRGB,
+            sampler_config,
+            device,
+            allocator,
+            upload_queues,
+        )
+    }
+
+    pub fn from_rgba(
+        data: &[u8],
+        width: u32,
+        height: u32,
+        sampler_config: SamplerConfig,
+        device: &ash::Device,
+        allocator: &mut VkAllocator,
+        upload_queues: &UploadQueues,
+    ) -> Result<Self, EngineError> {
+        let expected_len = width as usize * height as usize * 4;
+
+        // Vulkan doesn't allow images without any texels, so an empty texture is rejected too
+        if expected_len == 0 || data.len() != expected_len {
+            return Err(EngineError::InvalidTextureData(InvalidTextureData {
+                expected_len,
+                actual_len: data.len(),
+            }));
+        }
+
+        let image = image::RgbaImage::from_raw(width, height, data.to_vec())
+            .ok_or(InvalidTextureData {
+                expected_len,
+                actual_len: data.len(),
+            })?;
+
+        Self::from_layers(
+            vec![image],
+            vk::ImageViewType::TYPE_2D,
+            vk::Format::R8G8B8A8_SRGB,
+            sampler_config,
+            device,
+            allocator,
+            upload_queues,
+        )
+    }
+
+    // Normal maps hold directions rather than colors, so they are read without the sRGB decoding
+    pub fn normal_map_from_file<P: AsRef<std::path::Path>>(
+        path: P,
+        sampler_config: SamplerConfig,
+        device: &ash::Device,
+        allocator: &mut VkAllocator,
+        upload_queues: &UploadQueues,
+    ) -> Result<Self, EngineError> {
+        let image = image::open(path)?.to_rgba8();
+
+        Self::from_layers(
+            vec![image],
+            vk::ImageViewType::TYPE_2D,
+            vk::Format::R8G8B8A8_UNORM,
+            sampler_config,
+            device,
+            allocator,
+            upload_queues,
+        )
+    }
+
+    // A 1x1 normal map pointing straight out of the surface, for models without one
+    pub fn flat_normal_map(
+        device: &ash::Device,
+        allocator: &mut VkAllocator,
+        upload_queues: &UploadQueues,
+    ) -> Result<Self, EngineError> {
+        let image = image::RgbaImage::from_pixel(1, 1, image::Rgba([128, 128, 255, 255]));
+
+        Self::from_layers(
+            vec![image],
+            vk::ImageViewType::TYPE_2D,
+            vk::Format::R8G8B8A8_UNORM,
+            SamplerConfig::default(),
+            device,
+            allocator,
+            upload_queues,
+        )
+    }
+
+    fn from_layers(
+        layers: Vec<image::RgbaImage>,
+        view_type: vk::ImageViewType,
+        format: vk::Format,
+        sampler_config: SamplerConfig,
+        device: &ash::Device,
+        allocator: &mut VkAllocator,
+        upload_queues: &UploadQueues,
+    ) -> Result<Self, EngineError> {
+        let (width, height) = layers[0].dimensions();
+        let layer_count = layers.len() as u32;
+
+        let mip_levels = Self::mip_levels(width, height);
+
+        let flags = if view_type == vk::ImageViewType::CUBE {
+            vk::ImageCreateFlags::CUBE_COMPATIBLE
+        } else {
+            vk::ImageCreateFlags::empty()
+        };
 
         let image_create_info = vk::ImageCreateInfo::builder()
+            .flags(flags)
             .image_type(vk::ImageType::TYPE_2D)
             .extent(vk::Extent3D {
                 width,
                 height,
                 depth: 1,
             })
-            .mip_levels(1)
-            .array_layers(1)
-            .format(vk::Format::R8G8B8A8_SRGB)
+            .mip_levels(mip_levels)
+            .array_layers(layer_count)
+            .format(format)
             .samples(vk::SampleCountFlags::TYPE_1)
-            .usage(vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED);
+            .usage(
+                vk::ImageUsageFlags::TRANSFER_SRC
+                    | vk::ImageUsageFlags::TRANSFER_DST
+                    | vk::ImageUsageFlags::SAMPLED
+            );
 
         let (vk_image, allocation) = allocator.allocate_image(
             &image_create_info,
             gpu_allocator::MemoryLocation::GpuOnly,
             false
-        ).unwrap();
+        )?;
 
         let image_view_create_info = vk::ImageViewCreateInfo::builder()
             .image(vk_image)
-            .view_type(vk::ImageViewType::TYPE_2D)
-            .format(vk::Format::R8G8B8A8_SRGB)
+            .view_type(view_type)
+            .format(format)
             .subresource_range(vk::ImageSubresourceRange {
                 aspect_mask: vk::ImageAspectFlags::COLOR,
-                level_count: 1,
-                layer_count: 1,
+                level_count: mip_levels,
+                layer_count,
                 ..Default::default()
             });
 
         let image_view = unsafe {
             device.create_image_view(&image_view_create_info, None)
-        }.unwrap();
+        }?;
+
+        Self::upload_image(device, allocator, upload_queues, &layers, vk_image, mip_levels)?;
 
         let sampler_info = vk::SamplerCreateInfo::builder()
-            .mag_filter(vk::Filter::LINEAR)
-            .min_filter(vk::Filter::LINEAR);
+            .mag_filter(sampler_config.filter)
+            .min_filter(sampler_config.filter)
+            .mipmap_mode(sampler_config.mipmap_mode)
+            .address_mode_u(sampler_config.address_mode_u)
+            .address_mode_v(sampler_config.address_mode_v)
+            .address_mode_w(sampler_config.address_mode_w)
+            .border_color(sampler_config.border_color)
+            .anisotropy_enable(sampler_config.max_anisotropy > 1.0)
+            .max_anisotropy(sampler_config.max_anisotropy)
+            .min_lod(0.0)
+            .max_lod(mip_levels as f32);
 
         let sampler = unsafe {
             device.create_sampler(&sampler_info, None)
-        }.unwrap();
+        }?;
 
-        Texture {
-            image,
+        Ok(Texture {
+            layers,
             width,
             height,
             vk_image,
             image_view,
-            allocation,
+            allocation: Some(allocation),
             sampler,
+        })
+    }
+
+    pub unsafe fn cleanup(
+        &mut self,
+        allocator: &mut VkAllocator,
+        device: &ash::Device,
+    ) {
+        device.destroy_sampler(self.sampler, None);
+        device.destroy_image_view(self.image_view, None);
+
+        let destroyer = |device: &ash::Device| device.destroy_image(self.vk_image, None);
+        allocator.free(self.allocation.take().unwrap(), &destroyer);
+    }
+
+    // Copies the CPU side image to the GPU again, e.g. after its pixels were changed
+    pub fn upload(
+        &self,
+        device: &ash::Device,
+        allocator: &mut VkAllocator,
+        upload_queues: &UploadQueues,
+    ) -> Result<(), EngineError> {
+        Self::upload_image(
+            device,
+            allocator,
+            upload_queues,
+            &self.layers,
+            self.vk_image,
+            Self::mip_levels(self.width, self.height)
+        )
+    }
+
+    fn mip_levels(width: u32, height: u32) -> u32 {
+        32 - width.max(height).max(1).leading_zeros()
+    }
+
+    // Copies the layers into the base level through a staging buffer on the transfer queue,
+    // the remaining levels are generated on the graphics queue
+    fn upload_image(
+        device: &ash::Device,
+        allocator: &mut VkAllocator,
+        upload_queues: &UploadQueues,
+        layers: &[image::RgbaImage],
+        vk_image: vk::Image,
+        mip_levels: u32,
+    ) -> Result<(), EngineError> {
+        let (width, height) = layers[0].dimensions();
+        let layer_count = layers.len() as u32;
+        let layer_size = layers[0].as_raw().len();
+
+        let data: Vec<u8> = layers
+            .iter()
+            .flat_map(|layer| layer.as_raw().iter().copied())
+            .collect();
+
+        let mut buffer = EngineBuffer::new(
+            allocator,
+            data.len() as u64,
+            vk::BufferUsageFlags::TRANSFER_SRC,
+            gpu_allocator::MemoryLocation::CpuToGpu,
+        )?;
+
+        buffer.fill(allocator, &data)?;
+
+        let subresource_range = |base_mip_level: u32, level_count: u32| vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level,
+            level_count,
+            base_array_layer: 0,
+            layer_count,
+        };
+
+        let subresource_layers = |mip_level: u32, base_array_layer: u32, layer_count: u32| vk::ImageSubresourceLayers {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            mip_level,
+            base_array_layer,
+            layer_count,
+        };
+
+        let barrier = vk::ImageMemoryBarrier::builder()
+            .image(vk_image)
+            .src_access_mask(vk::AccessFlags::empty())
+            .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .old_layout(vk::ImageLayout::UNDEFINED)
+            .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .subresource_range(subresource_range(0, mip_levels))
+            .build();
+
+        let regions: Vec<vk::BufferImageCopy> = (0..layer_count)
+            .map(|layer| vk::BufferImageCopy {
+                buffer_offset: layer as u64 * layer_size as u64,
+                buffer_row_length: 0,
+                buffer_image_height: 0,
+                image_offset: vk::Offset3D { x: 0, y: 0, z: 0},
+                image_extent: vk::Extent3D {
+                    width,
+                    height,
+                    depth: 1
+                },
+                image_subresource: subresource_layers(0, layer, 1),
+            })
+            .collect();
+
+        // The layout stays the same, only the queue family owning the image changes
+        let ownership_barrier = vk::ImageMemoryBarrier::builder()
+            .image(vk_image)
+            .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .src_queue_family_index(upload_queues.transfer_family)
+            .dst_queue_family_index(upload_queues.graphics_family)
+            .subresource_range(subresource_range(0, mip_levels))
+            .build();
+
+        // The base level is copied on the transfer queue
+        let result = buffer::submit_once(
+            device,
+            upload_queues.transfer_pool,
+            upload_queues.transfer_queue,
+            |command_buffer| unsafe {
+                device.cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::TOP_OF_PIPE,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    &[barrier],
+                );
+
+                device.cmd_copy_buffer_to_image(
+                    command_buffer,
+                    buffer.buffer,
+                    vk_image,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &regions,
+                );
+
+                if upload_queues.needs_ownership_transfer() {
+                    let release = vk::ImageMemoryBarrier {
+                        src_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+                        ..ownership_barrier
+                    };
+
+                    device.cmd_pipeline_barrier(
+                        command_buffer,
+                        vk::PipelineStageFlags::TRANSFER,
+                        vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                        vk::DependencyFlags::empty(),
+                        &[],
+                        &[],
+                        &[release],
+                    );
+                }
+            },
+        );
+
+        unsafe {
+            buffer.cleanup(allocator);
+        }
+
+        result?;
+
+        // Blits need a graphics queue, so the mip chain is generated there
+        buffer::submit_once(
+            device,
+            upload_queues.graphics_pool,
+            upload_queues.graphics_queue,
+            |command_buffer| unsafe {
+                if upload_queues.needs_ownership_transfer() {
+                    let acquire = vk::ImageMemoryBarrier {
+                        dst_access_mask: vk::AccessFlags::TRANSFER_READ | vk::AccessFlags::TRANSFER_WRITE,
+                        ..ownership_barrier
+                    };
+
+                    device.cmd_pipeline_barrier(
+                        command_buffer,
+                        vk::PipelineStageFlags::TOP_OF_PIPE,
+                        vk::PipelineStageFlags::TRANSFER,
+                        vk::DependencyFlags::empty(),
+                        &[],
+                        &[],
+                        &[acquire],
+                    );
+                }
+
+                Self::record_mipmaps(device, command_buffer, vk_image, width, height, layer_count, mip_levels);
+            },
+        )?;
+
+        Ok(())
+    }
+
+    // Fills the levels after the base one by blitting each level into the next, halving the
+    // size every time, and leaves every level ready for sampling
+    #[allow(clippy::too_many_arguments)]
+    fn record_mipmaps(
+        device: &ash::Device,
+        command_buffer: vk::CommandBuffer,
+        vk_image: vk::Image,
+        width: u32,
+        height: u32,
+        layer_count: u32,
+        mip_levels: u32,
+    ) {
+        let subresource_range = |base_mip_level: u32, level_count: u32| vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level,
+            level_count,
+            base_array_layer: 0,
+            layer_count,
+        };
+
+        let subresource_layers = |mip_level: u32| vk::ImageSubresourceLayers {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            mip_level,
+            base_array_layer: 0,
+            layer_count,
+        };
+
+        let mut mip_width = width as i32;
+        let mut mip_height = height as i32;
+
+        for level in 1..mip_levels {
+            // The previous level is done being written to, it becomes the blit source
+            let barrier = vk::ImageMemoryBarrier::builder()
+                .image(vk_image)
+                .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+                .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                .subresource_range(subresource_range(level - 1, 1))
+                .build();
+
+            unsafe {
+                device.cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    &[barrier],
+                );
+            }
+
+            let next_width = (mip_width / 2).max(1);
+            let next_height = (mip_height / 2).max(1);
+
+            let blit = vk::ImageBlit {
+                src_subresource: subresource_layers(level - 1),
+                src_offsets: [
+                    vk::Offset3D { x: 0, y: 0, z: 0 },
+                    vk::Offset3D { x: mip_width, y: mip_height, z: 1 },
+                ],
+                dst_subresource: subresource_layers(level),
+                dst_offsets: [
+                    vk::Offset3D { x: 0, y: 0, z: 0 },
+                    vk::Offset3D { x: next_width, y: next_height, z: 1 },
+                ],
+            };
+
+            unsafe {
+                device.cmd_blit_image(
+                    command_buffer,
+                    vk_image,
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    vk_image,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &[blit],
+                    vk::Filter::LINEAR,
+                );
+            }
+
+            let barrier = vk::ImageMemoryBarrier::builder()
+                .image(vk_image)
+                .src_access_mask(vk::AccessFlags::TRANSFER_READ)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ)
+                .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .subresource_range(subresource_range(level - 1, 1))
+                .build();
+
+            unsafe {
+                device.cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::FRAGMENT_SHADER,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    &[barrier],
+                );
+            }
+
+            mip_width = next_width;
+            mip_height = next_height;
+        }
+
+        // The last level is never blitted from, so it is still in the transfer destination layout
+        let barrier = vk::ImageMemoryBarrier::builder()
+            .image(vk_image)
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+            .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .subresource_range(subresource_range(mip_levels - 1, 1))
+            .build();
+
+        unsafe {
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[barrier],
+            );
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,170 @@
+use std::time::{Duration, Instant};
+
+use ash::vk;
+use crate::engine::buffer::submit_once;
+use crate::engine::queue_families::QueueFamilies;
+
+// Weight of the newest frame in the smoothed fps, lower values react slower but jitter less
+const FPS_SMOOTHING: f32 = 0.1;
+
+pub struct FrameTimer {
+    last_frame: Instant,
+    delta: Duration,
+    smoothed_fps: f32,
+    frames: u64,
+}
+
+impl Default for FrameTimer {
+    fn default() -> Self {
+        FrameTimer::new()
+    }
+}
+
+#[allow(dead_code)]
+impl FrameTimer {
+    pub fn new() -> FrameTimer {
+        FrameTimer {
+            last_frame: Instant::now(),
+            delta: Duration::ZERO,
+            smoothed_fps: 0.0,
+            frames: 0,
+        }
+    }
+
+    // Called once per frame, the time since the previous call becomes the frame's delta
+    pub fn tick(&mut self) {
+        let now = Instant::now();
+
+        self.delta = now - self.last_frame;
+        self.last_frame = now;
+        self.frames += 1;
+
+        let delta_seconds = self.delta.as_secs_f32();
+
+        if delta_seconds > 0.0 {
+            let fps = 1.0 / delta_seconds;
+
+            self.smoothed_fps = if self.smoothed_fps == 0.0 {
+                fps
+            } else {
+                self.smoothed_fps + (fps - self.smoothed_fps) * FPS_SMOOTHING
+            };
+        }
+    }
+
+    pub fn delta_seconds(&self) -> f32 {
+        self.delta.as_secs_f32()
+    }
+
+    pub fn fps(&self) -> f32 {
+        self.smoothed_fps
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+}
+
+// Timestamps written before and after each command buffer's render pass, two queries per
+// command buffer. A command buffer's results are read right before it is recorded again,
+// when its previous submission is known to have finished.
+pub struct GpuTimer {
+    pub query_pool: vk::QueryPool,
+    timestamp_period: f32,
+    last_frame_ms: Option<f32>,
+}
+
+#[allow(dead_code)]
+impl GpuTimer {
+    // None if the graphics queue doesn't support timestamps
+    pub fn init(
+        device: &ash::Device,
+        queue_families: &QueueFamilies,
+        limits: &vk::PhysicalDeviceLimits,
+        command_pool: vk::CommandPool,
+        queue: vk::Queue,
+        command_buffer_count: usize,
+    ) -> Result<Option<GpuTimer>, vk::Result> {
+        let timestamp_valid_bits = queue_families.graphics_index
+            .and_then(|index| queue_families.properties(index))
+            .map_or(0, |properties| properties.timestamp_valid_bits);
+
+        if timestamp_valid_bits == 0 || limits.timestamp_period == 0.0 {
+            return Ok(None);
+        }
+
+        let query_count = 2 * command_buffer_count as u32;
+
+        let query_pool_info = vk::QueryPoolCreateInfo::builder()
+            .query_type(vk::QueryType::TIMESTAMP)
+            .query_count(query_count);
+
+        let query_pool = unsafe {
+            device.create_query_pool(&query_pool_info, None)
+        }?;
+
+        // Queries start out undefined, after a reset unwritten ones are just not ready
+        submit_once(device, command_pool, queue, |command_buffer| unsafe {
+            device.cmd_reset_query_pool(command_buffer, query_pool, 0, query_count);
+        })?;
+
+        Ok(Some(GpuTimer {
+            query_pool,
+            timestamp_period: limits.timestamp_period,
+            last_frame_ms: None,
+        }))
+    }
+
+    // Reads the previous results of this command buffer's queries and resets them, recorded
+    // before the render pass begins
+    pub fn begin(&mut self, device: &ash::Device, command_buffer: vk::CommandBuffer, index: usize) {
+        let first_query = 2 * index as u32;
+        let mut timestamps = [0u64; 2];
+
+        let result = unsafe {
+            device.get_query_pool_results(
+                self.query_pool,
+                first_query,
+                2,
+                &mut timestamps,
+                vk::QueryResultFlags::TYPE_64,
+            )
+        };
+
+        if result.is_ok() {
+            let ticks = timestamps[1].wrapping_sub(timestamps[0]);
+            self.last_frame_ms = Some(ticks as f32 * self.timestamp_period / 1_000_000.0);
+        }
+
+        unsafe {
+            device.cmd_reset_query_pool(command_buffer, self.query_pool, first_query, 2);
+
+            device.cmd_write_timestamp(
+                command_buffer,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                self.query_pool,
+                first_query,
+            );
+        }
+    }
+
+    // Recorded after the render pass ends
+    pub fn end(&self, device: &ash::Device, command_buffer: vk::CommandBuffer, index: usize) {
+        unsafe {
+            device.cmd_write_timestamp(
+                command_buffer,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                self.query_pool,
+                2 * index as u32 + 1,
+            );
+        }
+    }
+
+    pub fn last_frame_ms(&self) -> Option<f32> {
+        self.last_frame_ms
+    }
+
+    pub unsafe fn cleanup(&self, device: &ash::Device) {
+        device.destroy_query_pool(self.query_pool, None);
+    }
+}
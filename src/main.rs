@@ -1,195 +1,94 @@
 mod engine;
 
-use ash::vk;
-
-use winit::event::{Event, WindowEvent};
+use winit::event::{Event, MouseButton, VirtualKeyCode, WindowEvent};
 use winit::event_loop::EventLoop;
-use winit::window::Window;
+use winit::window::WindowBuilder;
 
 use crate::engine::camera::Camera;
+use crate::engine::input::Input;
 use crate::engine::model::{InstanceData, Model, TexturedInstanceData};
 use crate::engine::VulkanEngine;
 use crate::engine::light::{DirectionalLight, LightManager, PointLight};
 
 use nalgebra as na;
-use crate::engine::buffer::EngineBuffer;
-use crate::engine::texture::Texture;
+use crate::engine::texture::{SamplerConfig, Texture};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let event_loop = EventLoop::new();
-    let window = Window::new(&event_loop)?;
-
-    let mut engine = VulkanEngine::init(window)?;
-
-    let texture = Texture::from_file("assets/Picture.png", &engine.device, &mut engine.allocator);
-
-    let mut model = Model::quad();
+    let window = WindowBuilder::new()
+        .with_transparent(true)
+        .build(&event_loop)?;
+
+    let mut engine = VulkanEngine::builder()
+        .wireframe(true)
+        .sampler_anisotropy(true)
+        .build(window)?;
+    let upload_queues = engine.upload_queues();
+
+    let texture = Texture::from_file(
+        "assets/Picture.png",
+        SamplerConfig {
+            max_anisotropy: engine.sampler_anisotropy(16.0),
+            ..SamplerConfig::default()
+        },
+        &engine.device,
+        &mut engine.allocator,
+        &upload_queues,
+    )?;
 
     let aspect = texture.width as f32 / texture.height as f32;
 
-    model.insert_visibly(TexturedInstanceData::from_matrix(
-        na::Matrix4::new_translation(&na::Vector3::new(0.0, 0.0, 0.0))
-            * na::Matrix4::new_nonuniform_scaling(&na::Vector3::new(1.0 * aspect, 1.0, 1.0))
-    ));
+    let mut model = Model::quad_sized(2.0 * aspect, 2.0);
+
+    model.insert_visibly(TexturedInstanceData::from_matrix(na::Matrix4::identity()));
 
     model.update_vertex_buffer(&mut engine.allocator).unwrap();
     model.update_index_buffer(&mut engine.allocator).unwrap();
     model.update_instance_buffer( &mut engine.allocator).unwrap();
 
-    let models = vec![model];
-    engine.models = models;
+    engine.add_model(model);
 
-    let mut camera = Camera::builder()
-        .position(na::Vector3::new(0.0, 0.0, -5.0))
-        .build();
+    // Drawn once with its matrix pushed as a constant, it has no instances
+    let mut sphere = Model::sphere(3);
+    sphere.push_constant_matrix = Some(
+        na::Matrix4::new_translation(&na::Vector3::new(2.0 * aspect + 1.0, 0.0, 0.0))
+            * na::Matrix4::new_scaling(0.5)
+    );
 
-    // Maybe create an associated function for that
-    let data = texture.image.clone().into_raw();
+    sphere.update_vertex_buffer(&mut engine.allocator).unwrap();
+    sphere.update_index_buffer(&mut engine.allocator).unwrap();
 
-    let mut buffer = EngineBuffer::new(
-        &mut engine.allocator,
-        data.len() as u64,
-        vk::BufferUsageFlags::TRANSFER_SRC,
-        gpu_allocator::MemoryLocation::CpuToGpu,
-    )?;
-
-    buffer.fill(&mut engine.allocator, &data);
-    // ^
-
-    let command_buffer_allocate_info = vk::CommandBufferAllocateInfo::builder()
-        .command_pool(engine.pools.command_pool_graphics)
-        .command_buffer_count(1);
-
-    let copy_command_buffer = unsafe {
-        engine.device.allocate_command_buffers(&command_buffer_allocate_info)
-    }.unwrap()[0];
-
-    let cmd_begin_info = vk::CommandBufferBeginInfo::builder()
-        .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
-
-    unsafe {
-        engine.device.begin_command_buffer(copy_command_buffer, &cmd_begin_info)
-    }?;
-
-    let barrier = vk::ImageMemoryBarrier::builder()
-        .image(texture.vk_image)
-        .src_access_mask(vk::AccessFlags::empty())
-        .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
-        .old_layout(vk::ImageLayout::UNDEFINED)
-        .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
-        .subresource_range(vk::ImageSubresourceRange {
-            aspect_mask: vk::ImageAspectFlags::COLOR,
-            base_mip_level: 0,
-            level_count: 1,
-            base_array_layer: 0,
-            layer_count: 1,
-        })
-        .build();
+    engine.add_lit_model(sphere);
 
-    unsafe {
-        engine.device.cmd_pipeline_barrier(
-            copy_command_buffer,
-            vk::PipelineStageFlags::TOP_OF_PIPE,
-            vk::PipelineStageFlags::TRANSFER,
-            vk::DependencyFlags::empty(),
-            &[],
-            &[],
-            &[barrier],
-        );
-    }
+    engine.lights.add_light(DirectionalLight {
+        direction: na::Vector3::new(-1.0, 1.0, 1.0),
+        illuminance: [3.0, 3.0, 3.0],
+    })?;
+    engine.lights.add_light(PointLight {
+        position: na::Point3::new(2.0 * aspect + 1.0, -1.5, -1.5),
+        luminous_flux: [100.0, 60.0, 30.0],
+        range: Some(10.0),
+    })?;
+    // Uploads the lights added above along with the ambient light
+    engine.set_ambient([0.6, 0.7, 1.0], 0.05)?;
 
-    let image_subresource = vk::ImageSubresourceLayers {
-        aspect_mask: vk::ImageAspectFlags::COLOR,
-        mip_level: 0,
-        base_array_layer: 0,
-        layer_count: 1
-    };
-
-    let region = vk::BufferImageCopy {
-        buffer_offset: 0,
-        buffer_row_length: 0,
-        buffer_image_height: 0,
-        image_offset: vk::Offset3D { x: 0, y: 0, z: 0},
-        image_extent: vk::Extent3D {
-            width: texture.width,
-            height: texture.height,
-            depth: 1
-        },
-        image_subresource,
-        ..Default::default()
-    };
-
-    unsafe {
-        engine.device.cmd_copy_buffer_to_image(
-            copy_command_buffer,
-            buffer.buffer,
-            texture.vk_image,
-            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-            &[region],
-        );
-    }
+    engine.write_texture_descriptors(0, texture.image_view, texture.sampler)?;
+    engine.textures.push(texture);
 
-    let barrier = vk::ImageMemoryBarrier::builder()
-        .image(texture.vk_image)
-        .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
-        .dst_access_mask(vk::AccessFlags::SHADER_READ)
-        .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
-        .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
-        .subresource_range(vk::ImageSubresourceRange {
-            aspect_mask: vk::ImageAspectFlags::COLOR,
-            base_mip_level: 0,
-            level_count: 1,
-            base_array_layer: 0,
-            layer_count: 1,
-        })
+    let mut camera = Camera::builder()
+        .look_at(na::Point3::new(0.0, 0.0, -5.0), na::Point3::origin(), na::Vector3::new(0.0, -1.0, 0.0))
         .build();
-    unsafe {
-        engine.device.cmd_pipeline_barrier(
-            copy_command_buffer,
-            vk::PipelineStageFlags::TRANSFER,
-            vk::PipelineStageFlags::FRAGMENT_SHADER,
-            vk::DependencyFlags::empty(),
-            &[],
-            &[],
-            &[barrier],
-        )
-    };
-
-    unsafe {
-        engine.device.end_command_buffer(copy_command_buffer)
-    }?;
-
-    let submit_infos = [
-        vk::SubmitInfo::builder()
-            .command_buffers(&[copy_command_buffer])
-            .build()
-    ];
-
-    let fence = unsafe {
-        engine.device.create_fence(&vk::FenceCreateInfo::default(), None)
-    }?;
-
-    unsafe {
-        engine.device.queue_submit(engine.queues.graphics, &submit_infos, fence)
-    }?;
-
-    unsafe {
-        engine.device.wait_for_fences(&[fence], true, u64::MAX)
-    }?;
-
-    unsafe {
-        engine.device.destroy_fence(fence, None)
-    };
-
-    unsafe {
-        buffer.cleanup(&mut engine.allocator)
-    };
-
-    unsafe {
-        engine.device.free_command_buffers(engine.pools.command_pool_graphics, &[copy_command_buffer])
-    };
+
+    // Shaders compiled with e.g. `glslc shaders/shader_textured.frag -o shaders/shader_textured.frag.spv`
+    // replace the built-in ones and are reloaded whenever either file changes
+    let shader_paths = ["shaders/shader_textured.vert.spv", "shaders/shader_textured.frag.spv"];
+    let mut shaders_modified = None;
+
+    let mut input = Input::new();
 
     event_loop.run(move |event, _, control_flow| {
+        input.handle_event(&event);
+
         match event {
             Event::WindowEvent {
                 event: WindowEvent::CloseRequested,
@@ -197,157 +96,149 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             } => {
                 *control_flow = winit::event_loop::ControlFlow::Exit;
             }
+            // A minimized window reports a zero size, there is nothing to recreate for it
             Event::WindowEvent {
-                event: WindowEvent::KeyboardInput { input, .. },
+                event: WindowEvent::Resized(size),
                 ..
-            } => match input {
-                winit::event::KeyboardInput {
-                    state: winit::event::ElementState::Pressed,
-                    virtual_keycode: Some(keycode),
+            } if size.width > 0 && size.height > 0 => {
+                engine.framebuffer_resized = true;
+            }
+            // Toggles react to the key press itself, movement polls Input, see move_camera
+            Event::WindowEvent {
+                event: WindowEvent::KeyboardInput {
+                    input: winit::event::KeyboardInput {
+                        state: winit::event::ElementState::Pressed,
+                        virtual_keycode: Some(keycode),
+                        ..
+                    },
                     ..
-                } => match keycode {
-                    winit::event::VirtualKeyCode::Right => {
-                        camera.turn_right(0.1);
-                    }
-                    winit::event::VirtualKeyCode::Left => {
-                        camera.turn_left(0.1);
-                    }
-                    winit::event::VirtualKeyCode::Up => {
-                        camera.move_forward(0.05);
+                },
+                ..
+            } => {
+                match keycode {
+                    winit::event::VirtualKeyCode::T => {
+                        let transparent = !engine.swapchain.config.transparent;
+                        engine.set_transparent(transparent)
+                            .expect("Failed to recreate swapchain");
                     }
-                    winit::event::VirtualKeyCode::Down => {
-                        camera.move_backward(0.05);
+                    winit::event::VirtualKeyCode::F => {
+                        let wireframe = !engine.wireframe;
+                        engine.set_wireframe(wireframe);
                     }
-                    winit::event::VirtualKeyCode::PageUp => {
-                        camera.turn_up(0.02);
+                    winit::event::VirtualKeyCode::P => {
+                        engine.request_capture("screenshot.png");
                     }
-                    winit::event::VirtualKeyCode::PageDown => {
-                        camera.turn_down(0.02);
+                    winit::event::VirtualKeyCode::G => {
+                        let result = if engine.post_process.is_some() {
+                            engine.clear_post_process()
+                        } else {
+                            engine.set_grayscale_post_process()
+                        };
+
+                        if let Err(error) = result {
+                            println!("Failed to toggle grayscale: {}", error);
+                        }
                     }
                     _ => {}
-                },
-                _ => {}
+                }
             }
             Event::MainEventsCleared => {
+                engine.frame_timer.tick();
+
+                move_camera(&mut camera, &input, engine.frame_timer.delta_seconds());
+                input.end_frame();
+
+                let modified = last_modified(&shader_paths);
+
+                if modified.is_some() && modified != shaders_modified {
+                    shaders_modified = modified;
+
+                    engine.set_shader_paths(shader_paths[0], shader_paths[1]);
+
+                    if let Err(error) = engine.reload_pipeline() {
+                        println!("Failed to reload shaders: {}", error);
+                    }
+                }
+
                 engine.window.request_redraw();
             }
             Event::RedrawRequested(_) => {
-                engine.swapchain.calculate_current_image();
-
-                let (image_index, _) = unsafe {
-                    engine.swapchain.loader.acquire_next_image(
-                        engine.swapchain.swapchain,
-                        u64::MAX,
-                        engine.swapchain.image_available[engine.swapchain.current_image],
-                        vk::Fence::null()
-                    ).expect("Failed to acquire next image")
-                };
-
-                unsafe {
-                    engine.device.wait_for_fences(
-                        &[engine.swapchain.may_begin_drawing[engine.swapchain.current_image]],
-                        true,
-                        u64::MAX
-                    ).expect("Fence waiting");
-
-                    engine.device.reset_fences(
-                        &[engine.swapchain.may_begin_drawing[engine.swapchain.current_image]]
-                    ).expect("Resetting fences");
-
-                    camera.update_buffer(&mut engine.allocator, &mut engine.uniform_buffer).unwrap();
-
-                    for m in &mut engine.models {
-                        m.update_instance_buffer( &mut engine.allocator).unwrap();
-                    }
-
-                    let image_info = vk::DescriptorImageInfo {
-                        image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
-                        image_view: texture.image_view,
-                        sampler: texture.sampler,
-                        ..Default::default()
+                if engine.frame_timer.frames() % 60 == 0 {
+                    let title = match engine.last_gpu_frame_ms() {
+                        Some(gpu_ms) => format!("{:.0} fps, {:.2} ms GPU", engine.frame_timer.fps(), gpu_ms),
+                        None => format!("{:.0} fps", engine.frame_timer.fps()),
                     };
 
-                    let descriptor_write_image = vk::WriteDescriptorSet {
-                        dst_set: engine.descriptor_sets_texture[image_index as usize],
-                        dst_binding: 0,
-                        dst_array_element: 0,
-                        descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
-                        descriptor_count: 1,
-                        p_image_info: [image_info].as_ptr(),
-                        ..Default::default()
-                    };
-
-                    unsafe {
-                        engine.device.update_descriptor_sets(&[descriptor_write_image], &[]);
-                    }
+                    engine.window.set_title(&title);
+                }
 
-                    engine.update_command_buffer(image_index as usize)
-                        .expect("Failed to update command buffer");
-
-                    let semaphores_available = [
-                        engine.swapchain.image_available[engine.swapchain.current_image]
-                    ];
-
-                    let waiting_stages = [
-                        vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
-                    ];
-
-                    let semaphores_finished = [
-                        engine.swapchain.rendering_finished[engine.swapchain.current_image]
-                    ];
-
-                    let command_buffers = [
-                        engine.graphics_command_buffers[image_index as usize]
-                    ];
-
-                    let submit_info = [
-                        vk::SubmitInfo::builder()
-                            .wait_semaphores(&semaphores_available)
-                            .wait_dst_stage_mask(&waiting_stages)
-                            .command_buffers(&command_buffers)
-                            .signal_semaphores(&semaphores_finished)
-                            .build()
-                    ];
-
-                    engine.device.queue_submit(
-                        engine.queues.graphics,
-                        &submit_info,
-                        engine.swapchain.may_begin_drawing[engine.swapchain.current_image]
-                    ).expect("Queue submission failed");
-
-                    let swapchains = [engine.swapchain.swapchain];
-                    let indices = [image_index];
-                    let present_info = vk::PresentInfoKHR::builder()
-                        .wait_semaphores(&semaphores_finished)
-                        .swapchains(&swapchains)
-                        .image_indices(&indices);
-
-                    let res = engine.swapchain.loader.queue_present(
-                        engine.queues.graphics,
-                        &present_info
-                    );
-
-                    match res {
-                        Ok(..) => {}
-                        Err(ash::vk::Result::ERROR_OUT_OF_DATE_KHR) => {
-                            engine.recreate_swapchain()
-                                .expect("Failed to recreate swapchain");
-
-                            camera.set_aspect(
-                                engine.swapchain.extent.width as f32 /
-                                    engine.swapchain.extent.height as f32
-                            );
-
-                            camera.update_buffer(&mut engine.allocator, &mut engine.uniform_buffer)
-                                .expect("Failed to update Camera Uniform Buffer");
-                        }
-                        _ => {
-                            panic!("Unhandled queue presentation error");
-                        }
-                    }
+                if let Err(error) = engine.draw_frame(&mut camera) {
+                    panic!("Failed to draw frame: {}", error);
                 }
             }
             _ => {}
         }
     });
-}
\ No newline at end of file
+}
+// Units per second
+const MOVE_SPEED: f32 = 1.5;
+// Radians per second
+const TURN_SPEED: f32 = 1.5;
+const PITCH_SPEED: f32 = 0.6;
+// Radians per pixel of mouse movement
+const MOUSE_SENSITIVITY: f32 = 0.003;
+
+const MOVEMENT_KEYS: [VirtualKeyCode; 10] = [
+    VirtualKeyCode::Right,
+    VirtualKeyCode::Left,
+    VirtualKeyCode::Up,
+    VirtualKeyCode::Down,
+    VirtualKeyCode::D,
+    VirtualKeyCode::A,
+    VirtualKeyCode::E,
+    VirtualKeyCode::Q,
+    VirtualKeyCode::PageUp,
+    VirtualKeyCode::PageDown,
+];
+
+fn move_camera(camera: &mut Camera, input: &Input, delta_seconds: f32) {
+    let distance = MOVE_SPEED * delta_seconds;
+    let angle = TURN_SPEED * delta_seconds;
+    let pitch = PITCH_SPEED * delta_seconds;
+
+    for keycode in MOVEMENT_KEYS {
+        if !input.is_pressed(keycode) {
+            continue;
+        }
+
+        match keycode {
+            VirtualKeyCode::Right => camera.turn_right(angle),
+            VirtualKeyCode::Left => camera.turn_left(angle),
+            VirtualKeyCode::Up => camera.move_forward(distance),
+            VirtualKeyCode::Down => camera.move_backward(distance),
+            VirtualKeyCode::D => camera.move_right(distance),
+            VirtualKeyCode::A => camera.move_left(distance),
+            VirtualKeyCode::E => camera.move_up(distance),
+            VirtualKeyCode::Q => camera.move_down(distance),
+            VirtualKeyCode::PageUp => camera.turn_up(pitch),
+            VirtualKeyCode::PageDown => camera.turn_down(pitch),
+            _ => {}
+        }
+    }
+
+    // Mouse-look is active while the right mouse button is held
+    if input.is_mouse_pressed(MouseButton::Right) {
+        let (dx, dy) = input.mouse_delta();
+        camera.rotate(dx as f32 * MOUSE_SENSITIVITY, -dy as f32 * MOUSE_SENSITIVITY);
+    }
+}
+
+// The most recent modification time of the files, None if any of them is missing
+fn last_modified(paths: &[&str]) -> Option<std::time::SystemTime> {
+    paths
+        .iter()
+        .map(|path| std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+        .collect::<Option<Vec<_>>>()?
+        .into_iter()
+        .max()
+}